@@ -0,0 +1,37 @@
+// Run with `cargo bench --bench cpu_throughput`. Reports raw instruction
+// throughput of the CPU and bus, without any frontend in the loop.
+
+extern crate bugboy;
+
+use std::time::Instant;
+
+use bugboy::GameBoy;
+
+const INSTRUCTIONS: u64 = 5_000_000;
+
+fn bench_rom() -> Vec<u8> {
+    // LD HL,$C000
+    // loop: INC A; LD (HL),A; LD B,A; ADD A,B; LD A,(HL); JR loop
+    let program = [0x21, 0x00, 0xC0, 0x3C, 0x77, 0x47, 0x80, 0x7E, 0x18, 0xF9];
+    let mut data = vec![0u8; 0x8000];
+    data[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+    data
+}
+
+fn main() {
+    let mut gb = GameBoy::from_bytes(bench_rom()).expect("bench ROM should load");
+
+    let start = Instant::now();
+    for _ in 0..INSTRUCTIONS {
+        gb.step_instruction().expect("bench ROM should not fault");
+    }
+    let elapsed = start.elapsed();
+
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    println!(
+        "cpu_throughput: {} instructions in {:.3}s, {:.1} M instructions/s",
+        INSTRUCTIONS,
+        secs,
+        INSTRUCTIONS as f64 / secs / 1e6
+    );
+}
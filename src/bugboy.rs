@@ -1,118 +1,1517 @@
-#![feature(custom_attribute)]
-#![feature(slice_patterns)]
+extern crate bugboy;
 #[macro_use]
-extern crate enum_primitive;
-extern crate num;
+extern crate serde_json;
 
-mod gb_cpu;
-mod gb_mem;
-mod gb_opcodes;
-mod gb_hw_bus;
-mod gb_rom;
-mod tracelog;
-
-use std::cell::RefCell;
 use std::env;
-use std::io;
-use std::path::Path;
-use std::rc::Rc;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use bugboy::cheat::{Cheat, Cheats};
+use bugboy::disasm;
+use bugboy::fix::{self, HeaderFix};
+use bugboy::golden;
+use bugboy::movie::{Movie, Playback, Start};
+use bugboy::png;
+use bugboy::ramsearch::{RamSearch, ValueSize};
+use bugboy::record::{RecordOptions, Recorder};
+use bugboy::rewind::{self, Rewind};
+#[cfg(feature = "romdb")]
+use bugboy::romdb::{Overrides, RomDatabase};
+use bugboy::runloop::{RunLoop, Speed, MAX_SPEED, MIN_SPEED};
+use bugboy::screenshot::{Palette, Screenshot};
+use bugboy::term::{self, HeldButtons, Key, Terminal};
+use bugboy::{Button, EmuError, FrameClock, GameBoy, GbRom, Model, Strictness, ValidationPolicy};
+
+const USAGE: &str = "Usage: bugboy <command> [options] <rom>
+
+Commands:
+  run       Play a ROM in real time, the default if the command is left out
+  test      Run a test ROM headless and exit with 0 if it passes
+  trace     Log the CPU state before every instruction
+  disasm    Disassemble part of a ROM
+  info      Print what the cartridge header says
+  patch     Apply an IPS, BPS or UPS patch to a ROM
+  fix       Rewrite a ROM's header so it validates
+  search    Search RAM for where a game keeps a value
+
+Run `bugboy <command> --help` for the options each one takes.";
+
+const RUN_USAGE: &str = "Usage: bugboy run|test|trace [options] <rom>
+
+Options:
+  --model <dmg|sgb|cgb>  Hardware to emulate, picked from the header by default
+  --boot-rom <file>      Start from a boot ROM instead of the post-boot state
+  --patch <file>         Apply a patch, a sibling .ips/.bps/.ups is found anyway
+  --strictness <mode>    hardware, warn or strict handling of illegal accesses
+  --cheat <code>         Apply a Game Genie or GameShark code, can be given
+                         more than once
+  --cheats <file>        Load cheats from this file rather than one named
+                         after the ROM's CRC32 or title next to it
+  --cycles <n>           Stop after n CPU cycles
+  --frames <n>           Stop after n frames, 7200 by default for test
+  --headless             Don't read controls from stdin
+  --term                 Draw the screen in the terminal and play with the
+                         keyboard: arrows or WASD, X is A, Z is B, Enter is
+                         Start, Space is Select, R rewinds, P pauses and Q
+                         quits
+  --status               Show FPS, PC and ROM bank under the screen (--term)
+  --speed <x>            Run x times as fast as the real console, from 0.01
+                         to 100, e.g. 2 or 0.5
+  --uncapped             Run as fast as possible
+  --rewind-interval <n>  Frames between rewind snapshots, 4 by default
+  --rewind-memory <MiB>  Memory kept for rewinding, 16 by default, 0 turns
+                         rewinding off
+  --save-dir <dir>       Where battery saves go, next to the ROM by default
+  --palette <colours>    DMG colours: grey, green or four RRGGBB values
+                         separated by commas, lightest first
+  --screenshot-at-frame <n> <file>
+                         Save the screen as a PNG after frame n, can be
+                         given more than once
+  --scale <n>            Scale screenshots up n times
+  --indexed              Save screenshots as indexed colour PNGs
+  --reference <png>      Pass the test if the screen matches this image
+  --diff <png>           Where to show the pixels that didn't match, next to
+                         the reference by default
+  --hash <hex>           Pass the test if the screen hashes to this
+  --until-opcode <op>    End a screen test before running this opcode, such
+                         as 0x40 for LD B,B
+  --record <file>        Record video, an animated GIF if the name ends in
+                         .gif and uncompressed Y4M otherwise, or an input
+                         movie if it ends in .bbm. Can be given twice to
+                         record both
+  --record-audio <file>  Record the sound alongside as a WAV
+  --record-skip <n>      Drop n frames after each one recorded, 1 by
+                         default for GIFs and 0 for Y4M
+  --play <file>          Play back an input movie, stopping at its end or if
+                         the emulation falls out of sync with it
+  --trace-out <file>     Write the trace here instead of stdout (trace only)
+  -v, --verbose          Say more about what's going on
+  -q, --quiet            Only print errors
+  -h, --help             Show this help
+
+While running, enter p to pause or resume, n to advance a frame while paused,
+b to step back a frame and q to quit.";
+
+const SEARCH_USAGE: &str = "Usage: bugboy search [run options] <rom>
+
+Reads commands from stdin, one per line, so searches can be scripted:
+  new [u8|u16le|u16be]  Start a search with every RAM address a candidate
+  run [n]               Run n frames, 1 by default
+  hold [buttons]        Hold buttons such as a,right while running, or none
+  filter <test>         Keep candidates that pass: =, !=, > or < compare with
+                        the last snapshot, or with a number after them, +n
+                        and -n match a change by n, and changed, unchanged,
+                        increased and decreased do what they say
+  snapshot              Compare the next filter against RAM as it is now
+  list [n]              Show up to n candidates, 20 by default
+  save <file>           Write the candidates out as a watch list
+  code <n> <value>      Print GameShark codes holding candidate n at value
+  quit
+
+The run options are the same as for bugboy run.";
+
+const DISASM_USAGE: &str = "Usage: bugboy disasm [options] <rom>
+
+Options:
+  --start <addr>   Address to start at, 0x0100 by default
+  --count <n>      Instructions to show, 32 by default
+  --bank <n>       ROM bank for addresses 0x4000-0x7FFF, 1 by default";
+
+const INFO_USAGE: &str = "Usage: bugboy info [--json] [--dat <file> [--overrides <file>]] <rom>...";
+
+const PATCH_USAGE: &str = "Usage: bugboy patch <rom> <patch> <output>";
+
+const FIX_USAGE: &str = "Usage: bugboy fix [options] <rom> [output]
+  --title <text>        --mfg-code <code>     --licensee <code>
+  --old-licensee <n>    --cgb <n>             --sgb <n>
+  --cart-type <n>       --ram-size <n>        --region <n>
+  --version <n>         --pad-value <n>       --logo";
+
+// test ROMs that never report get two emulated minutes
+const DEFAULT_TEST_FRAMES: u64 = 7200;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+#[derive(Debug, Default)]
+struct Limits {
+    cycles: Option<u64>,
+    frames: Option<u64>,
+}
+
+impl Limits {
+    // Whether a run that has gone `frames` frames, now at `cycles` since
+    // power on, should stop. Frames are counted by whoever runs them, since
+    // at double speed they take twice the cycles.
+    fn reached(&self, cycles: u64, frames: u64) -> bool {
+        self.cycles.is_some_and(|c| cycles >= c) || self.frames.is_some_and(|f| frames >= f)
+    }
+}
+
+struct RunOptions {
+    rom: String,
+    model: Option<Model>,
+    boot_rom: Option<String>,
+    patch: Option<String>,
+    strictness: Strictness,
+    cheats: Vec<String>,
+    cheat_file: Option<PathBuf>,
+    limits: Limits,
+    headless: bool,
+    term: bool,
+    status: bool,
+    speed: Speed,
+    rewind_interval: u32,
+    rewind_memory: usize,
+    save_dir: Option<PathBuf>,
+    palette: Option<Palette>,
+    screenshots: Vec<(u64, PathBuf)>,
+    scale: usize,
+    indexed: bool,
+    reference: Option<PathBuf>,
+    diff: Option<PathBuf>,
+    hash: Option<u64>,
+    until_opcode: Option<u8>,
+    record: Option<PathBuf>,
+    record_audio: Option<PathBuf>,
+    record_skip: Option<u32>,
+    movie: Option<PathBuf>,
+    play: Option<PathBuf>,
+    trace_out: Option<String>,
+    verbosity: Verbosity,
+}
 
-use gb_cpu::DmgCpu;
-use gb_hw_bus::HardwareBus;
-use gb_mem::{MemoryController, RamAddress};
-use gb_rom::GbRom;
+// Accepts decimal, or hex with a 0x or $ prefix
+fn parse_number(arg: &str) -> Result<u64, String> {
+    let parsed = if let Some(hex) = arg.strip_prefix("0x").or_else(|| arg.strip_prefix('$')) {
+        u64::from_str_radix(hex, 16)
+    } else {
+        arg.parse()
+    };
+    parsed.map_err(|e| format!("{}: {}", arg, e))
+}
+
+fn parse_run_options(args: &[String]) -> Result<RunOptions, String> {
+    let mut rom = None;
+    let mut opts = RunOptions {
+        rom: String::new(),
+        model: None,
+        boot_rom: None,
+        patch: None,
+        strictness: Strictness::default(),
+        cheats: Vec::new(),
+        cheat_file: None,
+        limits: Limits::default(),
+        headless: false,
+        term: false,
+        status: false,
+        speed: Speed::Multiplier(1.0),
+        rewind_interval: rewind::DEFAULT_INTERVAL,
+        rewind_memory: rewind::DEFAULT_BUDGET,
+        save_dir: None,
+        palette: None,
+        screenshots: Vec::new(),
+        scale: 1,
+        indexed: false,
+        reference: None,
+        diff: None,
+        hash: None,
+        until_opcode: None,
+        record: None,
+        record_audio: None,
+        record_skip: None,
+        movie: None,
+        play: None,
+        trace_out: None,
+        verbosity: Verbosity::Normal,
+    };
 
-use tracelog::TraceLog;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--model" => opts.model = Some(value()?.parse()?),
+            "--boot-rom" => opts.boot_rom = Some(value()?.clone()),
+            "--patch" => opts.patch = Some(value()?.clone()),
+            "--strictness" => opts.strictness = value()?.parse()?,
+            "--cheat" => opts.cheats.push(value()?.clone()),
+            "--cheats" => opts.cheat_file = Some(PathBuf::from(value()?)),
+            "--cycles" => opts.limits.cycles = Some(parse_number(value()?)?),
+            "--frames" => opts.limits.frames = Some(parse_number(value()?)?),
+            "--headless" => opts.headless = true,
+            "--term" => opts.term = true,
+            "--status" => opts.status = true,
+            "--speed" => match value()?.parse::<f64>() {
+                Ok(m) if (MIN_SPEED..=MAX_SPEED).contains(&m) => opts.speed = Speed::Multiplier(m),
+                _ => {
+                    return Err(format!(
+                        "--speed needs a multiplier from {} to {}",
+                        MIN_SPEED, MAX_SPEED
+                    ))
+                }
+            },
+            "--uncapped" => opts.speed = Speed::Uncapped,
+            "--rewind-interval" => match parse_number(value()?)? {
+                0 => return Err(String::from("--rewind-interval needs to be at least 1")),
+                n => opts.rewind_interval = n as u32,
+            },
+            "--rewind-memory" => {
+                opts.rewind_memory = (parse_number(value()?)? as usize).saturating_mul(1024 * 1024)
+            }
+            "--save-dir" => opts.save_dir = Some(PathBuf::from(value()?)),
+            "--palette" => opts.palette = Some(value()?.parse()?),
+            "--screenshot-at-frame" => {
+                let frame = parse_number(value()?)?;
+                opts.screenshots.push((frame, PathBuf::from(value()?)));
+            }
+            "--scale" => match parse_number(value()?)? {
+                0 => return Err(String::from("--scale needs to be at least 1")),
+                n => opts.scale = n as usize,
+            },
+            "--indexed" => opts.indexed = true,
+            "--reference" => opts.reference = Some(PathBuf::from(value()?)),
+            "--diff" => opts.diff = Some(PathBuf::from(value()?)),
+            "--hash" => {
+                let hash = value()?;
+                let digits = hash.trim_start_matches("0x");
+                opts.hash =
+                    Some(u64::from_str_radix(digits, 16).map_err(|e| format!("{}: {}", hash, e))?);
+            }
+            "--until-opcode" => match parse_number(value()?)? {
+                op if op <= 0xFF => opts.until_opcode = Some(op as u8),
+                op => return Err(format!("opcode {:#X} is more than a byte", op)),
+            },
+            "--record" => {
+                let path = PathBuf::from(value()?);
+                if path.extension().is_some_and(|e| e == "bbm") {
+                    opts.movie = Some(path);
+                } else {
+                    opts.record = Some(path);
+                }
+            }
+            "--record-audio" => opts.record_audio = Some(PathBuf::from(value()?)),
+            "--record-skip" => opts.record_skip = Some(parse_number(value()?)? as u32),
+            "--play" => opts.play = Some(PathBuf::from(value()?)),
+            "--trace-out" => opts.trace_out = Some(value()?.clone()),
+            "-v" | "--verbose" => opts.verbosity = Verbosity::Verbose,
+            "-q" | "--quiet" => opts.verbosity = Verbosity::Quiet,
+            other if other.starts_with('-') => return Err(format!("unknown option {}", other)),
+            _ if rom.is_none() => rom = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    opts.rom = rom.ok_or_else(|| String::from("no ROM given"))?;
+    if opts.record_audio.is_some() && opts.record.is_none() {
+        return Err(String::from("--record-audio needs --record"));
+    }
+    if opts.movie.is_some() && opts.play.is_some() {
+        return Err(String::from("a movie can't be recorded while playing one"));
+    }
+    if opts.term && opts.headless {
+        return Err(String::from("--term and --headless can't be used together"));
+    }
+    Ok(opts)
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, EmuError> {
+    fs::read(path).map_err(|e| EmuError::Io(format!("{}: {}", path.display(), e)))
+}
 
 struct DmgBoy {
-    cpu: Rc<RefCell<DmgCpu>>,
-    mc: Rc<RefCell<MemoryController>>,
-    bus: Rc<RefCell<HardwareBus>>,
+    gb: GameBoy,
+    // where battery backed RAM is kept, for carts that have it
+    save_path: Option<PathBuf>,
+
+    palette: Option<Palette>,
+    scale: usize,
+    indexed: bool,
+    // frames still to be captured, and where to
+    screenshots: Vec<(u64, PathBuf)>,
+    recorder: Option<Recorder>,
+    // the input movie being recorded, and where it's going
+    movie: Option<(Movie, PathBuf)>,
+    playback: Option<Playback>,
+    rewind: Option<Rewind>,
 }
 
 impl DmgBoy {
-    fn new(rom: GbRom) -> Self {
-        let bus = Rc::new(RefCell::new(HardwareBus::new()));
-        let mc = Rc::new(RefCell::new(MemoryController::new(rom)));
-        let cpu = Rc::new(RefCell::new(DmgCpu::new(bus.clone(), mc.clone())));
-        DmgBoy {
-            bus: bus,
-            mc: mc,
-            cpu: cpu,
+    fn load(opts: &RunOptions) -> Result<Self, EmuError> {
+        let path = Path::new(&opts.rom);
+        let rom = GbRom::with_patch(path, opts.patch.as_ref().map(Path::new))?;
+        if opts.verbosity > Verbosity::Quiet {
+            for problem in rom.validate(ValidationPolicy::Warn)? {
+                eprintln!("WARNING: {}", problem);
+            }
+            if let Some(p) = rom.patch() {
+                eprintln!("Applied patch {}", p.display());
+            }
+        }
+        if opts.verbosity == Verbosity::Verbose {
+            rom.print_info();
+        }
+
+        let model = opts.model.unwrap_or_else(|| Model::for_rom(&rom));
+        let mut gb = match opts.boot_rom {
+            Some(ref boot) => GameBoy::with_boot_rom(rom, model, read_file(Path::new(boot))?)?,
+            None => GameBoy::with_model(rom, model),
+        };
+        gb.set_strictness(opts.strictness);
+
+        let cheat_file = match opts.cheat_file {
+            Some(ref file) => Some(file.clone()),
+            None => {
+                let dir = path.parent().unwrap_or_else(|| Path::new(""));
+                Cheats::find(dir, gb.rom_crc32(), gb.rom_title())
+            }
+        };
+        if let Some(ref file) = cheat_file {
+            *gb.cheats_mut() = Cheats::load(file)?;
+        }
+        for code in &opts.cheats {
+            gb.cheats_mut().add(Cheat::new(code)?);
+        }
+        if opts.verbosity == Verbosity::Verbose {
+            if let Some(ref file) = cheat_file {
+                println!("Loaded cheats from {}", file.display());
+            }
+            for cheat in gb.cheats().iter() {
+                println!("Cheat {}", cheat);
+            }
+            println!("Running as {:?}", model);
+        }
+
+        // a movie carries its own start, so it isn't given the battery save
+        let mut save_path = None;
+        let mut loaded_save = false;
+        if gb.save_ram().is_some() && opts.play.is_none() {
+            let dir = match opts.save_dir {
+                Some(ref dir) => dir.clone(),
+                None => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            };
+            let stem = path.file_stem().unwrap_or_default();
+            let save = dir.join(stem).with_extension("sav");
+            if save.exists() {
+                gb.load_save_ram(&read_file(&save)?)?;
+                loaded_save = true;
+                if opts.verbosity == Verbosity::Verbose {
+                    println!("Loaded {}", save.display());
+                }
+            }
+            save_path = Some(save);
+        }
+
+        let recorder = match opts.record {
+            Some(ref path) => {
+                let gif = path.extension().is_some_and(|e| e == "gif");
+                let options = RecordOptions {
+                    frame_skip: opts.record_skip.unwrap_or(if gif { 1 } else { 0 }),
+                    scale: opts.scale,
+                    palette: opts.palette,
+                };
+                Some(Recorder::create(
+                    path,
+                    opts.record_audio.as_deref(),
+                    options,
+                )?)
+            }
+            None => None,
+        };
+
+        let movie = match opts.movie {
+            Some(ref path) => {
+                let start = if opts.boot_rom.is_some() || loaded_save {
+                    Start::State(gb.save_state())
+                } else {
+                    Start::PowerOn
+                };
+                Some((Movie::new(&gb, start), path.clone()))
+            }
+            None => None,
+        };
+        let playback = match opts.play {
+            Some(ref path) => {
+                let movie = Movie::load(path)?;
+                if opts.verbosity == Verbosity::Verbose {
+                    println!(
+                        "Playing {} frames recorded by bugboy {}",
+                        movie.len(),
+                        movie.version
+                    );
+                }
+                Some(movie.play(&mut gb)?)
+            }
+            None => None,
+        };
+
+        Ok(DmgBoy {
+            gb,
+            save_path,
+            palette: opts.palette,
+            scale: opts.scale,
+            indexed: opts.indexed,
+            screenshots: opts.screenshots.clone(),
+            recorder,
+            movie,
+            playback,
+            rewind: None,
+        })
+    }
+
+    /// Writes the screen as it is now to a PNG, scaled and coloured as the
+    /// options asked.
+    fn screenshot(&self, path: &Path) -> Result<(), EmuError> {
+        Screenshot::capture(&self.gb, self.palette.as_ref())
+            .scaled(self.scale)
+            .save(path, self.indexed)
+    }
+
+    // Holds the buttons for the next frame of the movie being played
+    fn frame_start(&mut self) {
+        if let Some(ref playback) = self.playback {
+            playback.press(&mut self.gb);
+        }
+    }
+
+    // Goes back a frame if there's one to go back to. A movie being
+    // recorded goes back with it, to be recorded again from there.
+    fn step_back(&mut self) -> Result<bool, EmuError> {
+        let rewind = match self.rewind {
+            Some(ref mut rewind) => rewind,
+            None => return Ok(false),
+        };
+        if !rewind.step_back(&mut self.gb)? {
+            return Ok(false);
+        }
+        if let Some((ref mut movie, _)) = self.movie {
+            let frames = movie.len().saturating_sub(1);
+            movie.truncate(frames);
+        }
+        Ok(true)
+    }
+
+    fn movie_finished(&self) -> bool {
+        self.playback.as_ref().is_some_and(Playback::is_finished)
+    }
+
+    // Records the frame just finished and takes any screenshots due now
+    // that `frame` frames have run. Fails if a movie being played has gone
+    // out of sync.
+    fn frame_done(&mut self, frame: u64) -> Result<(), EmuError> {
+        self.print_warnings();
+        if let Some(ref mut playback) = self.playback {
+            playback.frame_done(&self.gb)?;
+        }
+        if let Some((ref mut movie, _)) = self.movie {
+            movie.record_frame(&self.gb);
+        }
+        if let Some(ref mut rewind) = self.rewind {
+            rewind.record_frame(&self.gb);
+        }
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.frame(&mut self.gb)?;
+        }
+        let (due, later) = self.screenshots.drain(..).partition(|&(f, _)| f <= frame);
+        self.screenshots = later;
+        for (_, path) in due {
+            self.screenshot(&path)?;
+        }
+        Ok(())
+    }
+
+    // Passes on the faults noted under --strictness warn
+    fn print_warnings(&mut self) {
+        for warning in self.gb.take_warnings() {
+            eprintln!("WARNING: {}", warning);
         }
     }
 
-    fn run(&mut self) {
-        let mut max_ticks = 100_000;
-        //let mut buffer = String::new();
-        //let stdin = io::stdin();
-        let mut log: Vec<TraceLog> = Vec::new();
-        loop {
-            match self.cpu.borrow_mut().tick(&mut log) {
-                Ok(_) => (),
-                Err(e) => {
-                    println!("ERROR: {}", e);
-                    break;
+    fn save(&self) -> Result<(), EmuError> {
+        if let Some((ref movie, ref path)) = self.movie {
+            movie.save(path)?;
+        }
+        let (path, ram) = match (self.save_path.as_ref(), self.gb.save_ram()) {
+            (Some(path), Some(ram)) => (path, ram),
+            _ => return Ok(()),
+        };
+        let io_error = |e: io::Error| EmuError::Io(format!("{}: {}", path.display(), e));
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir).map_err(io_error)?;
+            }
+        }
+        fs::write(path, ram).map_err(io_error)
+    }
+
+    // Runs in real time until the game stops, a limit is hit or it's quit.
+    // Unless headless, lines on stdin control the loop: p pauses or resumes,
+    // n advances a frame while paused and q quits.
+    fn run(&mut self, runner: &mut RunLoop, opts: &RunOptions) -> Result<(), EmuError> {
+        let (tx, rx) = mpsc::channel();
+        if !opts.headless {
+            thread::spawn(move || {
+                let stdin = io::stdin();
+                for line in stdin.lock().lines() {
+                    match line {
+                        Ok(line) => {
+                            if tx.send(line).is_err() {
+                                return;
+                            }
+                        }
+                        Err(_) => return,
+                    }
+                }
+            });
+        }
+
+        while !opts.limits.reached(self.gb.cycles(), runner.frames()) {
+            while let Ok(command) = rx.try_recv() {
+                match command.trim() {
+                    "p" if runner.is_paused() => runner.resume(),
+                    "p" => runner.pause(),
+                    "n" => runner.advance_frame(),
+                    "b" => {
+                        if !self.step_back()? {
+                            eprintln!("Nothing left to rewind");
+                        }
+                    }
+                    "q" => return Ok(()),
+                    other => eprintln!("Unknown command {:?}, expected p, n, b or q", other),
                 }
             }
 
-            if self.cpu.borrow().is_stopped() {
-                println!("Game was stopped");
-                break;
+            self.frame_start();
+            let report = runner.run_frame(&mut self.gb)?;
+            if report.cycles > 0 {
+                self.frame_done(runner.frames())?;
+            }
+            if self.movie_finished() {
+                return Ok(());
             }
+            if self.gb.is_stopped() {
+                if opts.verbosity > Verbosity::Quiet {
+                    println!("Game was stopped");
+                }
+                return Ok(());
+            }
+            runner.pace(&report);
+        }
+        Ok(())
+    }
+
+    // Like `run`, but draws each frame in the terminal and takes the joypad
+    // from keys typed at it
+    fn run_terminal(&mut self, runner: &mut RunLoop, opts: &RunOptions) -> Result<(), EmuError> {
+        let _terminal = Terminal::enter()
+            .map_err(|e| EmuError::Io(format!("setting up the terminal: {}", e)))?;
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 64];
+            let stdin = io::stdin();
+            let mut stdin = stdin.lock();
+            while let Ok(n) = stdin.read(&mut buf) {
+                if n == 0 || tx.send(buf[..n].to_vec()).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut held = HeldButtons::new();
+        // frames left to keep rewinding for, like a held button
+        let mut rewinding = 0;
+        let mut fps = 0.0;
+        let mut counted = (Instant::now(), runner.frames());
+        let stdout = io::stdout();
+        while !opts.limits.reached(self.gb.cycles(), runner.frames()) {
+            while let Ok(bytes) = rx.try_recv() {
+                for key in term::parse_keys(&bytes) {
+                    match key {
+                        Key::Button(button) => held.press(button),
+                        Key::Rewind => rewinding = term::HOLD_FRAMES,
+                        Key::Pause if runner.is_paused() => runner.resume(),
+                        Key::Pause => runner.pause(),
+                        Key::Quit => return Ok(()),
+                    }
+                }
+            }
+            let report = if rewinding > 0 {
+                rewinding -= 1;
+                self.step_back()?;
+                runner.idle_frame()
+            } else {
+                held.update(&mut self.gb);
+                self.frame_start();
+                let report = runner.run_frame(&mut self.gb)?;
+                if report.cycles > 0 {
+                    self.frame_done(runner.frames())?;
+                }
+                if self.movie_finished() {
+                    return Ok(());
+                }
+                if self.gb.is_stopped() {
+                    return Ok(());
+                }
+                report
+            };
+
+            let elapsed = counted.0.elapsed().as_secs_f64();
+            if elapsed >= 1.0 {
+                fps = (runner.frames() - counted.1) as f64 / elapsed;
+                counted = (Instant::now(), runner.frames());
+            }
+            let status = if opts.status {
+                let r = self.gb.registers();
+                Some(format!(
+                    "{:5.1} FPS  PC {:04X}  bank {:02X}{}",
+                    fps,
+                    r.pc,
+                    self.gb.rom_bank(),
+                    if rewinding > 0 {
+                        "  rewinding"
+                    } else if runner.is_paused() {
+                        "  paused"
+                    } else {
+                        ""
+                    }
+                ))
+            } else {
+                None
+            };
+            let screen = Screenshot::capture(&self.gb, self.palette.as_ref());
+            let frame = term::render(screen.pixels(), screen.width(), status.as_deref());
+            let mut out = stdout.lock();
+            out.write_all(frame.as_bytes())
+                .and_then(|_| out.flush())
+                .map_err(|e| EmuError::Io(format!("drawing to the terminal: {}", e)))?;
 
-            max_ticks -= 1;
-            if max_ticks == 0 {
-                println!("Reached the end of timer.");
+            runner.pace(&report);
+        }
+        Ok(())
+    }
+
+    // Runs until the ROM reports a result, either Blargg style over the link
+    // port or Mooneye style by loading the Fibonacci numbers and hitting
+    // LD B,B. Returns None if it never says.
+    fn run_test(&mut self, limits: &Limits) -> Result<Option<bool>, EmuError> {
+        let mut checked = 0;
+        let mut clock = FrameClock::new(&self.gb);
+        self.frame_start();
+        while !limits.reached(self.gb.cycles(), clock.frames()) && !self.gb.is_stopped() {
+            let pc = self.gb.registers().pc;
+            if self.gb.read_memory(pc) == 0x40 {
+                let r = self.gb.registers();
+                return Ok(Some(
+                    [r.b, r.c, r.d, r.e, r.h, r.l] == [3, 5, 8, 13, 21, 34],
+                ));
+            }
+            self.gb.step_instruction()?;
+            if clock.tick(&self.gb) {
+                self.frame_done(clock.frames())?;
+                self.frame_start();
+            }
+
+            let output = self.gb.serial_output();
+            if output.len() != checked {
+                checked = output.len();
+                let text = String::from_utf8_lossy(output);
+                if text.contains("Passed") {
+                    return Ok(Some(true));
+                }
+                if text.contains("Failed") {
+                    return Ok(Some(false));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Runs to the end of the limits, or the trigger opcode, then checks the
+    // screen against a reference image or hash. A diff image is written
+    // when the reference doesn't match.
+    fn screen_test(&mut self, opts: &RunOptions) -> Result<bool, EmuError> {
+        let mut clock = FrameClock::new(&self.gb);
+        let mut triggered = false;
+        self.frame_start();
+        while !opts.limits.reached(self.gb.cycles(), clock.frames()) && !self.gb.is_stopped() {
+            // an instruction at a time, so frames end where run_frame ends them
+            if golden::run_until(&mut self.gb, 1, opts.until_opcode)? {
+                triggered = true;
                 break;
             }
+            if clock.tick(&self.gb) {
+                self.frame_done(clock.frames())?;
+                self.frame_start();
+            }
+        }
+        if let Some(op) = opts.until_opcode {
+            if !triggered && opts.verbosity > Verbosity::Quiet {
+                eprintln!("WARNING: opcode {:02X} was never reached", op);
+            }
+        }
+
+        let screen = Screenshot::capture(&self.gb, self.palette.as_ref());
+        let hash = golden::frame_hash(&screen);
+        let quiet = opts.verbosity == Verbosity::Quiet;
+        if !quiet {
+            println!("Frame hash: {:016X}", hash);
+        }
+        let mut passed = true;
+
+        if let Some(expected) = opts.hash {
+            if hash != expected {
+                if !quiet {
+                    println!("Expected hash {:016X}", expected);
+                }
+                passed = false;
+            }
+        }
 
-            // match stdin.read_line(&mut buffer) {
-            //     Ok(_) => continue,
-            //     Err(e) => {
-            //         println!("Error reading stdin: {}", e);
-            //         return;
-            //     }
-            // }
+        if let Some(ref reference) = opts.reference {
+            let image = png::decode(&read_file(reference)?)?;
+            let comparison = golden::compare(&screen, &image);
+            if !comparison.passed() {
+                let diff = match opts.diff {
+                    Some(ref diff) => diff.clone(),
+                    None => reference.with_extension("diff.png"),
+                };
+                let d = &comparison.diff;
+                fs::write(&diff, png::encode_rgb(d.width, d.height, &d.pixels))
+                    .map_err(|e| EmuError::Io(format!("{}: {}", diff.display(), e)))?;
+                if !quiet {
+                    println!(
+                        "{} pixels differ from {}, see {}",
+                        comparison.mismatched,
+                        reference.display(),
+                        diff.display()
+                    );
+                }
+                passed = false;
+            }
         }
+
+        Ok(passed)
+    }
+
+    // One line per instruction in the format gameboy-doctor compares
+    fn trace<W: Write>(&mut self, out: &mut W, limits: &Limits) -> Result<(), EmuError> {
+        let io_error = |e: io::Error| EmuError::Io(format!("writing trace: {}", e));
+        let mut clock = FrameClock::new(&self.gb);
+        while !limits.reached(self.gb.cycles(), clock.frames()) && !self.gb.is_stopped() {
+            let r = self.gb.registers();
+            let mem: Vec<u8> = (0..4)
+                .map(|i| self.gb.read_memory(r.pc.wrapping_add(i)))
+                .collect();
+            writeln!(
+                out,
+                "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+                r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l, r.sp, r.pc, mem[0], mem[1], mem[2], mem[3]
+            )
+            .map_err(io_error)?;
+            self.gb.step_instruction()?;
+            clock.tick(&self.gb);
+            self.print_warnings();
+        }
+        out.flush().map_err(io_error)
     }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+#[cfg(feature = "romdb")]
+struct Catalogue {
+    dat: RomDatabase,
+    overrides: Option<Overrides>,
+}
 
-    if args.len() < 2 {
-        println!("Too few arguments specified.");
-        return;
+#[cfg(feature = "romdb")]
+impl Catalogue {
+    fn load(dat: Option<&String>, overrides: Option<&String>) -> Result<Option<Self>, EmuError> {
+        let dat = match dat {
+            Some(path) => RomDatabase::load(path)?,
+            None => return Ok(None),
+        };
+        let overrides = match overrides {
+            Some(path) => Some(Overrides::load(path)?),
+            None => None,
+        };
+        Ok(Some(Catalogue { dat, overrides }))
+    }
+
+    fn identify(&self, rom: &GbRom) -> serde_json::Value {
+        let (hashes, entry) = self.dat.identify(rom);
+        let game = self.overrides.as_ref().and_then(|o| o.get(&hashes, entry));
+        json!({
+            "crc32": format!("{:08x}", hashes.crc32),
+            "md5": hashes.md5,
+            "sha1": hashes.sha1,
+            "name": entry.map(|e| e.name.clone()),
+            "good_dump": entry.map(|e| e.good),
+            "model": game.and_then(|g| g.model.clone()),
+            "mapper": game.and_then(|g| g.mapper.clone()),
+            "palette": game.and_then(|g| g.palette).map(|p| {
+                p.iter().map(|c| format!("#{:06X}", c)).collect::<Vec<_>>()
+            }),
+        })
+    }
+}
+
+#[cfg(not(feature = "romdb"))]
+struct Catalogue;
+
+#[cfg(not(feature = "romdb"))]
+impl Catalogue {
+    fn load(dat: Option<&String>, _: Option<&String>) -> Result<Option<Self>, EmuError> {
+        match dat {
+            Some(_) => Err(EmuError::Io(String::from("built without DAT support"))),
+            None => Ok(None),
+        }
+    }
+
+    fn identify(&self, _: &GbRom) -> serde_json::Value {
+        json!({})
+    }
+}
+
+// Prints the header of every ROM given, as a table or with --json as an
+// array of objects. With --dat each ROM is also looked up in a No-Intro DAT,
+// and in a file of per-game overrides if one is given. Returns the exit code.
+fn info(args: &[String]) -> i32 {
+    let mut as_json = false;
+    let mut dat = None;
+    let mut overrides = None;
+    let mut paths = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let target = match arg.as_str() {
+            "--json" => {
+                as_json = true;
+                continue;
+            }
+            "--dat" => &mut dat,
+            "--overrides" => &mut overrides,
+            _ => {
+                paths.push(arg);
+                continue;
+            }
+        };
+        match args.next() {
+            Some(value) => *target = Some(value),
+            None => {
+                eprintln!("ERROR: {} needs a value", arg);
+                return 2;
+            }
+        }
     }
 
-    let path = Path::new(&args[1]);
+    if paths.is_empty() {
+        eprintln!("{}", INFO_USAGE);
+        return 2;
+    }
 
-    let mut absolute_path = match env::current_dir() {
-        Ok(p) => p,
+    let catalogue = match Catalogue::load(dat, overrides) {
+        Ok(c) => c,
         Err(e) => {
-            println!("Unable to get current environment path: {}", e);
-            panic!();
+            eprintln!("ERROR: {}", e);
+            return 1;
         }
     };
-    absolute_path.push(path);
 
-    let rom = match GbRom::new(absolute_path) {
+    let mut failed = false;
+    let mut infos = Vec::new();
+    for path in paths {
+        match GbRom::new(path) {
+            Ok(rom) if as_json => {
+                let mut info = rom.info_json();
+                info["path"] = json!(path);
+                if let Some(ref c) = catalogue {
+                    info["dat"] = c.identify(&rom);
+                }
+                infos.push(info);
+            }
+            Ok(rom) => {
+                println!("{}", path);
+                rom.print_info();
+                if let Some(ref c) = catalogue {
+                    if let Some(fields) = c.identify(&rom).as_object() {
+                        for (name, val) in fields {
+                            println!("+ {:26}: {}", name, val);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                failed = true;
+                if as_json {
+                    infos.push(json!({ "path": path, "error": e.to_string() }));
+                } else {
+                    eprintln!("{}: ERROR: {}", path, e);
+                }
+            }
+        }
+    }
+
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(&infos).unwrap());
+    }
+
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+// Applies a patch to a ROM and writes out the result. Returns the exit code.
+fn patch(args: &[String]) -> i32 {
+    if args.len() != 3 {
+        eprintln!("{}", PATCH_USAGE);
+        return 2;
+    }
+
+    let rom = match GbRom::with_patch(&args[0], Some(Path::new(&args[1]))) {
         Ok(r) => r,
         Err(e) => {
-            println!("ERROR loading ROM: {}", e);
-            return;
+            eprintln!("ERROR: {}", e);
+            return 1;
+        }
+    };
+
+    match fs::write(&args[2], rom.data()) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("ERROR writing {}: {}", args[2], e);
+            1
+        }
+    }
+}
+
+// Accepts decimal or 0x prefixed hex
+fn parse_byte(arg: Option<&String>) -> Result<u8, String> {
+    let arg = match arg {
+        Some(a) => a,
+        None => return Err(String::from("missing value")),
+    };
+    let parsed = if arg.starts_with("0x") || arg.starts_with("0X") {
+        u8::from_str_radix(&arg[2..], 16)
+    } else {
+        arg.parse()
+    };
+    parsed.map_err(|e| format!("{}: {}", arg, e))
+}
+
+// Rewrites a ROM's header so it validates, in place unless an output file is
+// given. Returns the exit code.
+fn fix_header(args: &[String]) -> i32 {
+    let mut header = HeaderFix {
+        pad_value: 0xFF,
+        ..HeaderFix::default()
+    };
+    let mut paths = Vec::new();
+
+    let mut options = args.iter();
+    while let Some(arg) = options.next() {
+        let byte = match arg.as_str() {
+            "--title" => {
+                header.title = options.next().cloned();
+                continue;
+            }
+            "--mfg-code" => {
+                header.mfg_code = options.next().cloned();
+                continue;
+            }
+            "--licensee" => {
+                header.new_licensee = options.next().cloned();
+                continue;
+            }
+            "--logo" => {
+                header.logo = true;
+                continue;
+            }
+            "--cgb" => &mut header.cgb_flag,
+            "--old-licensee" => &mut header.old_licensee,
+            "--sgb" => &mut header.sgb_flag,
+            "--cart-type" => &mut header.cart_type,
+            "--ram-size" => &mut header.ram_size,
+            "--region" => &mut header.region,
+            "--version" => &mut header.version,
+            "--pad-value" => {
+                match parse_byte(options.next()) {
+                    Ok(val) => header.pad_value = val,
+                    Err(e) => {
+                        eprintln!("ERROR: {} {}", arg, e);
+                        return 2;
+                    }
+                }
+                continue;
+            }
+            _ => {
+                paths.push(arg);
+                continue;
+            }
+        };
+
+        match parse_byte(options.next()) {
+            Ok(val) => *byte = Some(val),
+            Err(e) => {
+                eprintln!("ERROR: {} {}", arg, e);
+                return 2;
+            }
+        }
+    }
+
+    if paths.is_empty() || paths.len() > 2 {
+        eprintln!("{}", FIX_USAGE);
+        return 2;
+    }
+
+    let rom = match fs::read(paths[0]) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("ERROR reading {}: {}", paths[0], e);
+            return 1;
+        }
+    };
+
+    let fixed = match fix::fix(&rom, &header) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return 1;
+        }
+    };
+
+    let output = paths.last().unwrap();
+    match fs::write(output, fixed) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("ERROR writing {}: {}", output, e);
+            1
+        }
+    }
+}
+
+// Lists instructions from a ROM. Returns the exit code.
+fn disassemble(args: &[String]) -> i32 {
+    let mut start = 0x0100u64;
+    let mut count = 32u64;
+    let mut bank = 1u64;
+    let mut path = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let target = match arg.as_str() {
+            "--start" => &mut start,
+            "--count" => &mut count,
+            "--bank" => &mut bank,
+            _ => {
+                path = Some(arg);
+                continue;
+            }
+        };
+        match args.next().map(|a| parse_number(a)) {
+            Some(Ok(n)) => *target = n,
+            Some(Err(e)) => {
+                eprintln!("ERROR: {} {}", arg, e);
+                return 2;
+            }
+            None => {
+                eprintln!("ERROR: {} needs a value", arg);
+                return 2;
+            }
+        }
+    }
+
+    let path = match path {
+        Some(p) => p,
+        None => {
+            eprintln!("{}", DISASM_USAGE);
+            return 2;
+        }
+    };
+    let rom = match GbRom::new(path) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return 1;
+        }
+    };
+
+    let data = rom.data();
+    let banks = data.len().div_ceil(0x4000) as u64;
+    if bank >= banks {
+        eprintln!("ERROR: --bank {} is past the ROM's {} banks", bank, banks);
+        return 2;
+    }
+    let mut addr = start;
+    for _ in 0..count {
+        if addr > 0x7FFF {
+            break;
+        }
+        let (bank, offset) = if addr < 0x4000 {
+            (0, addr as usize)
+        } else {
+            (bank, (bank * 0x4000 + addr - 0x4000) as usize)
+        };
+        if offset >= data.len() {
+            break;
+        }
+
+        // instructions don't run on past the end of the bank
+        let end = (offset | 0x3FFF) + 1;
+        let bytes = &data[offset..end.min(data.len())];
+        let instruction = disasm::decode(bytes, addr as u16);
+        let hex: Vec<String> = bytes[..instruction.len]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        println!(
+            "{:02X}:{:04X}  {:<9} {}",
+            bank,
+            addr,
+            hex.join(" "),
+            instruction.text
+        );
+        addr += instruction.len as u64;
+    }
+    0
+}
+
+// run, test and trace. Returns the exit code.
+fn run_command(command: &str, args: &[String]) -> i32 {
+    let mut opts = match parse_run_options(args) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            eprintln!("{}", RUN_USAGE);
+            return 2;
+        }
+    };
+
+    let mut bugboy = match DmgBoy::load(&opts) {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("ERROR loading {}: {}", opts.rom, e);
+            return 1;
+        }
+    };
+
+    let result = match command {
+        "test" => {
+            if opts.limits.cycles.is_none() && opts.limits.frames.is_none() {
+                opts.limits.frames = Some(DEFAULT_TEST_FRAMES);
+            }
+            if opts.reference.is_some() || opts.hash.is_some() {
+                bugboy.screen_test(&opts).inspect(|&passed| {
+                    if opts.verbosity > Verbosity::Quiet {
+                        println!("{}", if passed { "PASSED" } else { "FAILED" });
+                    }
+                })
+            } else {
+                bugboy.run_test(&opts.limits).map(|passed| {
+                    if opts.verbosity > Verbosity::Quiet {
+                        print!("{}", String::from_utf8_lossy(bugboy.gb.serial_output()));
+                        match passed {
+                            Some(true) => println!("PASSED"),
+                            Some(false) => println!("FAILED"),
+                            None => println!("TIMED OUT after {} cycles", bugboy.gb.cycles()),
+                        }
+                    }
+                    passed == Some(true)
+                })
+            }
+        }
+        "trace" => {
+            let traced = match opts.trace_out {
+                Some(ref path) => match File::create(path) {
+                    Ok(file) => bugboy.trace(&mut BufWriter::new(file), &opts.limits),
+                    Err(e) => Err(EmuError::Io(format!("{}: {}", path, e))),
+                },
+                None => {
+                    let stdout = io::stdout();
+                    let mut out = BufWriter::new(stdout.lock());
+                    bugboy.trace(&mut out, &opts.limits)
+                }
+            };
+            traced.map(|_| true)
+        }
+        _ => {
+            let mut runner = RunLoop::new();
+            runner.set_speed(opts.speed);
+            if opts.rewind_memory > 0 && opts.play.is_none() {
+                bugboy.rewind = Some(Rewind::new(opts.rewind_interval, opts.rewind_memory));
+            }
+            let ran = if opts.term {
+                bugboy.run_terminal(&mut runner, &opts)
+            } else {
+                bugboy.run(&mut runner, &opts)
+            };
+            ran.map(|_| {
+                if opts.verbosity > Verbosity::Quiet {
+                    println!(
+                        "Ran {} frames ({} cycles)",
+                        runner.frames(),
+                        runner.cycles()
+                    );
+                }
+                true
+            })
         }
     };
+    bugboy.print_warnings();
+
+    for (frame, path) in &bugboy.screenshots {
+        eprintln!(
+            "WARNING: stopped before frame {}, {} wasn't saved",
+            frame,
+            path.display()
+        );
+    }
+
+    if let Some(recorder) = bugboy.recorder.take() {
+        if let Err(e) = recorder.finish() {
+            eprintln!("ERROR: {}", e);
+            return 1;
+        }
+    }
+
+    if let Err(e) = bugboy.save() {
+        eprintln!("ERROR saving: {}", e);
+        return 1;
+    }
 
-    let mut bugboy = DmgBoy::new(rom);
-    {
-        let mc = bugboy.mc.borrow();
-        let addr = RamAddress::new(0x0100);
-        println!("Hello, world! {}", mc.read(addr) as char);
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            1
+        }
     }
+}
+
+fn parse_buttons(list: &str) -> Result<Vec<Button>, String> {
+    list.split(',')
+        .filter(|b| !b.trim().is_empty())
+        .map(|b| match b.trim().to_lowercase().as_str() {
+            "right" => Ok(Button::Right),
+            "left" => Ok(Button::Left),
+            "up" => Ok(Button::Up),
+            "down" => Ok(Button::Down),
+            "a" => Ok(Button::A),
+            "b" => Ok(Button::B),
+            "select" => Ok(Button::Select),
+            "start" => Ok(Button::Start),
+            other => Err(format!("unknown button {}", other)),
+        })
+        .collect()
+}
 
-    bugboy.run();
+// Runs one line of a RAM search session. Returns false once it's time to
+// stop.
+fn search_command(
+    bugboy: &mut DmgBoy,
+    runner: &mut RunLoop,
+    search: &mut Option<RamSearch>,
+    line: &str,
+) -> Result<bool, String> {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        Some(c) => c,
+        None => return Ok(true),
+    };
+    let rest: Vec<&str> = words.collect();
+    let no_search = || String::from("no search yet, start one with new");
+
+    match command {
+        "new" => {
+            let size = match rest.first() {
+                Some(size) => size.parse()?,
+                None => ValueSize::U8,
+            };
+            let started = RamSearch::new(&bugboy.gb, size);
+            println!("{} candidates", started.len());
+            *search = Some(started);
+        }
+        "run" => {
+            let frames = match rest.first() {
+                Some(n) => parse_number(n)?,
+                None => 1,
+            };
+            for _ in 0..frames {
+                bugboy.frame_start();
+                runner
+                    .run_frame(&mut bugboy.gb)
+                    .map_err(|e| e.to_string())?;
+                bugboy
+                    .frame_done(runner.frames())
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        "hold" => {
+            let held = parse_buttons(&rest.concat())?;
+            for &button in Button::ALL.iter() {
+                bugboy.gb.set_button(button, held.contains(&button));
+            }
+        }
+        "filter" => {
+            let filter = rest.join(" ").parse()?;
+            let search = search.as_mut().ok_or_else(no_search)?;
+            println!("{} candidates", search.filter(&bugboy.gb, filter));
+        }
+        "snapshot" => search.as_mut().ok_or_else(no_search)?.snapshot(&bugboy.gb),
+        "list" => {
+            let count = match rest.first() {
+                Some(n) => parse_number(n)? as usize,
+                None => 20,
+            };
+            let search = search.as_ref().ok_or_else(no_search)?;
+            let candidates = search.candidates(&bugboy.gb);
+            for (i, c) in candidates.iter().take(count).enumerate() {
+                println!(
+                    "{:4} {:02X}:{:04X} {} {} (was {})",
+                    i,
+                    c.bank,
+                    c.addr,
+                    search.size(),
+                    c.value,
+                    c.previous
+                );
+            }
+            if candidates.len() > count {
+                println!("... and {} more", candidates.len() - count);
+            }
+        }
+        "save" => {
+            let path = rest.first().ok_or("save needs a file")?;
+            let search = search.as_ref().ok_or_else(no_search)?;
+            fs::write(path, search.watch_list(&bugboy.gb))
+                .map_err(|e| format!("{}: {}", path, e))?;
+        }
+        "code" => {
+            if rest.len() != 2 {
+                return Err(String::from("code needs a candidate number and a value"));
+            }
+            let index = parse_number(rest[0])? as usize;
+            let search = search.as_ref().ok_or_else(no_search)?;
+            let value = parse_number(rest[1])?;
+            if value > search.size().max() as u64 {
+                return Err(format!("{} doesn't fit in {}", rest[1], search.size()));
+            }
+            let candidates = search.candidates(&bugboy.gb);
+            let candidate = candidates
+                .get(index)
+                .ok_or_else(|| format!("there's no candidate {}", index))?;
+            let codes = search
+                .game_shark(&bugboy.gb, candidate, value as u16)
+                .map_err(|e| e.to_string())?;
+            println!("{}", codes.join(" "));
+        }
+        "quit" | "q" => return Ok(false),
+        other => return Err(format!("unknown command {}", other)),
+    }
+    Ok(true)
+}
+
+// An interactive RAM search, driven by commands on stdin. Returns the exit
+// code: 1 if any command failed, so scripts notice.
+fn search(args: &[String]) -> i32 {
+    let opts = match parse_run_options(args) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            eprintln!("{}", SEARCH_USAGE);
+            return 2;
+        }
+    };
+
+    let mut bugboy = match DmgBoy::load(&opts) {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("ERROR loading {}: {}", opts.rom, e);
+            return 1;
+        }
+    };
+
+    let mut runner = RunLoop::new();
+    runner.set_speed(Speed::Uncapped);
+    let mut search = None;
+    let mut failed = false;
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        match search_command(&mut bugboy, &mut runner, &mut search, &line) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                failed = true;
+            }
+        }
+    }
+
+    if let Err(e) = bugboy.save() {
+        eprintln!("ERROR saving: {}", e);
+        return 1;
+    }
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let (command, rest) = match args.first().map(String::as_str) {
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+        Some("-h") | Some("--help") | Some("help") => {
+            println!("{}", USAGE);
+            return;
+        }
+        Some(command) => match command {
+            "run" | "test" | "trace" | "disasm" | "info" | "patch" | "fix" | "search" => {
+                (command, &args[1..])
+            }
+            // a bare ROM path just runs it
+            _ => ("run", &args[..]),
+        },
+    };
+
+    if rest.iter().any(|a| a == "-h" || a == "--help") {
+        let usage = match command {
+            "disasm" => DISASM_USAGE,
+            "info" => INFO_USAGE,
+            "patch" => PATCH_USAGE,
+            "fix" => FIX_USAGE,
+            "search" => SEARCH_USAGE,
+            _ => RUN_USAGE,
+        };
+        println!("{}", usage);
+        return;
+    }
 
-    println!("Done.");
+    process::exit(match command {
+        "disasm" => disassemble(rest),
+        "info" => info(rest),
+        "patch" => patch(rest),
+        "fix" => fix_header(rest),
+        "search" => search(rest),
+        _ => run_command(command, rest),
+    });
 }
@@ -0,0 +1,394 @@
+//! Game Genie and GameShark cheats.
+//!
+//! A Game Genie sits between the cartridge and the console and changes what
+//! the CPU reads from ROM. Codes look like `ABC-DEF-GHI`: the new value, the
+//! address, and optionally the byte that has to be there already. The
+//! compare byte is what makes a code bank aware; the same address in other
+//! ROM banks holds something else and is left alone.
+//!
+//! A GameShark writes to RAM instead, once every frame during V-blank.
+//! Codes are `ttvvaaaa`: the type, the value and the address low byte
+//! first. Type `01` writes wherever the address is mapped, and `9X` writes
+//! CGB work RAM bank X whichever bank the game has switched in.
+//!
+//! Cheat files have a code per line, with an optional description after
+//! it. Lines starting with `#` are comments and a `-` before a code loads
+//! it switched off:
+//!
+//! ```text
+//! # Pocket Monsters
+//! 01FF57D1 Max money
+//! -00A-17B-C49 Walk through walls
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use gb_error::EmuError;
+
+fn bad<T>(code: &str, reason: &str) -> Result<T, EmuError> {
+    Err(EmuError::Cheat(format!("{}: {}", code, reason)))
+}
+
+fn hex_digits(code: &str) -> Option<Vec<u8>> {
+    code.chars()
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect()
+}
+
+/// A single decoded code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheatCode {
+    /// Reads of `addr` in ROM see `value`, if they'd have seen `compare`.
+    GameGenie {
+        addr: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+    /// `value` is written to `addr` every frame. `wram_bank` picks a CGB
+    /// work RAM bank for 0xD000-0xDFFF instead of the one switched in.
+    GameShark {
+        addr: u16,
+        value: u8,
+        wram_bank: Option<usize>,
+    },
+}
+
+impl FromStr for CheatCode {
+    type Err = EmuError;
+
+    fn from_str(code: &str) -> Result<Self, EmuError> {
+        let code = code.trim();
+        if code.contains('-') {
+            parse_game_genie(code)
+        } else if code.len() == 8 {
+            parse_game_shark(code)
+        } else {
+            bad(
+                code,
+                "expected a Game Genie code like ABC-DEF or ABC-DEF-GHI, or an 8 digit GameShark code",
+            )
+        }
+    }
+}
+
+fn parse_game_genie(code: &str) -> Result<CheatCode, EmuError> {
+    let groups: Vec<&str> = code.split('-').collect();
+    if !(groups.len() == 2 || groups.len() == 3) || groups.iter().any(|g| g.len() != 3) {
+        return bad(code, "Game Genie codes are 6 or 9 digits, in groups of 3");
+    }
+    let d = match hex_digits(&groups.concat()) {
+        Some(d) => d,
+        None => return bad(code, "Game Genie codes are hex digits"),
+    };
+
+    let value = d[0] << 4 | d[1];
+    let addr = ((d[5] ^ 0xF) as u16) << 12 | (d[2] as u16) << 8 | (d[3] as u16) << 4 | d[4] as u16;
+    if addr > 0x7FFF {
+        return bad(code, &format!("address {:04X} isn't in ROM", addr));
+    }
+    // the compare byte is scrambled: rotated 2 bits and XORed with 0xBA.
+    // The digit between its two halves doesn't affect anything.
+    let compare = if d.len() == 9 {
+        Some((d[6] << 4 | d[8]).rotate_right(2) ^ 0xBA)
+    } else {
+        None
+    };
+
+    Ok(CheatCode::GameGenie {
+        addr,
+        value,
+        compare,
+    })
+}
+
+fn parse_game_shark(code: &str) -> Result<CheatCode, EmuError> {
+    let d = match hex_digits(code) {
+        Some(d) => d,
+        None => return bad(code, "GameShark codes are hex digits"),
+    };
+    let byte = |i: usize| d[i * 2] << 4 | d[i * 2 + 1];
+
+    let value = byte(1);
+    let addr = (byte(3) as u16) << 8 | byte(2) as u16;
+    let wram_bank = match byte(0) {
+        0x00 | 0x01 => None,
+        // bank 0 can't be switched in, so it means 1 like SVBK
+        t @ 0x90..=0x97 => Some(((t & 0x07) as usize).max(1)),
+        t if t & 0xF0 == 0x80 => {
+            return bad(code, "cartridge RAM bank codes (8X) aren't supported")
+        }
+        t => return bad(code, &format!("unknown code type {:02X}", t)),
+    };
+
+    let ram = (0xA000..=0xDFFF).contains(&addr) || (0xFF80..=0xFFFE).contains(&addr);
+    if !ram {
+        return bad(code, &format!("address {:04X} isn't RAM", addr));
+    }
+    if wram_bank.is_some() && !(0xD000..=0xDFFF).contains(&addr) {
+        return bad(code, "a work RAM bank needs an address from D000 to DFFF");
+    }
+
+    Ok(CheatCode::GameShark {
+        addr,
+        value,
+        wram_bank,
+    })
+}
+
+/// A code as it was entered, and whether it's switched on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cheat {
+    pub text: String,
+    pub description: String,
+    pub code: CheatCode,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    pub fn new(text: &str) -> Result<Self, EmuError> {
+        Ok(Cheat {
+            text: text.trim().to_uppercase(),
+            description: String::new(),
+            code: text.parse()?,
+            enabled: true,
+        })
+    }
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.enabled {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", self.text)?;
+        if !self.description.is_empty() {
+            write!(f, " {}", self.description)?;
+        }
+        Ok(())
+    }
+}
+
+/// The cheats applied to a machine.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Cheats {
+    list: Vec<Cheat>,
+    // whether any enabled Game Genie code needs checking on ROM reads
+    patching: bool,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats::default()
+    }
+
+    /// Reads a cheat file, stopping at the first bad code with its line
+    /// number.
+    pub fn parse(text: &str) -> Result<Self, EmuError> {
+        let mut cheats = Cheats::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (enabled, line) = match line.strip_prefix('-') {
+                Some(rest) => (false, rest),
+                None => (true, line),
+            };
+            let (code, description) = match line.find(char::is_whitespace) {
+                Some(end) => (&line[..end], line[end..].trim()),
+                None => (line, ""),
+            };
+            let mut cheat = Cheat::new(code).map_err(|e| match e {
+                EmuError::Cheat(reason) => EmuError::Cheat(format!("line {}: {}", i + 1, reason)),
+                e => e,
+            })?;
+            cheat.description = String::from(description);
+            cheat.enabled = enabled;
+            cheats.add(cheat);
+        }
+        Ok(cheats)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, EmuError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| EmuError::Io(format!("{}: {}", path.display(), e)))?;
+        Cheats::parse(&text)
+    }
+
+    /// Looks in `dir` for the cheat file for a ROM, named after its CRC32
+    /// as 8 hex digits or after its header title, such as `1A2B3C4D.cht`
+    /// or `TETRIS.cht`.
+    pub fn find(dir: &Path, crc32: u32, title: &str) -> Option<PathBuf> {
+        let names = [
+            format!("{:08X}", crc32),
+            format!("{:08x}", crc32),
+            title.to_string(),
+        ];
+        names
+            .iter()
+            .filter(|name| !name.is_empty())
+            .map(|name| dir.join(format!("{}.cht", name)))
+            .find(|path| path.is_file())
+    }
+
+    /// Writes the cheats out in the file format.
+    pub fn to_text(&self) -> String {
+        self.list.iter().map(|c| format!("{}\n", c)).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cheat> {
+        self.list.iter()
+    }
+
+    pub fn add(&mut self, cheat: Cheat) {
+        self.list.push(cheat);
+        self.update();
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Cheat> {
+        if index >= self.list.len() {
+            return None;
+        }
+        let cheat = self.list.remove(index);
+        self.update();
+        Some(cheat)
+    }
+
+    /// Switches a cheat on or off, returning false if there's no such cheat.
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.list.get_mut(index) {
+            Some(cheat) => cheat.enabled = enabled,
+            None => return false,
+        }
+        self.update();
+        true
+    }
+
+    fn update(&mut self) {
+        self.patching = self
+            .list
+            .iter()
+            .any(|c| c.enabled && matches!(c.code, CheatCode::GameGenie { .. }));
+    }
+
+    /// What a read of ROM at `addr` sees, given the byte really there.
+    pub fn patch_rom(&self, addr: u16, byte: u8) -> u8 {
+        if !self.patching {
+            return byte;
+        }
+        for cheat in self.list.iter().filter(|c| c.enabled) {
+            if let CheatCode::GameGenie {
+                addr: a,
+                value,
+                compare,
+            } = cheat.code
+            {
+                if a == addr && compare.is_none_or(|c| c == byte) {
+                    return value;
+                }
+            }
+        }
+        byte
+    }
+
+    /// The GameShark writes due every frame, as (address, value, WRAM bank).
+    pub fn ram_writes(&self) -> impl Iterator<Item = (u16, u8, Option<usize>)> + '_ {
+        self.list
+            .iter()
+            .filter(|c| c.enabled)
+            .filter_map(|c| match c.code {
+                CheatCode::GameShark {
+                    addr,
+                    value,
+                    wram_bank,
+                } => Some((addr, value, wram_bank)),
+                CheatCode::GameGenie { .. } => None,
+            })
+    }
+}
+
+#[test]
+fn cheat_code_test() {
+    let code = |text: &str| text.parse::<CheatCode>();
+
+    // value 00, address 0x4A17 with the top nibble inverted, compare C9 from the
+    // seventh and ninth digits, rotated and scrambled
+    assert!(
+        code("00A-17B-C49").unwrap()
+            == CheatCode::GameGenie {
+                addr: 0x4A17,
+                value: 0x00,
+                compare: Some(0xC9u8.rotate_right(2) ^ 0xBA),
+            }
+    );
+    assert!(
+        code("3EA-0AF").unwrap()
+            == CheatCode::GameGenie {
+                addr: 0x0A0A,
+                value: 0x3E,
+                compare: None,
+            }
+    );
+    assert!(
+        code("01FF57D1").unwrap()
+            == CheatCode::GameShark {
+                addr: 0xD157,
+                value: 0xFF,
+                wram_bank: None,
+            }
+    );
+    assert!(
+        code("930A00D0").unwrap()
+            == CheatCode::GameShark {
+                addr: 0xD000,
+                value: 0x0A,
+                wram_bank: Some(3),
+            }
+    );
+
+    for bad in &[
+        "00A-17B-C4",
+        "00A-17G-C49",
+        "00A-177",
+        "00A-170",
+        "01FF0040",
+        "02FF00C0",
+        "810000A0",
+        "910000C0",
+        "12345",
+    ] {
+        assert!(code(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn cheats_test() {
+    let text = "# comment\n\n01FF57D1 Max money\n-00A-17B-C49 Off for now\n3EA-0AF\n";
+    let mut cheats = Cheats::parse(text).unwrap();
+    assert!(cheats.iter().count() == 3);
+    assert!(cheats.to_text() == "01FF57D1 Max money\n-00A-17B-C49 Off for now\n3EA-0AF\n");
+    assert!(cheats.ram_writes().collect::<Vec<_>>() == vec![(0xD157, 0xFF, None)]);
+
+    // compare bytes only patch the bank that holds them
+    let compare = 0xC9u8.rotate_right(2) ^ 0xBA;
+    assert!(cheats.patch_rom(0x4A17, compare) == compare);
+    cheats.set_enabled(1, true);
+    assert!(cheats.patch_rom(0x4A17, compare) == 0x00);
+    assert!(cheats.patch_rom(0x4A17, compare ^ 1) == compare ^ 1);
+    assert!(cheats.patch_rom(0x0A0A, 0x12) == 0x3E);
+    cheats.remove(2);
+    assert!(cheats.patch_rom(0x0A0A, 0x12) == 0x12);
+
+    match Cheats::parse("01FF57D1\nnonsense\n") {
+        Err(EmuError::Cheat(reason)) => assert!(reason.starts_with("line 2: ")),
+        _ => panic!("expected an error"),
+    }
+}
@@ -0,0 +1,143 @@
+//! Turns machine code back into RGBDS style assembly.
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "[HL]", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = [
+    "ADD A,", "ADC A,", "SUB", "SBC A,", "AND", "XOR", "OR", "CP",
+];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const ACC: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+/// One decoded instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    /// Bytes the instruction takes up, operands included.
+    pub len: usize,
+    pub text: String,
+}
+
+fn op(len: usize, text: String) -> Instruction {
+    Instruction { len, text }
+}
+
+/// Decodes the instruction at the start of `bytes`, which sits at `addr`.
+/// Relative jumps are shown with their target. Bytes that aren't an
+/// instruction, or one cut off by the end of `bytes`, come out as `db`.
+pub fn decode(bytes: &[u8], addr: u16) -> Instruction {
+    let data = match bytes.first() {
+        Some(b) => *b,
+        None => return op(0, String::new()),
+    };
+    let instruction = decode_op(bytes, addr);
+    if instruction.len > bytes.len() {
+        return op(1, format!("db ${:02X}", data));
+    }
+    instruction
+}
+
+fn decode_op(bytes: &[u8], addr: u16) -> Instruction {
+    let code = bytes[0];
+    let n8 = bytes.get(1).cloned().unwrap_or(0);
+    let n16 = u16::from_le_bytes([n8, bytes.get(2).cloned().unwrap_or(0)]);
+    let target = addr.wrapping_add(2).wrapping_add(n8 as i8 as u16);
+
+    let (x, y, z) = (code >> 6, ((code >> 3) & 7) as usize, (code & 7) as usize);
+    let (p, q) = (y >> 1, y & 1);
+
+    match (x, z) {
+        (0, 0) => match y {
+            0 => op(1, String::from("NOP")),
+            1 => op(3, format!("LD [${:04X}], SP", n16)),
+            2 => op(2, String::from("STOP")),
+            3 => op(2, format!("JR ${:04X}", target)),
+            _ => op(2, format!("JR {}, ${:04X}", CC[y - 4], target)),
+        },
+        (0, 1) if q == 0 => op(3, format!("LD {}, ${:04X}", RP[p], n16)),
+        (0, 1) => op(1, format!("ADD HL, {}", RP[p])),
+        (0, 2) => {
+            let mem = ["[BC]", "[DE]", "[HL+]", "[HL-]"][p];
+            if q == 0 {
+                op(1, format!("LD {}, A", mem))
+            } else {
+                op(1, format!("LD A, {}", mem))
+            }
+        }
+        (0, 3) if q == 0 => op(1, format!("INC {}", RP[p])),
+        (0, 3) => op(1, format!("DEC {}", RP[p])),
+        (0, 4) => op(1, format!("INC {}", R[y])),
+        (0, 5) => op(1, format!("DEC {}", R[y])),
+        (0, 6) => op(2, format!("LD {}, ${:02X}", R[y], n8)),
+        (0, _) => op(1, String::from(ACC[y])),
+        (1, 6) if y == 6 => op(1, String::from("HALT")),
+        (1, _) => op(1, format!("LD {}, {}", R[y], R[z])),
+        (2, _) => op(1, format!("{} {}", ALU[y], R[z])),
+        (_, 0) => match y {
+            0..=3 => op(1, format!("RET {}", CC[y])),
+            4 => op(2, format!("LDH [${:02X}], A", n8)),
+            5 => op(2, format!("ADD SP, {}", n8 as i8)),
+            6 => op(2, format!("LDH A, [${:02X}]", n8)),
+            _ => op(2, format!("LD HL, SP{:+}", n8 as i8)),
+        },
+        (_, 1) if q == 0 => op(1, format!("POP {}", RP2[p])),
+        (_, 1) => op(1, String::from(["RET", "RETI", "JP HL", "LD SP, HL"][p])),
+        (_, 2) => match y {
+            0..=3 => op(3, format!("JP {}, ${:04X}", CC[y], n16)),
+            4 => op(1, String::from("LDH [C], A")),
+            5 => op(3, format!("LD [${:04X}], A", n16)),
+            6 => op(1, String::from("LDH A, [C]")),
+            _ => op(3, format!("LD A, [${:04X}]", n16)),
+        },
+        (_, 3) => match y {
+            0 => op(3, format!("JP ${:04X}", n16)),
+            1 => {
+                let (x, y, z) = (n8 >> 6, ((n8 >> 3) & 7) as usize, (n8 & 7) as usize);
+                match x {
+                    0 => op(2, format!("{} {}", ROT[y], R[z])),
+                    1 => op(2, format!("BIT {}, {}", y, R[z])),
+                    2 => op(2, format!("RES {}, {}", y, R[z])),
+                    _ => op(2, format!("SET {}, {}", y, R[z])),
+                }
+            }
+            6 => op(1, String::from("DI")),
+            7 => op(1, String::from("EI")),
+            _ => op(1, format!("db ${:02X}", code)),
+        },
+        (_, 4) if y < 4 => op(3, format!("CALL {}, ${:04X}", CC[y], n16)),
+        (_, 5) if q == 0 => op(1, format!("PUSH {}", RP2[p])),
+        (_, 5) if p == 0 => op(3, format!("CALL ${:04X}", n16)),
+        (_, 6) => op(2, format!("{} ${:02X}", ALU[y], n8)),
+        (_, 7) => op(1, format!("RST ${:02X}", y * 8)),
+        _ => op(1, format!("db ${:02X}", code)),
+    }
+}
+
+#[test]
+fn decode_test() {
+    let cases: [(&[u8], &str); 10] = [
+        (&[0x00], "NOP"),
+        (&[0xC3, 0x50, 0x01], "JP $0150"),
+        (&[0x18, 0xFE], "JR $0100"),
+        (&[0x20, 0x05], "JR NZ, $0107"),
+        (&[0x7E], "LD A, [HL]"),
+        (&[0x96], "SUB [HL]"),
+        (&[0xE0, 0x40], "LDH [$40], A"),
+        (&[0xCB, 0x7C], "BIT 7, H"),
+        (&[0xF8, 0xFE], "LD HL, SP-2"),
+        (&[0xD3], "db $D3"),
+    ];
+    for &(bytes, text) in cases.iter() {
+        let instruction = decode(bytes, 0x0100);
+        assert!(
+            instruction.text == text,
+            "{:?} gave {}",
+            bytes,
+            instruction.text
+        );
+        assert!(instruction.len == bytes.len());
+    }
+
+    // cut off by the end of the data
+    assert!(decode(&[0xC3, 0x50], 0x0100) == op(1, String::from("db $C3")));
+}
@@ -0,0 +1,139 @@
+//! rgbfix-style header repair: rewrites header fields, pads the ROM to a size
+//! the header can describe and recomputes both checksums.
+
+use gb_error::EmuError;
+use gb_rom::NINTENDO_LOGO;
+
+const MIN_ROM_SIZE: usize = 0x8000;
+const MAX_ROM_SIZE: usize = 0x80_0000;
+
+const TITLE_ADDR: usize = 0x0134;
+const MFG_CODE_ADDR: usize = 0x013F;
+
+/// Header fields to rewrite. Fields left as `None` are kept as they are.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderFix {
+    pub title: Option<String>,
+    pub mfg_code: Option<String>,
+    pub cgb_flag: Option<u8>,
+    /// Two characters. Also sets the old licensee to 0x33, which tells the
+    /// hardware to look here, unless `old_licensee` is given too.
+    pub new_licensee: Option<String>,
+    pub old_licensee: Option<u8>,
+    pub sgb_flag: Option<u8>,
+    pub cart_type: Option<u8>,
+    pub ram_size: Option<u8>,
+    pub region: Option<u8>,
+    pub version: Option<u8>,
+    /// Writes the Nintendo logo the boot ROM checks for.
+    pub logo: bool,
+    /// The byte to pad the ROM out with.
+    pub pad_value: u8,
+}
+
+fn field_error<T>(offset: usize, reason: String) -> Result<T, EmuError> {
+    Err(EmuError::RomParse { offset, reason })
+}
+
+fn write_text(rom: &mut [u8], addr: usize, len: usize, text: &str) -> Result<(), EmuError> {
+    if text.len() > len {
+        return field_error(addr, format!("\"{}\" is longer than {} bytes", text, len));
+    }
+
+    let field = &mut rom[addr..addr + len];
+    for b in field.iter_mut() {
+        *b = 0;
+    }
+    field[..text.len()].copy_from_slice(text.as_bytes());
+    Ok(())
+}
+
+/// Applies `fix` to a copy of `rom` and returns a ROM that validates.
+pub fn fix(rom: &[u8], fix: &HeaderFix) -> Result<Vec<u8>, EmuError> {
+    // pad to the next size the header can describe
+    let size = rom.len().max(MIN_ROM_SIZE).next_power_of_two();
+    if size > MAX_ROM_SIZE {
+        return field_error(0x0148, format!("{} bytes is too big for a ROM", rom.len()));
+    }
+    let mut out = rom.to_vec();
+    out.resize(size, fix.pad_value);
+
+    if fix.logo {
+        out[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+    }
+
+    // the manufacturer code takes the end of the title, so write it after
+    if let Some(ref title) = fix.title {
+        let len = if fix.mfg_code.is_some() { 11 } else { 15 };
+        write_text(&mut out, TITLE_ADDR, len, title)?;
+    }
+    if let Some(ref code) = fix.mfg_code {
+        write_text(&mut out, MFG_CODE_ADDR, 4, code)?;
+    }
+    if let Some(ref licensee) = fix.new_licensee {
+        if licensee.len() != 2 {
+            return field_error(
+                0x0144,
+                format!("licensee \"{}\" isn't two characters", licensee),
+            );
+        }
+        out[0x0144..0x0146].copy_from_slice(licensee.as_bytes());
+        out[0x014B] = 0x33;
+    }
+
+    let fields = [
+        (0x0143, fix.cgb_flag),
+        (0x0146, fix.sgb_flag),
+        (0x0147, fix.cart_type),
+        (0x0149, fix.ram_size),
+        (0x014A, fix.region),
+        (0x014B, fix.old_licensee),
+        (0x014C, fix.version),
+    ];
+    for &(addr, val) in fields.iter() {
+        if let Some(val) = val {
+            out[addr] = val;
+        }
+    }
+    out[0x0148] = (size / MIN_ROM_SIZE).trailing_zeros() as u8;
+
+    out[0x014D] = out[TITLE_ADDR..0x014D]
+        .iter()
+        .fold(0u8, |x, b| x.wrapping_sub(*b).wrapping_sub(1));
+
+    out[0x014E] = 0;
+    out[0x014F] = 0;
+    let global = out.iter().fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+    out[0x014E] = (global >> 8) as u8;
+    out[0x014F] = global as u8;
+    Ok(out)
+}
+
+#[test]
+fn fix_validates_test() {
+    use gb_rom::GbRom;
+
+    let header = HeaderFix {
+        title: Some(String::from("HOMEBREW")),
+        mfg_code: Some(String::from("ABCE")),
+        cgb_flag: Some(0x80),
+        new_licensee: Some(String::from("01")),
+        cart_type: Some(0x01),
+        logo: true,
+        pad_value: 0xFF,
+        ..HeaderFix::default()
+    };
+    let out = fix(&[0x00; 0x9000], &header).unwrap();
+    assert!(out.len() == 0x10000);
+    assert!(out[0xFFFF] == 0xFF);
+
+    let rom = GbRom::from_bytes(out).unwrap();
+    assert!(rom.validation().is_valid());
+    assert!(rom.title() == "HOMEBREW");
+
+    let long = HeaderFix {
+        title: Some(String::from("A TITLE THAT IS TOO LONG")),
+        ..HeaderFix::default()
+    };
+    assert!(fix(&[], &long).is_err());
+}
@@ -0,0 +1,562 @@
+use gb_error::EmuError;
+use gb_state::{StateReader, StateWriter};
+use gb_system::AUDIO_SAMPLE_RATE;
+use runloop::CPU_HZ;
+
+const APU_START: u16 = 0xFF10;
+const NR52_ADDR: u16 = 0xFF26;
+const WAVE_RAM_START: u16 = 0xFF30;
+
+// Register indices. Each channel has five, NRx0 to NRx4, starting at 5x.
+const NR10: usize = 0x00;
+const NR30: usize = 0x0A;
+const NR31: usize = 0x0B;
+const NR32: usize = 0x0C;
+const NR43: usize = 0x12;
+const NR50: usize = 0x14;
+const NR51: usize = 0x15;
+const NR52: usize = 0x16;
+
+const NR52_POWER: u8 = 1 << 7;
+
+// Bits that always read back as 1, unused slots included
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+// Register values the DMG boot ROM leaves behind
+const POST_BOOT: [u8; 0x17] = [
+    0x80, 0xBF, 0xF3, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x77, 0xF3, 0xF1, // NR50-NR52
+];
+
+// The eight steps of each square wave duty cycle, first step in the top bit
+const DUTY_CYCLES: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+// Length, sweep and envelope are clocked by a 512 Hz frame sequencer
+const FRAME_SEQUENCER_DOTS: u64 = 8192;
+
+// How much charge the output capacitor keeps each sample. It takes out the
+// DC offset the DACs add, like the real console's does.
+const HIGH_PASS: f32 = 0.996;
+
+// Samples held for a host that isn't taking them, a second's worth
+const MAX_BUFFERED: usize = AUDIO_SAMPLE_RATE as usize * 2;
+
+// What a channel is doing beyond what its registers say
+#[derive(Debug, Default, Clone, Copy)]
+struct Channel {
+    on: bool,
+    dac: bool,
+    length: u16,
+    // dots until the waveform moves on a step
+    timer: u32,
+    // step through the duty cycle or wave RAM
+    pos: u8,
+    volume: u8,
+    envelope_timer: u8,
+}
+
+// Sound registers (0xFF10-0xFF26) and wave RAM (0xFF30-0xFF3F), and the two
+// square, wave and noise channels they drive
+#[derive(Debug)]
+pub struct Apu {
+    regs: [u8; 0x17],
+    wave_ram: [u8; 0x10],
+
+    channels: [Channel; 4],
+    // the noise channel's shift register
+    lfsr: u16,
+    // channel 1's frequency sweep
+    sweep_freq: u16,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+
+    frame_step: u8,
+    frame_timer: u64,
+    // counts up by the sample rate each dot, so a sample is due every time
+    // it passes CPU_HZ
+    sample_clock: u64,
+    capacitors: [f32; 2],
+    samples: Vec<i16>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        let mut channels = [Channel::default(); 4];
+        // the boot sound has faded out by the time the game starts
+        channels[0].on = true;
+        channels[0].dac = true;
+
+        Apu {
+            regs: POST_BOOT,
+            wave_ram: [0u8; 0x10],
+            channels,
+            lfsr: 0,
+            sweep_freq: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            frame_step: 0,
+            frame_timer: FRAME_SEQUENCER_DOTS,
+            sample_clock: 0,
+            capacitors: [0.0; 2],
+            samples: Vec::new(),
+        }
+    }
+
+    fn powered(&self) -> bool {
+        self.regs[NR52] & NR52_POWER != 0
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        if addr >= WAVE_RAM_START {
+            return self.wave_ram[(addr - WAVE_RAM_START) as usize];
+        }
+
+        let idx = (addr - APU_START) as usize;
+        if idx == NR52 {
+            let playing = (0..4)
+                .filter(|&ch| self.channels[ch].on)
+                .fold(0, |bits, ch| bits | 1 << ch);
+            return self.regs[NR52] & NR52_POWER | playing | READ_MASKS[NR52];
+        }
+        match self.regs.get(idx) {
+            Some(val) => val | READ_MASKS[idx],
+            None => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        if addr >= WAVE_RAM_START {
+            self.wave_ram[(addr - WAVE_RAM_START) as usize] = val;
+            return;
+        }
+
+        if addr == NR52_ADDR {
+            if val & NR52_POWER == 0 {
+                // powering off clears every register and silences everything
+                self.regs = [0u8; 0x17];
+                self.channels = [Channel::default(); 4];
+            } else if !self.powered() {
+                self.regs[NR52] |= NR52_POWER;
+                self.frame_step = 0;
+            }
+            return;
+        }
+
+        let idx = (addr - APU_START) as usize;
+        if !self.powered() || idx >= self.regs.len() {
+            return;
+        }
+        self.regs[idx] = val;
+
+        let ch = idx / 5;
+        match idx {
+            // lengths count up to 64, or 256 for the wave channel
+            0x01 | 0x06 | 0x10 => self.channels[ch].length = 64 - (val & 0x3F) as u16,
+            NR31 => self.channels[ch].length = 256 - val as u16,
+            // the DAC is off when the channel could only make silence, and
+            // takes the channel with it
+            0x02 | 0x07 | 0x11 | NR30 => {
+                let dac = if idx == NR30 {
+                    val & 0x80 != 0
+                } else {
+                    val & 0xF8 != 0
+                };
+                self.channels[ch].dac = dac;
+                self.channels[ch].on &= dac;
+            }
+            0x04 | 0x09 | 0x0E | 0x13 if val & 0x80 != 0 => self.trigger(ch),
+            _ => {}
+        }
+    }
+
+    fn freq(&self, ch: usize) -> u16 {
+        self.regs[ch * 5 + 3] as u16 | ((self.regs[ch * 5 + 4] & 0x07) as u16) << 8
+    }
+
+    // Dots between steps of the channel's waveform
+    fn period(&self, ch: usize) -> u32 {
+        match ch {
+            0 | 1 => (2048 - self.freq(ch) as u32) * 4,
+            2 => (2048 - self.freq(ch) as u32) * 2,
+            _ => {
+                let nr43 = self.regs[NR43];
+                let divisor = match nr43 & 0x07 {
+                    0 => 8,
+                    d => d as u32 * 16,
+                };
+                divisor << (nr43 >> 4)
+            }
+        }
+    }
+
+    fn trigger(&mut self, ch: usize) {
+        let period = self.period(ch);
+        let envelope = self.regs[ch * 5 + 2];
+        let c = &mut self.channels[ch];
+        c.on = c.dac;
+        if c.length == 0 {
+            c.length = if ch == 2 { 256 } else { 64 };
+        }
+        c.timer = period;
+        c.pos = 0;
+        c.volume = envelope >> 4;
+        c.envelope_timer = envelope & 0x07;
+
+        match ch {
+            0 => {
+                let nr10 = self.regs[NR10];
+                self.sweep_freq = self.freq(0);
+                self.sweep_timer = match (nr10 >> 4) & 0x07 {
+                    0 => 8,
+                    p => p,
+                };
+                self.sweep_enabled = nr10 & 0x77 != 0;
+                if nr10 & 0x07 != 0 {
+                    self.sweep_next();
+                }
+            }
+            3 => self.lfsr = 0x7FFF,
+            _ => {}
+        }
+    }
+
+    // The frequency the sweep moves channel 1 to next. Going past the top
+    // turns the channel off.
+    fn sweep_next(&mut self) -> u16 {
+        let nr10 = self.regs[NR10];
+        let delta = self.sweep_freq >> (nr10 & 0x07);
+        let next = if nr10 & 0x08 != 0 {
+            self.sweep_freq - delta
+        } else {
+            self.sweep_freq + delta
+        };
+        if next > 2047 {
+            self.channels[0].on = false;
+        }
+        next
+    }
+
+    fn clock_sweep(&mut self) {
+        let nr10 = self.regs[NR10];
+        let period = (nr10 >> 4) & 0x07;
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer > 0 {
+            return;
+        }
+        self.sweep_timer = if period == 0 { 8 } else { period };
+        if !self.sweep_enabled || period == 0 {
+            return;
+        }
+
+        let next = self.sweep_next();
+        if next <= 2047 && nr10 & 0x07 != 0 {
+            self.sweep_freq = next;
+            self.regs[0x03] = next as u8;
+            self.regs[0x04] = self.regs[0x04] & !0x07 | (next >> 8) as u8;
+            // checked again straight away, but only to overflow
+            self.sweep_next();
+        }
+    }
+
+    fn clock_lengths(&mut self) {
+        for (ch, c) in self.channels.iter_mut().enumerate() {
+            if self.regs[ch * 5 + 4] & 0x40 != 0 && c.length > 0 {
+                c.length -= 1;
+                if c.length == 0 {
+                    c.on = false;
+                }
+            }
+        }
+    }
+
+    fn clock_envelopes(&mut self) {
+        for ch in [0, 1, 3] {
+            let envelope = self.regs[ch * 5 + 2];
+            let period = envelope & 0x07;
+            let c = &mut self.channels[ch];
+            if period == 0 {
+                continue;
+            }
+            c.envelope_timer = c.envelope_timer.saturating_sub(1);
+            if c.envelope_timer == 0 {
+                c.envelope_timer = period;
+                if envelope & 0x08 != 0 {
+                    c.volume = (c.volume + 1).min(15);
+                } else {
+                    c.volume = c.volume.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        match self.frame_step {
+            0 | 4 => self.clock_lengths(),
+            2 | 6 => {
+                self.clock_lengths();
+                self.clock_sweep();
+            }
+            7 => self.clock_envelopes(),
+            _ => {}
+        }
+        self.frame_step = (self.frame_step + 1) & 0x07;
+    }
+
+    // Moves every playing channel's waveform on by `dots`
+    fn advance(&mut self, dots: u32) {
+        for ch in 0..4 {
+            if !self.channels[ch].on {
+                continue;
+            }
+            let period = self.period(ch);
+            let mut left = dots;
+            while left >= self.channels[ch].timer {
+                left -= self.channels[ch].timer;
+                self.channels[ch].timer = period;
+                match ch {
+                    0 | 1 => self.channels[ch].pos = (self.channels[ch].pos + 1) & 0x07,
+                    2 => self.channels[ch].pos = (self.channels[ch].pos + 1) & 0x1F,
+                    _ => {
+                        let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+                        self.lfsr = (self.lfsr >> 1) | bit << 14;
+                        // the short mode repeats every 127 steps
+                        if self.regs[NR43] & 0x08 != 0 {
+                            self.lfsr = self.lfsr & !(1 << 6) | bit << 6;
+                        }
+                    }
+                }
+            }
+            self.channels[ch].timer -= left;
+        }
+    }
+
+    // The channel's output right now, 0-15
+    fn output(&self, ch: usize) -> u8 {
+        let c = &self.channels[ch];
+        if !c.on {
+            return 0;
+        }
+        match ch {
+            0 | 1 => {
+                let duty = DUTY_CYCLES[(self.regs[ch * 5 + 1] >> 6) as usize];
+                ((duty >> (7 - c.pos)) & 1) * c.volume
+            }
+            2 => {
+                let byte = self.wave_ram[(c.pos / 2) as usize];
+                let sample = if c.pos.is_multiple_of(2) {
+                    byte >> 4
+                } else {
+                    byte & 0x0F
+                };
+                match (self.regs[NR32] >> 5) & 0x03 {
+                    0 => 0,
+                    shift => sample >> (shift - 1),
+                }
+            }
+            _ => {
+                if self.lfsr & 1 == 0 {
+                    c.volume
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    // One stereo sample of every channel through its DAC, panned and scaled
+    // by the master volume
+    fn mix(&mut self) -> [i16; 2] {
+        let mut mixed = [0i32; 2];
+        if self.powered() {
+            let panning = self.regs[NR51];
+            for ch in 0..4 {
+                if !self.channels[ch].dac {
+                    continue;
+                }
+                let analog = 2 * self.output(ch) as i32 - 15;
+                if panning & (0x10 << ch) != 0 {
+                    mixed[0] += analog;
+                }
+                if panning & (1 << ch) != 0 {
+                    mixed[1] += analog;
+                }
+            }
+            let volume = self.regs[NR50];
+            mixed[0] *= ((volume >> 4) & 0x07) as i32 + 1;
+            mixed[1] *= (volume & 0x07) as i32 + 1;
+        }
+
+        // at most 4 channels of 15 at 8 times volume, scaled up to fill i16
+        let mut out = [0i16; 2];
+        for side in 0..2 {
+            let input = (mixed[side] * 64) as f32;
+            let filtered = input - self.capacitors[side];
+            self.capacitors[side] = input - filtered * HIGH_PASS;
+            out[side] = filtered.clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+        out
+    }
+
+    /// Runs the channels for `dots` of the 4 MiHz clock, which keeps going
+    /// at the same rate in double speed.
+    pub fn step(&mut self, dots: u64) {
+        let rate = AUDIO_SAMPLE_RATE as u64;
+        let mut dots = dots;
+        while dots > 0 {
+            // up to whichever comes first of the next sample and the next
+            // frame sequencer step
+            let to_sample = (CPU_HZ - self.sample_clock).div_ceil(rate);
+            let n = dots.min(to_sample).min(self.frame_timer);
+            if self.powered() {
+                self.advance(n as u32);
+            }
+            dots -= n;
+
+            self.frame_timer -= n;
+            if self.frame_timer == 0 {
+                self.frame_timer = FRAME_SEQUENCER_DOTS;
+                if self.powered() {
+                    self.clock_frame_sequencer();
+                }
+            }
+
+            self.sample_clock += n * rate;
+            if self.sample_clock >= CPU_HZ {
+                self.sample_clock -= CPU_HZ;
+                let sample = self.mix();
+                if self.samples.len() >= MAX_BUFFERED {
+                    self.samples.drain(..MAX_BUFFERED / 2);
+                }
+                self.samples.extend_from_slice(&sample);
+            }
+        }
+    }
+
+    /// Drains the interleaved stereo samples made since the last call.
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.samples)
+    }
+
+    // Sound waiting to be taken belongs to the host, so isn't saved
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.put_bytes(&self.regs);
+        state.put_bytes(&self.wave_ram);
+        for c in &self.channels {
+            state.put_bool(c.on);
+            state.put_bool(c.dac);
+            state.put_u16(c.length);
+            state.put_u32(c.timer);
+            state.put_u8(c.pos);
+            state.put_u8(c.volume);
+            state.put_u8(c.envelope_timer);
+        }
+        state.put_u16(self.lfsr);
+        state.put_u16(self.sweep_freq);
+        state.put_u8(self.sweep_timer);
+        state.put_bool(self.sweep_enabled);
+        state.put_u8(self.frame_step);
+        state.put_u64(self.frame_timer);
+        state.put_u64(self.sample_clock);
+        for capacitor in &self.capacitors {
+            state.put_u32(capacitor.to_bits());
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmuError> {
+        state.get_bytes_into(&mut self.regs)?;
+        state.get_bytes_into(&mut self.wave_ram)?;
+        for c in self.channels.iter_mut() {
+            c.on = state.get_bool()?;
+            c.dac = state.get_bool()?;
+            c.length = state.get_u16()?;
+            c.timer = state.get_u32()?;
+            c.pos = state.get_u8()? & 0x1F;
+            c.volume = state.get_u8()? & 0x0F;
+            c.envelope_timer = state.get_u8()?;
+        }
+        self.lfsr = state.get_u16()?;
+        self.sweep_freq = state.get_u16()?;
+        self.sweep_timer = state.get_u8()?;
+        self.sweep_enabled = state.get_bool()?;
+        self.frame_step = state.get_u8()? & 0x07;
+        self.frame_timer = state.get_u64()?.clamp(1, FRAME_SEQUENCER_DOTS);
+        self.sample_clock = state.get_u64()? % CPU_HZ;
+        for capacitor in self.capacitors.iter_mut() {
+            *capacitor = f32::from_bits(state.get_u32()?);
+        }
+        self.samples.clear();
+        Ok(())
+    }
+}
+
+#[test]
+fn apu_read_mask_test() {
+    let mut apu = Apu::new();
+    apu.write(0xFF11, 0x00);
+    assert!(apu.read(0xFF11) == 0x3F);
+    assert!(apu.read(0xFF15) == 0xFF);
+    assert!(apu.read(0xFF27) == 0xFF);
+
+    apu.write(0xFF26, 0x00);
+    apu.write(0xFF12, 0xF0);
+    assert!(apu.read(0xFF12) == 0x00);
+    assert!(apu.read(0xFF26) == 0x70);
+}
+
+#[test]
+fn apu_sound_test() {
+    let mut apu = Apu::new();
+    apu.take_samples();
+
+    // channel 2 at full volume, a 50% square of 131072 / (2048 - 1798) =
+    // 524 Hz, in both ears, with its length running out after 1/256 s
+    apu.write(0xFF24, 0x77);
+    apu.write(0xFF25, 0x22);
+    apu.write(0xFF16, 0x80 | 63);
+    apu.write(0xFF17, 0xF0);
+    apu.write(0xFF18, (1798 & 0xFF) as u8);
+    apu.write(0xFF19, 0x80 | (1798 >> 8) as u8);
+    assert!(apu.read(0xFF26) & 0x02 != 0);
+
+    // a second makes a second of sound
+    apu.step(CPU_HZ);
+    let samples = apu.take_samples();
+    assert!(samples.len() == 2 * AUDIO_SAMPLE_RATE as usize);
+    assert!(samples.iter().any(|&s| s > 1000) && samples.iter().any(|&s| s < -1000));
+    assert!(samples.chunks(2).all(|lr| lr[0] == lr[1]));
+
+    // with the length counter on the note stops at the next length clock
+    apu.write(0xFF16, 0x80 | 63);
+    apu.write(0xFF19, 0xC0 | (1798 >> 8) as u8);
+    assert!(apu.read(0xFF26) & 0x02 != 0);
+    apu.step(FRAME_SEQUENCER_DOTS * 2);
+    assert!(apu.read(0xFF26) & 0x02 == 0);
+
+    // the state carries everything needed to carry on the same
+    apu.write(0xFF19, 0x80 | (1798 >> 8) as u8);
+    apu.step(1000);
+    let mut state = StateWriter::new();
+    apu.save_state(&mut state);
+    let state = state.finish();
+    let mut copy = Apu::new();
+    copy.load_state(&mut StateReader::new(&state)).unwrap();
+    apu.take_samples();
+    apu.step(CPU_HZ / 100);
+    copy.step(CPU_HZ / 100);
+    assert!(apu.take_samples() == copy.take_samples());
+
+    // nothing comes out powered off, past the last of the filter's charge
+    apu.write(0xFF26, 0x00);
+    apu.step(CPU_HZ / 10);
+    let samples = apu.take_samples();
+    assert!(samples[samples.len() - 2..].iter().all(|&s| s.abs() < 16));
+}
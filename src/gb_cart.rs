@@ -0,0 +1,334 @@
+use gb_error::EmuError;
+use gb_rom::{CartType, GbRom};
+use gb_state::{StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+// MBC2 carries 512 half-bytes of RAM inside the controller
+const MBC2_RAM_SIZE: usize = 0x200;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mbc {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+impl Mbc {
+    fn from_cart_type(cart_type: CartType) -> Self {
+        match cart_type {
+            CartType::MBC1 | CartType::MBC1_RAM | CartType::MBC1_RAM_BATTERY => Mbc::Mbc1,
+            CartType::MBC2 | CartType::MBC2_BATTERY => Mbc::Mbc2,
+            CartType::MBC3
+            | CartType::MBC3_RAM
+            | CartType::MBC3_RAM_BATTERY
+            | CartType::MBC3_TIMER_BATTERY
+            | CartType::MBC3_TIMER_RAM_BATTERY => Mbc::Mbc3,
+            CartType::MBC5
+            | CartType::MBC5_RAM
+            | CartType::MBC5_RAM_BATTERY
+            | CartType::MBC5_RUMBLE
+            | CartType::MBC5_RUMBLE_RAM
+            | CartType::MBC5_RUMBLE_RAM_BATTERY => Mbc::Mbc5,
+            // everything else is treated as a plain 32 KiB cart until it
+            // gets a mapper of its own
+            _ => Mbc::None,
+        }
+    }
+}
+
+// The cartridge slot: ROM at 0x0000-0x7FFF and external RAM at 0xA000-0xBFFF,
+// with the bank controller listening to writes into the ROM area.
+#[derive(Debug)]
+pub struct Cartridge {
+    rom: GbRom,
+    ram: Vec<u8>,
+    mbc: Mbc,
+
+    ram_enabled: bool,
+    rom_bank: usize,
+    ram_bank: usize, // MBC1 uses this for the upper ROM bits too
+    banking_mode: bool,
+    rtc: [u8; 5],
+}
+
+impl Cartridge {
+    pub fn new(rom: GbRom) -> Self {
+        let mbc = Mbc::from_cart_type(rom.cart_type());
+        let ram_size = match mbc {
+            Mbc::Mbc2 => MBC2_RAM_SIZE,
+            _ => rom.ram_size().bytes(),
+        };
+
+        Cartridge {
+            rom,
+            ram: vec![0u8; ram_size],
+            mbc,
+
+            // carts without a controller have nothing to enable
+            ram_enabled: mbc == Mbc::None,
+            rom_bank: 1,
+            ram_bank: 0,
+            banking_mode: false,
+            rtc: [0u8; 5],
+        }
+    }
+
+    pub fn rom(&self) -> &GbRom {
+        &self.rom
+    }
+
+    // All of the external RAM, every bank
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    // RAM that keeps its contents with the power off
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        let battery = matches!(
+            self.rom.cart_type(),
+            CartType::MBC1_RAM_BATTERY
+                | CartType::MBC2_BATTERY
+                | CartType::ROM_RAM_BATTERY
+                | CartType::MMM01_RAM_BATTERY
+                | CartType::MBC3_TIMER_BATTERY
+                | CartType::MBC3_TIMER_RAM_BATTERY
+                | CartType::MBC3_RAM_BATTERY
+                | CartType::MBC5_RAM_BATTERY
+                | CartType::MBC5_RUMBLE_RAM_BATTERY
+                | CartType::MBC7_SENSOR_RUMBLE_RAM_BATTERY
+                | CartType::HuC1_RAM_BATTERY
+        );
+        if battery && !self.ram.is_empty() {
+            Some(&self.ram)
+        } else {
+            None
+        }
+    }
+
+    pub fn load_ram(&mut self, data: &[u8]) -> Result<(), EmuError> {
+        if data.len() != self.ram.len() {
+            return Err(EmuError::StateLoad(format!(
+                "save is {} bytes, the cart has {}",
+                data.len(),
+                self.ram.len()
+            )));
+        }
+        self.ram.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        (self.rom.data().len() / ROM_BANK_SIZE).max(1)
+    }
+
+    // bank mapped at 0x0000-0x3FFF
+    fn low_rom_bank(&self) -> usize {
+        match self.mbc {
+            Mbc::Mbc1 if self.banking_mode => self.ram_bank << 5,
+            _ => 0,
+        }
+    }
+
+    // bank mapped at 0x4000-0x7FFF
+    pub fn current_rom_bank(&self) -> usize {
+        let bank = match self.mbc {
+            Mbc::None => 1,
+            Mbc::Mbc1 => {
+                let low = self.rom_bank & 0x1F;
+                (if low == 0 { 1 } else { low }) | (self.ram_bank << 5)
+            }
+            Mbc::Mbc2 => {
+                let low = self.rom_bank & 0x0F;
+                if low == 0 {
+                    1
+                } else {
+                    low
+                }
+            }
+            Mbc::Mbc3 => {
+                let low = self.rom_bank & 0x7F;
+                if low == 0 {
+                    1
+                } else {
+                    low
+                }
+            }
+            Mbc::Mbc5 => self.rom_bank & 0x1FF,
+        };
+        bank % self.rom_bank_count()
+    }
+
+    fn current_ram_bank(&self) -> usize {
+        match self.mbc {
+            Mbc::Mbc1 if self.banking_mode => self.ram_bank,
+            Mbc::Mbc1 | Mbc::Mbc2 | Mbc::None => 0,
+            Mbc::Mbc3 | Mbc::Mbc5 => self.ram_bank,
+        }
+    }
+
+    pub fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 {
+            self.low_rom_bank() % self.rom_bank_count()
+        } else {
+            self.current_rom_bank()
+        };
+
+        let idx = bank * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+        match self.rom.data().get(idx) {
+            Some(val) => *val,
+            None => 0xFF,
+        }
+    }
+
+    // Writes into the ROM area are commands for the bank controller.
+    pub fn write_rom(&mut self, addr: u16, val: u8) {
+        match self.mbc {
+            Mbc::None => (),
+            Mbc::Mbc1 => match addr {
+                0x0000..=0x1FFF => self.ram_enabled = val & 0x0F == 0x0A,
+                0x2000..=0x3FFF => self.rom_bank = (val & 0x1F) as usize,
+                0x4000..=0x5FFF => self.ram_bank = (val & 0x03) as usize,
+                _ => self.banking_mode = val & 0x01 != 0,
+            },
+            Mbc::Mbc2 => {
+                if addr < 0x4000 {
+                    // address bit 8 picks between RAM enable and ROM bank
+                    if addr & 0x0100 == 0 {
+                        self.ram_enabled = val & 0x0F == 0x0A;
+                    } else {
+                        self.rom_bank = (val & 0x0F) as usize;
+                    }
+                }
+            }
+            Mbc::Mbc3 => match addr {
+                0x0000..=0x1FFF => self.ram_enabled = val & 0x0F == 0x0A,
+                0x2000..=0x3FFF => self.rom_bank = (val & 0x7F) as usize,
+                0x4000..=0x5FFF => self.ram_bank = val as usize,
+                _ => {
+                    // RTC latch, the clock itself isn't running yet
+                }
+            },
+            Mbc::Mbc5 => match addr {
+                0x0000..=0x1FFF => self.ram_enabled = val & 0x0F == 0x0A,
+                0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | val as usize,
+                0x3000..=0x3FFF => {
+                    self.rom_bank = (self.rom_bank & 0xFF) | ((val as usize & 0x01) << 8)
+                }
+                0x4000..=0x5FFF => self.ram_bank = (val & 0x0F) as usize,
+                _ => (),
+            },
+        }
+    }
+
+    fn ram_index(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+
+        let offset = match self.mbc {
+            Mbc::Mbc2 => addr as usize & (MBC2_RAM_SIZE - 1),
+            _ => self.current_ram_bank() * RAM_BANK_SIZE + (addr as usize & (RAM_BANK_SIZE - 1)),
+        };
+        Some(offset % self.ram.len())
+    }
+
+    fn rtc_register(&self) -> Option<usize> {
+        match self.mbc {
+            Mbc::Mbc3 if self.ram_enabled && (0x08..=0x0C).contains(&self.ram_bank) => {
+                Some(self.ram_bank - 0x08)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        if let Some(reg) = self.rtc_register() {
+            return self.rtc[reg];
+        }
+
+        match self.ram_index(addr) {
+            Some(idx) if self.mbc == Mbc::Mbc2 => self.ram[idx] | 0xF0,
+            Some(idx) => self.ram[idx],
+            None => 0xFF,
+        }
+    }
+
+    pub fn write_ram(&mut self, addr: u16, val: u8) {
+        if let Some(reg) = self.rtc_register() {
+            self.rtc[reg] = val;
+            return;
+        }
+
+        if let Some(idx) = self.ram_index(addr) {
+            self.ram[idx] = if self.mbc == Mbc::Mbc2 {
+                val & 0x0F
+            } else {
+                val
+            };
+        }
+    }
+
+    // The ROM and mapper come from the image, so only the banking state and
+    // RAM are saved
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.put_bytes(&self.ram);
+        state.put_bool(self.ram_enabled);
+        state.put_u64(self.rom_bank as u64);
+        state.put_u64(self.ram_bank as u64);
+        state.put_bool(self.banking_mode);
+        state.put_bytes(&self.rtc);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmuError> {
+        state.get_bytes_into(&mut self.ram)?;
+        self.ram_enabled = state.get_bool()?;
+        self.rom_bank = state.get_index(0x200)?;
+        self.ram_bank = state.get_index(0x100)?;
+        self.banking_mode = state.get_bool()?;
+        state.get_bytes_into(&mut self.rtc)
+    }
+}
+
+#[cfg(test)]
+fn test_cart(cart_type: u8, banks: usize, ram_size: u8) -> Cartridge {
+    let mut data = vec![0u8; banks * ROM_BANK_SIZE];
+    for bank in 0..banks {
+        data[bank * ROM_BANK_SIZE] = bank as u8;
+    }
+    data[0x0147] = cart_type;
+    data[0x0149] = ram_size;
+    Cartridge::new(GbRom::from_bytes(data).unwrap())
+}
+
+#[test]
+fn mbc1_rom_banking_test() {
+    let mut cart = test_cart(0x01, 8, 0x00);
+    assert!(cart.read_rom(0x4000) == 1);
+
+    cart.write_rom(0x2000, 0x05);
+    assert!(cart.read_rom(0x4000) == 5);
+
+    // bank 0 can't be mapped into the upper area
+    cart.write_rom(0x2000, 0x00);
+    assert!(cart.read_rom(0x4000) == 1);
+    assert!(cart.read_rom(0x0000) == 0);
+}
+
+#[test]
+fn mbc5_ram_enable_test() {
+    let mut cart = test_cart(0x1A, 4, 0x03);
+    cart.write_ram(0xA000, 0x42);
+    assert!(cart.read_ram(0xA000) == 0xFF);
+
+    cart.write_rom(0x0000, 0x0A);
+    cart.write_rom(0x4000, 0x02);
+    cart.write_ram(0xA000, 0x42);
+    assert!(cart.read_ram(0xA000) == 0x42);
+
+    cart.write_rom(0x4000, 0x01);
+    assert!(cart.read_ram(0xA000) == 0x00);
+}
@@ -1,24 +1,36 @@
-use std::rc::Rc;
-use std::cell::RefCell;
-
 use num::FromPrimitive;
 
-use gb_hw_bus::HardwareBus;
-use gb_mem::{MemoryController, RamAddress, decrement_16, increment_16, IE_ADDR};
+use gb_error::{EmuError, Strictness};
+use gb_hw_bus::Bus;
+use gb_mem::{decrement_16, increment_16, RamAddress, IE_ADDR, IF_ADDR};
 use gb_opcodes::{OpCodes, SecondOpAction, SecondOpRegister, SecondOpType};
+use gb_system::Model;
 
-use tracelog::{MemChange, TraceLog};
+use gb_state::{StateReader, StateWriter};
+use tracelog::TraceLog;
 
 const ZERO_FLAG: u8 = 1 << 7;
 const SUBT_FLAG: u8 = 1 << 6;
 const HALF_CARRY_FLAG: u8 = 1 << 5;
 const CARRY_FLAG: u8 = 1 << 4;
 
-const VBLANK_IF: u8 = 1;
-const LCDC_IF: u8 = 1 << 1;
-const TIMER_OVERFLOW_IF: u8 = 1 << 2;
-const SERIAL_IO_COMPLETE_IF: u8 = 1 << 3;
-const P10_P13_TERM_NEG_EDGE_IF: u8 = 1 << 4;
+// warnings kept until taken; a game stuck in a loop of bad writes would
+// otherwise pile them up without end
+const MAX_WARNINGS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub f: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
 
 #[derive(Debug)]
 pub struct DmgCpu {
@@ -36,50 +48,110 @@ pub struct DmgCpu {
     ime: bool, // interrupt master enabled
     halt: bool,
     stop: bool,
+    locked: bool, // hung on an illegal opcode until power off
 
-    clock: u64,
+    strictness: Strictness,
+    op_pc: u16, // address of the instruction being executed
+    // faults noted under Strictness::Warn, waiting to be taken
+    warnings: Vec<String>,
+    dropped_warnings: usize,
 
-    mc: Rc<RefCell<MemoryController>>,
-    bus: Rc<RefCell<HardwareBus>>,
+    clock: u64,
 }
 
 impl DmgCpu {
-    pub fn new(bus: Rc<RefCell<HardwareBus>>, mc: Rc<RefCell<MemoryController>>) -> Self {
+    // Registers start out as the boot ROM leaves them, which is how games
+    // tell the models apart.
+    pub fn new(model: Model) -> Self {
+        let [a, f, b, c, d, e, h, l] = match model {
+            Model::Dmg => [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::Cgb => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+        };
+
         DmgCpu {
-            a: 0u8,
-            b: 0u8,
-            c: 0u8,
-            d: 0u8,
-            e: 0u8,
-            f: 0u8,
-            h: 0u8,
-            l: 0u8,
+            a,
+            b,
+            c,
+            d,
+            e,
+            f,
+            h,
+            l,
             sp: RamAddress::new(0xFFFEu16),
             pc: RamAddress::new(0x0100u16),
 
-            ime: true,
+            ime: false,
             halt: false,
             stop: false,
+            locked: false,
+
+            strictness: Strictness::default(),
+            op_pc: 0x0100,
+            warnings: Vec::new(),
+            dropped_warnings: 0,
 
             clock: 0u64,
+        }
+    }
 
-            mc: mc,
-            bus: bus,
+    // Registers as the CPU comes out of reset, before a boot ROM has run
+    pub fn at_reset(model: Model) -> Self {
+        DmgCpu {
+            a: 0,
+            f: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            sp: RamAddress::new(0x0000),
+            pc: RamAddress::new(0x0000),
+            op_pc: 0x0000,
+            ..DmgCpu::new(model)
         }
     }
 
-    fn sync_hardware_bus(&mut self) {
-        self.bus.borrow_mut().sync(self.clock);
+    fn sync_hardware_bus<B: Bus>(&self, bus: &mut B) {
+        bus.sync(self.clock);
     }
 
-    pub fn get_memory_controller(&self) -> Rc<RefCell<MemoryController>> {
-        self.mc.clone()
+    // Writes through the bus and decides what an illegal access means. The
+    // memory controller has already done whatever the console would do with
+    // the write by the time it reports it.
+    fn write_memory<B: Bus>(
+        &mut self,
+        bus: &mut B,
+        addr: RamAddress,
+        val: u8,
+    ) -> Result<(), EmuError> {
+        match bus.write(addr, val) {
+            Err(err) => self.fault(err),
+            ok => ok,
+        }
     }
 
-    fn read_pc_mem_and_increment(&mut self) -> u8 {
-        let result = self.mc.borrow().read(self.pc.post_inc(1));
+    fn fault(&mut self, err: EmuError) -> Result<(), EmuError> {
+        match self.strictness {
+            Strictness::Hardware => Ok(()),
+            Strictness::Warn => {
+                if self.warnings.len() < MAX_WARNINGS {
+                    self.warnings
+                        .push(format!("{} (PC {:#06X})", err, self.op_pc));
+                } else {
+                    self.dropped_warnings += 1;
+                }
+                Ok(())
+            }
+            Strictness::Strict => Err(err),
+        }
+    }
+
+    fn read_pc_mem_and_increment<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let result = bus.read(self.pc.post_inc(1));
         self.clock += 4;
-        self.sync_hardware_bus();
+        self.sync_hardware_bus(bus);
         result
     }
 
@@ -104,25 +176,19 @@ impl DmgCpu {
         RamAddress::new(0xFF00 | self.c as u16)
     }
 
-    fn make_ffn_address(&mut self) -> RamAddress {
-        let n = self.read_pc_mem_and_increment() as u16;
+    fn make_ffn_address<B: Bus>(&mut self, bus: &mut B) -> RamAddress {
+        let n = self.read_pc_mem_and_increment(bus) as u16;
         RamAddress::new(0xFF00u16 | n)
     }
 
-    fn read_address_pair(&mut self) -> (u8, u8) {
-        let low = self.read_pc_mem_and_increment();
-        let high = self.read_pc_mem_and_increment();
+    fn read_address_pair<B: Bus>(&mut self, bus: &mut B) -> (u8, u8) {
+        let low = self.read_pc_mem_and_increment(bus);
+        let high = self.read_pc_mem_and_increment(bus);
         (low, high)
     }
 
-    fn write_address_pair(&mut self, high: &mut u8, low: &mut u8) {
-        let pair = self.read_address_pair();
-        *low = pair.0;
-        *high = pair.1;
-    }
-
-    fn make_nn_address(&mut self) -> RamAddress {
-        let pair = self.read_address_pair();
+    fn make_nn_address<B: Bus>(&mut self, bus: &mut B) -> RamAddress {
+        let pair = self.read_address_pair(bus);
         let low = pair.0 as u16;
         let high = pair.1 as u16;
         RamAddress::new((high << 8) | low)
@@ -147,7 +213,7 @@ impl DmgCpu {
 
     fn add(&mut self, a: u8, b: u8) -> u8 {
         let r = self.add_no_zcheck(a, b);
-        self.set_flag_conditional(ZERO_FLAG, r as u8 == 0);
+        self.set_flag_conditional(ZERO_FLAG, r == 0);
         r
     }
 
@@ -312,81 +378,88 @@ impl DmgCpu {
     }
 
     // program flow
-    fn read_pc_as_address(&mut self) -> u16 {
-        let mc = self.mc.borrow();
-        let low = mc.read(self.pc.post_inc(1)) as u16;
-        let high = mc.read(self.pc.post_inc(1)) as u16;
+    fn read_pc_as_address<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        let low = bus.read(self.pc.post_inc(1)) as u16;
+        let high = bus.read(self.pc.post_inc(1)) as u16;
         high << 8 | low
     }
 
-    fn do_jump_conditional(&mut self, test: bool) {
-        let dest = self.read_pc_as_address();
+    fn do_jump_conditional<B: Bus>(&mut self, bus: &mut B, test: bool) {
+        let dest = self.read_pc_as_address(bus);
         if test {
             self.pc.set(dest);
         }
     }
 
-    fn do_jump_relative_conditional(&mut self, test: bool) {
-        let offset = self.mc.borrow().read(self.pc.post_inc(1));
-        self.sync_hardware_bus();
+    fn do_jump_relative_conditional<B: Bus>(&mut self, bus: &mut B, test: bool) {
+        let offset = bus.read(self.pc.post_inc(1));
+        self.sync_hardware_bus(bus);
 
         if test {
             self.pc.inc(offset as i8 as u16);
         }
     }
 
-    fn push_address_parts(&mut self, high: u8, low: u8) -> Result<(), String> {
-        match self.mc.borrow_mut().write(self.sp.dec(1), low) {
+    fn push_address_parts<B: Bus>(
+        &mut self,
+        bus: &mut B,
+        high: u8,
+        low: u8,
+    ) -> Result<(), EmuError> {
+        // the stack grows down, high byte first
+        let addr = self.sp.dec(1);
+        match self.write_memory(bus, addr, high) {
             Ok(_) => (),
             Err(err) => return Err(err),
         }
-        self.sync_hardware_bus();
-        match self.mc.borrow_mut().write(self.sp.dec(1), high) {
+        self.sync_hardware_bus(bus);
+        let addr = self.sp.dec(1);
+        match self.write_memory(bus, addr, low) {
             Ok(_) => (),
             Err(err) => return Err(err),
         }
-        self.sync_hardware_bus();
+        self.sync_hardware_bus(bus);
         Ok(())
     }
 
-    fn push_address_u16(&mut self, addr: u16) -> Result<(), String> {
-        let high = (addr & 0xFF00 >> 8) as u8;
+    fn push_address_u16<B: Bus>(&mut self, bus: &mut B, addr: u16) -> Result<(), EmuError> {
+        let high = ((addr & 0xFF00) >> 8) as u8;
         let low = (addr & 0x00FF) as u8;
-        return self.push_address_parts(high, low);
+        self.push_address_parts(bus, high, low)
     }
 
-    fn push_address(&mut self, addr: RamAddress) -> Result<(), String> {
-        return self.push_address_u16(addr.get());
+    fn push_address<B: Bus>(&mut self, bus: &mut B, addr: RamAddress) -> Result<(), EmuError> {
+        self.push_address_u16(bus, addr.get())
     }
 
-    fn pop_address_parts(&mut self) -> (u8, u8) {
-        let high = self.mc.borrow().read(self.sp.post_inc(1));
-        self.sync_hardware_bus();
-        let low = self.mc.borrow().read(self.sp.post_inc(1));
-        self.sync_hardware_bus();
+    fn pop_address_parts<B: Bus>(&mut self, bus: &mut B) -> (u8, u8) {
+        let low = bus.read(self.sp.post_inc(1));
+        self.sync_hardware_bus(bus);
+        let high = bus.read(self.sp.post_inc(1));
+        self.sync_hardware_bus(bus);
         (high, low)
     }
 
-    fn pop_address_u16(&mut self) -> u16 {
-        let parts = self.pop_address_parts();
+    fn pop_address_u16<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        let parts = self.pop_address_parts(bus);
         (parts.0 as u16) << 8 | (parts.1 as u16)
     }
 
-    fn do_call_conditional(&mut self, test: bool) -> Result<(), String> {
-        let dest = self.read_pc_as_address();
+    fn do_call_conditional<B: Bus>(&mut self, bus: &mut B, test: bool) -> Result<(), EmuError> {
+        let dest = self.read_pc_as_address(bus);
 
         if test {
             let addr = self.pc.get();
             self.pc.set(dest);
-            return self.push_address_u16(addr);
+            return self.push_address_u16(bus, addr);
         }
 
         Ok(())
     }
 
-    fn do_return_conditional(&mut self, test: bool) {
+    fn do_return_conditional<B: Bus>(&mut self, bus: &mut B, test: bool) {
         if test {
-            let addr = self.pop_address_u16();
+            let addr = self.pop_address_u16(bus);
             self.pc.set(addr);
         }
     }
@@ -394,18 +467,18 @@ impl DmgCpu {
     // multibyte ops
     fn hand_rotate_shift_op(&mut self, value: u8, op: SecondOpAction) -> u8 {
         match op {
-            SecondOpAction::RLC => return self.do_rlc(value),
-            SecondOpAction::RL => return self.do_rl(value),
-            SecondOpAction::RRC => return self.do_rrc(value),
-            SecondOpAction::RR => return self.do_rr(value),
-            SecondOpAction::SLA => return self.do_sla(value),
-            SecondOpAction::SRA => return self.do_sra(value),
-            SecondOpAction::SRL => return self.do_srl(value),
-            SecondOpAction::SWAP => return self.do_swap(value),
+            SecondOpAction::RLC => self.do_rlc(value),
+            SecondOpAction::RL => self.do_rl(value),
+            SecondOpAction::RRC => self.do_rrc(value),
+            SecondOpAction::RR => self.do_rr(value),
+            SecondOpAction::SLA => self.do_sla(value),
+            SecondOpAction::SRA => self.do_sra(value),
+            SecondOpAction::SRL => self.do_srl(value),
+            SecondOpAction::SWAP => self.do_swap(value),
         }
     }
 
-    fn decode_and_execute_cb_op(&mut self, sop: u8) -> Result<(), String> {
+    fn decode_and_execute_cb_op<B: Bus>(&mut self, bus: &mut B, sop: u8) -> Result<(), EmuError> {
         let op_type: SecondOpType = SecondOpType::from_u8(sop);
         let action = SecondOpAction::from_u8(sop);
         let register = SecondOpRegister::from_u8(sop);
@@ -423,8 +496,8 @@ impl DmgCpu {
                     SecondOpRegister::H => self.h,
                     SecondOpRegister::L => self.l,
                     SecondOpRegister::mHL => {
-                        let val = self.mc.borrow().read(self.make_hl_address());
-                        self.sync_hardware_bus();
+                        let val = bus.read(self.make_hl_address());
+                        self.sync_hardware_bus(bus);
                         val
                     }
                 };
@@ -440,13 +513,13 @@ impl DmgCpu {
                 SecondOpRegister::L => self.l |= bit_mask,
                 SecondOpRegister::mHL => {
                     let hl = self.make_hl_address();
-                    let val = self.mc.borrow_mut().read(hl);
-                    self.sync_hardware_bus();
-                    match self.mc.borrow_mut().write(hl, val | bit_mask) {
+                    let val = bus.read(hl);
+                    self.sync_hardware_bus(bus);
+                    match self.write_memory(bus, hl, val | bit_mask) {
                         Ok(_) => (),
                         Err(err) => return Err(err),
                     }
-                    self.sync_hardware_bus();
+                    self.sync_hardware_bus(bus);
                 }
             },
             SecondOpType::RESET => match register {
@@ -458,13 +531,14 @@ impl DmgCpu {
                 SecondOpRegister::H => self.h &= !bit_mask,
                 SecondOpRegister::L => self.l &= !bit_mask,
                 SecondOpRegister::mHL => {
-                    let mut mc = self.mc.borrow_mut();
                     let hl = self.make_hl_address();
-                    let val = mc.read(hl);
-                    match mc.write(hl, val & !bit_mask) {
+                    let val = bus.read(hl);
+                    self.sync_hardware_bus(bus);
+                    match self.write_memory(bus, hl, val & !bit_mask) {
                         Ok(_) => (),
                         Err(err) => return Err(err),
                     }
+                    self.sync_hardware_bus(bus);
                 }
             },
             SecondOpType::ROTATE_SHIFT => match register {
@@ -480,13 +554,13 @@ impl DmgCpu {
                 SecondOpRegister::L => self.l &= !bit_mask,
                 SecondOpRegister::mHL => {
                     let hl = self.make_hl_address();
-                    let val = self.mc.borrow_mut().read(hl);
-                    self.sync_hardware_bus();
-                    match self.mc.borrow_mut().write(hl, val & !bit_mask) {
+                    let val = bus.read(hl);
+                    self.sync_hardware_bus(bus);
+                    match self.write_memory(bus, hl, val & !bit_mask) {
                         Ok(_) => (),
                         Err(err) => return Err(err),
                     }
-                    self.sync_hardware_bus();
+                    self.sync_hardware_bus(bus);
                 }
             },
         }
@@ -510,7 +584,7 @@ impl DmgCpu {
                 temp = (temp.wrapping_sub(0x06)) & 0xFF;
             }
             if c {
-                temp = temp - 0x06;
+                temp -= 0x06;
             }
         } else {
             if hc || (temp & 0x0f) > 9 {
@@ -528,42 +602,144 @@ impl DmgCpu {
         self.reset_flag(HALF_CARRY_FLAG);
     }
 
+    fn service_interrupt<B: Bus>(&mut self, bus: &mut B, pending: u8) -> Result<(), EmuError> {
+        // lowest bit has the highest priority
+        let bit = pending.trailing_zeros() as u16;
+        let flags = bus.read(IF_ADDR);
+        self.write_memory(bus, IF_ADDR, flags & !(1 << bit))?;
+
+        self.ime = false;
+        self.clock += 20;
+        let pc = self.pc;
+        self.push_address(bus, pc)?;
+        self.pc = RamAddress::new(0x0040 + bit * 8);
+        self.sync_hardware_bus(bus);
+        Ok(())
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halt
+    }
+
     pub fn is_stopped(&self) -> bool {
         self.stop
     }
 
-    pub fn tick(&mut self, log: &mut Vec<TraceLog>) -> Result<(), String> {
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.strictness = strictness;
+    }
+
+    pub fn strictness(&self) -> Strictness {
+        self.strictness
+    }
+
+    /// Drains the faults noted under `Strictness::Warn`.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        let mut warnings = std::mem::take(&mut self.warnings);
+        if self.dropped_warnings > 0 {
+            warnings.push(format!("{} more faults not shown", self.dropped_warnings));
+            self.dropped_warnings = 0;
+        }
+        warnings
+    }
+
+    pub fn get_clock(&self) -> u64 {
+        self.clock
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            f: self.f,
+            h: self.h,
+            l: self.l,
+            sp: self.sp.get(),
+            pc: self.pc.get(),
+        }
+    }
+
+    pub fn tick<B: Bus>(&mut self, bus: &mut B, log: &mut Vec<TraceLog>) -> Result<(), EmuError> {
         if self.stop {
             return Ok(());
         }
 
-        let op_val = self.read_pc_mem_and_increment();
-        return self.do_op(op_val, log);
+        // the CPU sits out any DMA that took the bus during the last step
+        let stall = bus.take_stall();
+        if stall > 0 {
+            self.clock += stall;
+            self.sync_hardware_bus(bus);
+            return Ok(());
+        }
+
+        let pending = bus.read(IE_ADDR) & bus.read(IF_ADDR) & 0x1F;
+        if pending != 0 && !self.locked {
+            // any pending interrupt wakes the CPU, even with IME off
+            self.halt = false;
+            if self.ime {
+                return self.service_interrupt(bus, pending);
+            }
+        }
+
+        if self.halt || self.locked {
+            self.clock += 4;
+            self.sync_hardware_bus(bus);
+            return Ok(());
+        }
+
+        self.op_pc = self.pc.get();
+        let op_val = self.read_pc_mem_and_increment(bus);
+        self.do_op(bus, op_val, log)
     }
 
-    pub fn do_op(&mut self, op_val: u8, log: &mut Vec<TraceLog>) -> Result<(), String> {
+    pub fn do_op<B: Bus>(
+        &mut self,
+        bus: &mut B,
+        op_val: u8,
+        log: &mut Vec<TraceLog>,
+    ) -> Result<(), EmuError> {
         let op = match OpCodes::from_u8(op_val) {
             Some(op) => op,
             None => {
-                let err = format!("Unrecognized opcode value: {}!!!", op_val);
-                return Err(err);
+                let pc = self.pc.get().wrapping_sub(1);
+                let bank = match pc {
+                    0x4000..=0x7FFF => bus.rom_bank(),
+                    _ => 0,
+                };
+                // the console hangs for good, with only the rest of the
+                // hardware still running
+                self.locked = true;
+                return self.fault(EmuError::UnknownOpcode {
+                    opcode: op_val,
+                    pc,
+                    bank,
+                });
             }
         };
 
-        let mut log_item = TraceLog::new(op);
+        let log_item = TraceLog::new(op);
 
         // should be safe to subtract 1 because we just incremented?
+        #[cfg(feature = "run_trace")]
         println!(
             "    A:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} F:{:02X} H:{:02X} L:{:02X}",
             self.a, self.b, self.c, self.d, self.e, self.f, self.h, self.l
         );
+        #[cfg(feature = "run_trace")]
         println!(
             "{:<10} ({:#06X})",
             format!("{:?}", op),
             self.pc.get().wrapping_sub(1)
         );
 
-        let mut result: Result<(), String> = Ok(());
+        let mut result: Result<(), EmuError> = Ok(());
         match op {
             OpCodes::LD_A_A => {
                 // do nothing since it's copying to itself
@@ -713,178 +889,178 @@ impl DmgCpu {
                 // pass
             }
             OpCodes::LD_A_N => {
-                self.a = self.read_pc_mem_and_increment();
+                self.a = self.read_pc_mem_and_increment(bus);
             }
             OpCodes::LD_B_N => {
-                self.b = self.read_pc_mem_and_increment();
+                self.b = self.read_pc_mem_and_increment(bus);
             }
             OpCodes::LD_C_N => {
-                self.c = self.read_pc_mem_and_increment();
+                self.c = self.read_pc_mem_and_increment(bus);
             }
             OpCodes::LD_D_N => {
-                self.d = self.read_pc_mem_and_increment();
+                self.d = self.read_pc_mem_and_increment(bus);
             }
             OpCodes::LD_E_N => {
-                self.e = self.read_pc_mem_and_increment();
+                self.e = self.read_pc_mem_and_increment(bus);
             }
             OpCodes::LD_H_N => {
-                self.h = self.read_pc_mem_and_increment();
+                self.h = self.read_pc_mem_and_increment(bus);
             }
             OpCodes::LD_L_N => {
-                self.l = self.read_pc_mem_and_increment();
+                self.l = self.read_pc_mem_and_increment(bus);
             }
             OpCodes::LD_A_mHL => {
                 let addr = self.make_hl_address();
-                self.a = self.mc.borrow().read(addr);
+                self.a = bus.read(addr);
             }
             OpCodes::LD_B_mHL => {
                 let addr = self.make_hl_address();
-                self.b = self.mc.borrow().read(addr);
+                self.b = bus.read(addr);
             }
             OpCodes::LD_C_mHL => {
                 let addr = self.make_hl_address();
-                self.c = self.mc.borrow().read(addr);
+                self.c = bus.read(addr);
             }
             OpCodes::LD_D_mHL => {
                 let addr = self.make_hl_address();
-                self.d = self.mc.borrow().read(addr);
+                self.d = bus.read(addr);
             }
             OpCodes::LD_E_mHL => {
                 let addr = self.make_hl_address();
-                self.e = self.mc.borrow().read(addr);
+                self.e = bus.read(addr);
             }
             OpCodes::LD_H_mHL => {
                 let addr = self.make_hl_address();
-                self.h = self.mc.borrow().read(addr);
+                self.h = bus.read(addr);
             }
             OpCodes::LD_L_mHL => {
                 let addr = self.make_hl_address();
-                self.l = self.mc.borrow().read(addr);
+                self.l = bus.read(addr);
             }
             OpCodes::LD_mHL_A => {
                 let addr = self.make_hl_address();
                 let val = self.a;
-                result = self.mc.borrow_mut().write(addr, val);
+                result = self.write_memory(bus, addr, val);
             }
             OpCodes::LD_mHL_B => {
                 let addr = self.make_hl_address();
                 let val = self.b;
-                result = self.mc.borrow_mut().write(addr, val);
+                result = self.write_memory(bus, addr, val);
             }
             OpCodes::LD_mHL_C => {
                 let addr = self.make_hl_address();
                 let val = self.c;
-                result = self.mc.borrow_mut().write(addr, val);
+                result = self.write_memory(bus, addr, val);
             }
             OpCodes::LD_mHL_D => {
                 let addr = self.make_hl_address();
                 let val = self.d;
-                result = self.mc.borrow_mut().write(addr, val);
+                result = self.write_memory(bus, addr, val);
             }
             OpCodes::LD_mHL_E => {
                 let addr = self.make_hl_address();
                 let val = self.e;
-                result = self.mc.borrow_mut().write(addr, val);
+                result = self.write_memory(bus, addr, val);
             }
             OpCodes::LD_mHL_H => {
                 let addr = self.make_hl_address();
                 let val = self.h;
-                result = self.mc.borrow_mut().write(addr, val);
+                result = self.write_memory(bus, addr, val);
             }
             OpCodes::LD_mHL_L => {
                 let addr = self.make_hl_address();
                 let val = self.l;
-                result = self.mc.borrow_mut().write(addr, val);
+                result = self.write_memory(bus, addr, val);
             }
             OpCodes::LD_mHL_N => {
                 let addr = self.make_hl_address();
-                let val = self.read_pc_mem_and_increment();
-                result = self.mc.borrow_mut().write(addr, val);
+                let val = self.read_pc_mem_and_increment(bus);
+                result = self.write_memory(bus, addr, val);
             }
             OpCodes::LD_A_mBC => {
                 let addr = self.make_bc_address();
-                self.a = self.mc.borrow().read(addr);
+                self.a = bus.read(addr);
             }
             OpCodes::LD_A_mDE => {
                 let addr = self.make_de_address();
-                self.a = self.mc.borrow().read(addr);
+                self.a = bus.read(addr);
             }
             OpCodes::LD_A_mC => {
                 let addr = self.make_ffc_address();
-                self.a = self.mc.borrow().read(addr);
+                self.a = bus.read(addr);
             }
             OpCodes::LD_mC_A => {
                 let addr = self.make_ffc_address();
                 let a = self.a;
-                result = self.mc.borrow_mut().write(addr, a);
+                result = self.write_memory(bus, addr, a);
             }
             OpCodes::LD_A_mN => {
-                let addr = self.make_ffn_address();
-                self.a = self.mc.borrow().read(addr);
+                let addr = self.make_ffn_address(bus);
+                self.a = bus.read(addr);
             }
             OpCodes::LD_mN_A => {
-                let addr = self.make_ffn_address();
+                let addr = self.make_ffn_address(bus);
                 let a = self.a;
-                result = self.mc.borrow_mut().write(addr, a);
+                result = self.write_memory(bus, addr, a);
             }
             OpCodes::LD_A_mNN => {
-                let addr = self.make_nn_address();
-                self.a = self.mc.borrow().read(addr);
+                let addr = self.make_nn_address(bus);
+                self.a = bus.read(addr);
             }
             OpCodes::LD_mNN_A => {
-                let addr = self.make_nn_address();
+                let addr = self.make_nn_address(bus);
                 let a = self.a;
-                result = self.mc.borrow_mut().write(addr, a);
+                result = self.write_memory(bus, addr, a);
             }
             OpCodes::LD_A_HLI => {
                 let addr = self.make_hl_address();
-                self.a = self.mc.borrow().read(addr);
+                self.a = bus.read(addr);
                 increment_16(&mut self.h, &mut self.l);
             }
             OpCodes::LD_A_HLD => {
                 let addr = self.make_hl_address();
-                self.a = self.mc.borrow().read(addr);
+                self.a = bus.read(addr);
                 decrement_16(&mut self.h, &mut self.l);
             }
             OpCodes::LD_mBC_A => {
                 let addr = self.make_bc_address();
                 let a = self.a;
-                result = self.mc.borrow_mut().write(addr, a);
+                result = self.write_memory(bus, addr, a);
             }
             OpCodes::LD_mDE_A => {
                 let addr = self.make_de_address();
                 let a = self.a;
-                result = self.mc.borrow_mut().write(addr, a);
+                result = self.write_memory(bus, addr, a);
             }
             OpCodes::LD_HLI_A => {
                 let addr = self.make_hl_address();
                 let a = self.a;
                 increment_16(&mut self.h, &mut self.l);
-                result = self.mc.borrow_mut().write(addr, a);
+                result = self.write_memory(bus, addr, a);
             }
             OpCodes::LD_HLD_A => {
                 let addr = self.make_hl_address();
                 let a = self.a;
                 decrement_16(&mut self.h, &mut self.l);
-                result = self.mc.borrow_mut().write(addr, a);
+                result = self.write_memory(bus, addr, a);
             }
             OpCodes::LD_BC_NN => {
-                let pair = self.read_address_pair();
+                let pair = self.read_address_pair(bus);
                 self.b = pair.1;
                 self.c = pair.0;
             }
             OpCodes::LD_DE_NN => {
-                let pair = self.read_address_pair();
+                let pair = self.read_address_pair(bus);
                 self.d = pair.1;
                 self.e = pair.0;
             }
             OpCodes::LD_HL_NN => {
-                let pair = self.read_address_pair();
+                let pair = self.read_address_pair(bus);
                 self.h = pair.1;
                 self.l = pair.0;
             }
             OpCodes::LD_SP_NN => {
-                self.sp = self.make_nn_address();
+                self.sp = self.make_nn_address(bus);
             }
             OpCodes::LD_SP_HL => {
                 self.sp = self.make_hl_address();
@@ -892,61 +1068,60 @@ impl DmgCpu {
             OpCodes::PUSH_BC => {
                 let b = self.b;
                 let c = self.c;
-                result = self.push_address_parts(b, c);
+                result = self.push_address_parts(bus, b, c);
             }
             OpCodes::PUSH_DE => {
                 let d = self.d;
                 let e = self.e;
-                result = self.push_address_parts(d, e);
+                result = self.push_address_parts(bus, d, e);
             }
             OpCodes::PUSH_HL => {
                 let h = self.h;
                 let l = self.l;
-                result = self.push_address_parts(h, l);
+                result = self.push_address_parts(bus, h, l);
             }
             OpCodes::PUSH_AF => {
                 let a = self.a;
                 let f = self.f;
-                result = self.push_address_parts(a, f);
+                result = self.push_address_parts(bus, a, f);
             }
             OpCodes::POP_BC => {
-                let parts = self.pop_address_parts();
+                let parts = self.pop_address_parts(bus);
                 self.b = parts.0;
                 self.c = parts.1;
             }
             OpCodes::POP_DE => {
-                let parts = self.pop_address_parts();
-                self.b = parts.0;
-                self.c = parts.1;
+                let parts = self.pop_address_parts(bus);
+                self.d = parts.0;
+                self.e = parts.1;
             }
             OpCodes::POP_HL => {
-                let parts = self.pop_address_parts();
-                self.b = parts.0;
-                self.c = parts.1;
+                let parts = self.pop_address_parts(bus);
+                self.h = parts.0;
+                self.l = parts.1;
             }
             OpCodes::POP_AF => {
-                let parts = self.pop_address_parts();
-                self.b = parts.0;
-                self.c = parts.1;
+                let parts = self.pop_address_parts(bus);
+                self.a = parts.0;
+                // the low nibble of F doesn't exist
+                self.f = parts.1 & 0xF0;
             }
             OpCodes::LDHL_SP_e => {
-                let b = self.read_pc_mem_and_increment();
+                let b = self.read_pc_mem_and_increment(bus);
                 let sp = self.sp.get();
                 let temp = self.add_to_u16(b, sp);
                 self.h = ((temp & 0xFF00) >> 8) as u8;
                 self.l = (temp & 0x00FF) as u8;
             }
             OpCodes::LD_mNN_SP => {
-                let mut addr = self.make_nn_address();
+                let mut addr = self.make_nn_address(bus);
                 let sp = self.sp.get();
-                result = self.mc
-                    .borrow_mut()
-                    .write(addr.post_inc(1), (sp & 0x00ff) as u8);
+                result = self.write_memory(bus, addr.post_inc(1), (sp & 0x00ff) as u8);
                 match result {
                     Ok(_) => (),
                     r @ Err(_) => return r,
                 }
-                result = self.mc.borrow_mut().write(addr, ((sp & 0xff00) >> 8) as u8);
+                result = self.write_memory(bus, addr, ((sp & 0xff00) >> 8) as u8);
             }
             OpCodes::ADD_A_A => {
                 let val = self.a;
@@ -984,13 +1159,13 @@ impl DmgCpu {
                 self.a = self.add(a, val);
             }
             OpCodes::ADD_A_N => {
-                let val = self.read_pc_mem_and_increment();
+                let val = self.read_pc_mem_and_increment(bus);
                 let a = self.a;
                 self.a = self.add(a, val);
             }
             OpCodes::ADD_A_mHL => {
                 let addr = self.make_hl_address();
-                let val = self.mc.borrow().read(addr);
+                let val = bus.read(addr);
                 let a = self.a;
                 self.a = self.add(a, val);
             }
@@ -1030,13 +1205,13 @@ impl DmgCpu {
                 self.a = self.add_with_carry(a, val);
             }
             OpCodes::ADC_A_N => {
-                let val = self.read_pc_mem_and_increment();
+                let val = self.read_pc_mem_and_increment(bus);
                 let a = self.a;
                 self.a = self.add_with_carry(a, val);
             }
             OpCodes::ADC_A_mHL => {
                 let addr = self.make_hl_address();
-                let val = self.mc.borrow().read(addr);
+                let val = bus.read(addr);
                 let a = self.a;
                 self.a = self.add_with_carry(a, val);
             }
@@ -1076,13 +1251,13 @@ impl DmgCpu {
                 self.a = self.subtract(a, val);
             }
             OpCodes::SUB_N => {
-                let val = self.read_pc_mem_and_increment();
+                let val = self.read_pc_mem_and_increment(bus);
                 let a = self.a;
                 self.a = self.subtract(a, val);
             }
             OpCodes::SUB_mHL => {
                 let addr = self.make_hl_address();
-                let val = self.mc.borrow().read(addr);
+                let val = bus.read(addr);
                 let a = self.a;
                 self.a = self.subtract(a, val);
             }
@@ -1122,108 +1297,108 @@ impl DmgCpu {
                 self.a = self.subtract_with_carry(a, val);
             }
             OpCodes::SBC_A_N => {
-                let val = self.read_pc_mem_and_increment();
+                let val = self.read_pc_mem_and_increment(bus);
                 let a = self.a;
                 self.a = self.subtract_with_carry(a, val);
             }
             OpCodes::SBC_A_mHL => {
                 let addr = self.make_hl_address();
-                let val = self.mc.borrow().read(addr);
+                let val = bus.read(addr);
                 let a = self.a;
                 self.a = self.subtract_with_carry(a, val);
             }
             OpCodes::AND_A => {
-                self.a = self.a & self.a;
+                // A op A leaves A unchanged, only the flags move
                 let a = self.a;
                 self.set_logic_flags(a, true);
             }
             OpCodes::AND_B => {
-                self.a = self.a & self.b;
+                self.a &= self.b;
                 let a = self.a;
                 self.set_logic_flags(a, true);
             }
             OpCodes::AND_C => {
-                self.a = self.a & self.c;
+                self.a &= self.c;
                 let a = self.a;
                 self.set_logic_flags(a, true);
             }
             OpCodes::AND_D => {
-                self.a = self.a & self.d;
+                self.a &= self.d;
                 let a = self.a;
                 self.set_logic_flags(a, true);
             }
             OpCodes::AND_E => {
-                self.a = self.a & self.e;
+                self.a &= self.e;
                 let a = self.a;
                 self.set_logic_flags(a, true);
             }
             OpCodes::AND_H => {
-                self.a = self.a & self.h;
+                self.a &= self.h;
                 let a = self.a;
                 self.set_logic_flags(a, true);
             }
             OpCodes::AND_L => {
-                self.a = self.a & self.l;
+                self.a &= self.l;
                 let a = self.a;
                 self.set_logic_flags(a, true);
             }
             OpCodes::AND_N => {
-                self.a = self.a & self.read_pc_mem_and_increment();
+                self.a &= self.read_pc_mem_and_increment(bus);
                 let a = self.a;
                 self.set_logic_flags(a, true);
             }
             OpCodes::AND_mHL => {
                 let addr = self.make_hl_address();
-                let val = self.mc.borrow().read(addr);
-                self.a = self.a & val;
+                let val = bus.read(addr);
+                self.a &= val;
                 let a = self.a;
                 self.set_logic_flags(a, true);
             }
             OpCodes::OR_A => {
-                self.a = self.a | self.a;
+                // A op A leaves A unchanged, only the flags move
                 let a = self.a;
                 self.set_logic_flags(a, false);
             }
             OpCodes::OR_B => {
-                self.a = self.a | self.b;
+                self.a |= self.b;
                 let a = self.a;
                 self.set_logic_flags(a, false);
             }
             OpCodes::OR_C => {
-                self.a = self.a | self.c;
+                self.a |= self.c;
                 let a = self.a;
                 self.set_logic_flags(a, false);
             }
             OpCodes::OR_D => {
-                self.a = self.a | self.d;
+                self.a |= self.d;
                 let a = self.a;
                 self.set_logic_flags(a, false);
             }
             OpCodes::OR_E => {
-                self.a = self.a | self.e;
+                self.a |= self.e;
                 let a = self.a;
                 self.set_logic_flags(a, false);
             }
             OpCodes::OR_H => {
-                self.a = self.a | self.h;
+                self.a |= self.h;
                 let a = self.a;
                 self.set_logic_flags(a, false);
             }
             OpCodes::OR_L => {
-                self.a = self.a | self.l;
+                self.a |= self.l;
                 let a = self.a;
                 self.set_logic_flags(a, false);
             }
             OpCodes::OR_N => {
-                let val = self.read_pc_mem_and_increment();
-                self.a = self.a | val;
+                let val = self.read_pc_mem_and_increment(bus);
+                self.a |= val;
                 let a = self.a;
                 self.set_logic_flags(a, false);
             }
             OpCodes::OR_mHL => {
                 let addr = self.make_hl_address();
-                let val = self.mc.borrow().read(addr);
-                self.a = self.a | val;
+                let val = bus.read(addr);
+                self.a |= val;
                 let a = self.a;
                 self.set_logic_flags(a, false);
             }
@@ -1263,14 +1438,14 @@ impl DmgCpu {
                 self.set_logic_flags(a, false);
             }
             OpCodes::XOR_N => {
-                let val = self.read_pc_mem_and_increment();
+                let val = self.read_pc_mem_and_increment(bus);
                 self.a ^= val;
                 let a = self.a;
                 self.set_logic_flags(a, false);
             }
             OpCodes::XOR_mHL => {
                 let addr = self.make_hl_address();
-                let val = self.mc.borrow().read(addr);
+                let val = bus.read(addr);
                 self.a ^= val;
                 let a = self.a;
                 self.set_logic_flags(a, false);
@@ -1311,13 +1486,13 @@ impl DmgCpu {
                 self.subtract_with_carry(a, val);
             }
             OpCodes::CP_N => {
-                let val = self.read_pc_mem_and_increment();
+                let val = self.read_pc_mem_and_increment(bus);
                 let a = self.a;
                 self.subtract_with_carry(a, val);
             }
             OpCodes::CP_mHL => {
                 let addr = self.make_hl_address();
-                let val = self.mc.borrow().read(addr);
+                let val = bus.read(addr);
                 let a = self.a;
                 self.subtract_with_carry(a, val);
             }
@@ -1358,9 +1533,9 @@ impl DmgCpu {
             }
             OpCodes::INC_mHL => {
                 let addr = self.make_hl_address();
-                let mut val = self.mc.borrow().read(addr);
+                let mut val = bus.read(addr);
                 self.increment(&mut val);
-                result = self.mc.borrow_mut().write(addr, val);
+                result = self.write_memory(bus, addr, val);
             }
             OpCodes::DEC_A => {
                 let mut val = self.a;
@@ -1399,9 +1574,9 @@ impl DmgCpu {
             }
             OpCodes::DEC_mHL => {
                 let addr = self.make_hl_address();
-                let mut val = self.mc.borrow().read(addr);
+                let mut val = bus.read(addr);
                 self.decrement(&mut val);
-                result = self.mc.borrow_mut().write(addr, val);
+                result = self.write_memory(bus, addr, val);
             }
             OpCodes::ADD_HL_BC => {
                 let h = self.h;
@@ -1434,7 +1609,7 @@ impl DmgCpu {
                 self.h = self.add(h, ((sp_val & 0xFF00) >> 8) as u8 + carry);
             }
             OpCodes::ADD_SP_e => {
-                let val = self.read_pc_mem_and_increment() as u16;
+                let val = self.read_pc_mem_and_increment(bus) as u16;
                 self.sp.inc(val);
             }
             OpCodes::INC_BC => {
@@ -1479,131 +1654,131 @@ impl DmgCpu {
             }
             OpCodes::MULTI_BYTE_OP => {
                 // this code accounts for many variants based on the second byte read
-                let next_op = self.read_pc_mem_and_increment();
-                result = self.decode_and_execute_cb_op(next_op);
+                let next_op = self.read_pc_mem_and_increment(bus);
+                result = self.decode_and_execute_cb_op(bus, next_op);
             }
             OpCodes::JP_NN => {
-                self.do_jump_conditional(true);
+                self.do_jump_conditional(bus, true);
             }
             OpCodes::JP_NZ_NN => {
                 let f = self.f;
-                self.do_jump_conditional((f & ZERO_FLAG) == 0);
+                self.do_jump_conditional(bus, (f & ZERO_FLAG) == 0);
             }
             OpCodes::JP_Z_NN => {
                 let f = self.f;
-                self.do_jump_conditional((f & ZERO_FLAG) == ZERO_FLAG);
+                self.do_jump_conditional(bus, (f & ZERO_FLAG) == ZERO_FLAG);
             }
             OpCodes::JP_NC_NN => {
                 let f = self.f;
-                self.do_jump_conditional((f & CARRY_FLAG) == 0);
+                self.do_jump_conditional(bus, (f & CARRY_FLAG) == 0);
             }
             OpCodes::JP_C_NN => {
                 let f = self.f;
-                self.do_jump_conditional((f & CARRY_FLAG) == CARRY_FLAG);
+                self.do_jump_conditional(bus, (f & CARRY_FLAG) == CARRY_FLAG);
             }
             OpCodes::JR_e => {
-                self.do_jump_relative_conditional(true);
+                self.do_jump_relative_conditional(bus, true);
             }
             OpCodes::JR_NZ_e => {
                 let f = self.f;
-                self.do_jump_relative_conditional((f & ZERO_FLAG) == 0);
+                self.do_jump_relative_conditional(bus, (f & ZERO_FLAG) == 0);
             }
             OpCodes::JR_Z_e => {
                 let f = self.f;
-                self.do_jump_relative_conditional((f & ZERO_FLAG) == ZERO_FLAG);
+                self.do_jump_relative_conditional(bus, (f & ZERO_FLAG) == ZERO_FLAG);
             }
             OpCodes::JR_NC_e => {
                 let f = self.f;
-                self.do_jump_relative_conditional((f & CARRY_FLAG) == 0);
+                self.do_jump_relative_conditional(bus, (f & CARRY_FLAG) == 0);
             }
             OpCodes::JR_C_e => {
                 let f = self.f;
-                self.do_jump_relative_conditional((f & CARRY_FLAG) == CARRY_FLAG);
+                self.do_jump_relative_conditional(bus, (f & CARRY_FLAG) == CARRY_FLAG);
             }
             OpCodes::JP_mHL => {
                 // self.actually just loads self.hL into self.pc, not memory at self.hL... :(
                 self.pc = self.make_hl_address();
             }
             OpCodes::CALL_NN => {
-                result = self.do_call_conditional(true);
+                result = self.do_call_conditional(bus, true);
             }
             OpCodes::CALL_NZ_NN => {
                 let f = self.f;
-                result = self.do_call_conditional((f & ZERO_FLAG) == 0);
+                result = self.do_call_conditional(bus, (f & ZERO_FLAG) == 0);
             }
             OpCodes::CALL_Z_NN => {
                 let f = self.f;
-                result = self.do_call_conditional((f & ZERO_FLAG) == ZERO_FLAG);
+                result = self.do_call_conditional(bus, (f & ZERO_FLAG) == ZERO_FLAG);
             }
             OpCodes::CALL_NC_NN => {
                 let f = self.f;
-                result = self.do_call_conditional((f & CARRY_FLAG) == 0);
+                result = self.do_call_conditional(bus, (f & CARRY_FLAG) == 0);
             }
             OpCodes::CALL_C_NN => {
                 let f = self.f;
-                result = self.do_call_conditional((f & CARRY_FLAG) == CARRY_FLAG);
+                result = self.do_call_conditional(bus, (f & CARRY_FLAG) == CARRY_FLAG);
             }
             OpCodes::RET => {
-                self.do_return_conditional(true);
+                self.do_return_conditional(bus, true);
             }
             OpCodes::RETI => {
-                self.do_return_conditional(true);
+                self.do_return_conditional(bus, true);
                 self.ime = true;
             }
             OpCodes::RET_NZ => {
                 let f = self.f;
-                self.do_return_conditional((f & ZERO_FLAG) == 0);
+                self.do_return_conditional(bus, (f & ZERO_FLAG) == 0);
             }
             OpCodes::RET_Z => {
                 let f = self.f;
-                self.do_return_conditional((f & ZERO_FLAG) == ZERO_FLAG);
+                self.do_return_conditional(bus, (f & ZERO_FLAG) == ZERO_FLAG);
             }
             OpCodes::RET_NC => {
                 let f = self.f;
-                self.do_return_conditional((f & CARRY_FLAG) == 0);
+                self.do_return_conditional(bus, (f & CARRY_FLAG) == 0);
             }
             OpCodes::RET_C => {
                 let f = self.f;
-                self.do_return_conditional((f & CARRY_FLAG) == CARRY_FLAG);
+                self.do_return_conditional(bus, (f & CARRY_FLAG) == CARRY_FLAG);
             }
             OpCodes::RST_0 => {
                 let pc = self.pc;
-                result = self.push_address(pc);
+                result = self.push_address(bus, pc);
                 self.pc = RamAddress::new(0x0000);
             }
             OpCodes::RST_1 => {
                 let pc = self.pc;
-                result = self.push_address(pc);
+                result = self.push_address(bus, pc);
                 self.pc = RamAddress::new(0x0008);
             }
             OpCodes::RST_2 => {
                 let pc = self.pc;
-                result = self.push_address(pc);
+                result = self.push_address(bus, pc);
                 self.pc = RamAddress::new(0x0010);
             }
             OpCodes::RST_3 => {
                 let pc = self.pc;
-                result = self.push_address(pc);
+                result = self.push_address(bus, pc);
                 self.pc = RamAddress::new(0x0018);
             }
             OpCodes::RST_4 => {
                 let pc = self.pc;
-                result = self.push_address(pc);
+                result = self.push_address(bus, pc);
                 self.pc = RamAddress::new(0x0020);
             }
             OpCodes::RST_5 => {
                 let pc = self.pc;
-                result = self.push_address(pc);
+                result = self.push_address(bus, pc);
                 self.pc = RamAddress::new(0x0028);
             }
             OpCodes::RST_6 => {
                 let pc = self.pc;
-                result = self.push_address(pc);
+                result = self.push_address(bus, pc);
                 self.pc = RamAddress::new(0x0030);
             }
             OpCodes::RST_7 => {
                 let pc = self.pc;
-                result = self.push_address(pc);
+                result = self.push_address(bus, pc);
                 self.pc = RamAddress::new(0x0038);
             }
             OpCodes::DAA => {
@@ -1619,9 +1794,15 @@ impl DmgCpu {
                 self.halt = true;
             }
             OpCodes::STOP => {
-                // TODO: set all inputs to self.lOW
-                self.stop = true;
-                result = self.mc.borrow_mut().write(IE_ADDR, 0);
+                if bus.switch_speed() {
+                    // an armed KEY1 turns STOP into a speed switch, and the
+                    // byte after it is skipped
+                    self.read_pc_mem_and_increment(bus);
+                } else {
+                    // TODO: set all inputs to self.lOW
+                    self.stop = true;
+                    result = self.write_memory(bus, IE_ADDR, 0);
+                }
             }
             OpCodes::EI => {
                 self.ime = true;
@@ -1635,4 +1816,122 @@ impl DmgCpu {
 
         result
     }
+
+    // Strictness is a setting rather than machine state, so isn't saved
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for &reg in [
+            self.a, self.b, self.c, self.d, self.e, self.f, self.h, self.l,
+        ]
+        .iter()
+        {
+            state.put_u8(reg);
+        }
+        state.put_u16(self.sp.get());
+        state.put_u16(self.pc.get());
+        state.put_bool(self.ime);
+        state.put_bool(self.halt);
+        state.put_bool(self.stop);
+        state.put_bool(self.locked);
+        state.put_u16(self.op_pc);
+        state.put_u64(self.clock);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmuError> {
+        for reg in [
+            &mut self.a,
+            &mut self.b,
+            &mut self.c,
+            &mut self.d,
+            &mut self.e,
+            &mut self.f,
+            &mut self.h,
+            &mut self.l,
+        ] {
+            *reg = state.get_u8()?;
+        }
+        self.sp = RamAddress::new(state.get_u16()?);
+        self.pc = RamAddress::new(state.get_u16()?);
+        self.ime = state.get_bool()?;
+        self.halt = state.get_bool()?;
+        self.stop = state.get_bool()?;
+        self.locked = state.get_bool()?;
+        self.op_pc = state.get_u16()?;
+        self.clock = state.get_u64()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn stack_order_test() {
+    use gb_system::{test_rom, GameBoy};
+
+    // LD SP,$D000; LD BC,$1234; PUSH BC; POP DE; CALL $0110; NOP,
+    // with RET at $0110
+    let mut data = test_rom(&[
+        0x31, 0x00, 0xD0, 0x01, 0x34, 0x12, 0xC5, 0xD1, 0xCD, 0x10, 0x01, 0x00,
+    ]);
+    data[0x0110] = 0xC9;
+    let mut gb = GameBoy::from_bytes(data).unwrap();
+    for _ in 0..3 {
+        gb.step_instruction().unwrap();
+    }
+
+    // high byte goes in first, at the higher address
+    let regs = gb.registers();
+    assert!(regs.sp == 0xCFFE);
+    assert!(gb.read_memory(0xCFFF) == 0x12 && gb.read_memory(0xCFFE) == 0x34);
+
+    gb.step_instruction().unwrap();
+    let regs = gb.registers();
+    assert!(regs.d == 0x12 && regs.e == 0x34);
+    assert!(regs.sp == 0xD000);
+
+    // CALL pushes the address after itself the same way
+    gb.step_instruction().unwrap();
+    let regs = gb.registers();
+    assert!(regs.pc == 0x0110 && regs.sp == 0xCFFE);
+    assert!(gb.read_memory(0xCFFF) == 0x01 && gb.read_memory(0xCFFE) == 0x0B);
+
+    gb.step_instruction().unwrap();
+    let regs = gb.registers();
+    assert!(regs.pc == 0x010B && regs.sp == 0xD000);
+}
+
+#[test]
+fn interrupt_enable_test() {
+    use gb_system::{test_rom, GameBoy};
+
+    // DI; LD A,4; LDH [$FF],A; LDH [$0F],A; NOP; EI; NOP; NOP,
+    // with RETI in the timer handler
+    let mut data = test_rom(&[
+        0xF3, 0x3E, 0x04, 0xE0, 0xFF, 0xE0, 0x0F, 0x00, 0xFB, 0x00, 0x00,
+    ]);
+    data[0x0050] = 0xD9;
+    let mut gb = GameBoy::from_bytes(data).unwrap();
+
+    // the timer interrupt is pending but DI holds it off
+    for _ in 0..5 {
+        gb.step_instruction().unwrap();
+    }
+    assert!(gb.registers().pc == 0x0108);
+    assert!(gb.read_memory(IF_ADDR.get()) & 0x04 != 0);
+
+    // EI lets it through
+    let mut steps = 0;
+    while gb.registers().pc != 0x0050 {
+        gb.step_instruction().unwrap();
+        steps += 1;
+        assert!(steps <= 2);
+    }
+    assert!(gb.read_memory(IF_ADDR.get()) & 0x04 == 0);
+    let sp = gb.registers().sp;
+    let ret = gb.read_memory(sp) as u16 | (gb.read_memory(sp + 1) as u16) << 8;
+    assert!(ret == 0x0109 || ret == 0x010A);
+
+    // dispatching turned IME off, and RETI turns it back on
+    gb.write_memory(IF_ADDR.get(), 0x04).unwrap();
+    gb.step_instruction().unwrap();
+    assert!(gb.registers().pc == ret);
+    gb.step_instruction().unwrap();
+    assert!(gb.registers().pc == 0x0050);
 }
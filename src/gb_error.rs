@@ -0,0 +1,80 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use gb_mem::MemorySection;
+
+/// Everything that can go wrong while loading or running a machine.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmuError {
+    /// The cartridge image couldn't be understood. `offset` is the byte in
+    /// the image that was at fault.
+    RomParse { offset: usize, reason: String },
+    /// The CPU fetched a byte that isn't an instruction. `bank` is the ROM
+    /// bank mapped at `pc`, or 0 when running outside of switchable ROM.
+    UnknownOpcode { opcode: u8, pc: u16, bank: usize },
+    /// The CPU accessed memory in a way the hardware doesn't allow.
+    IllegalAccess { addr: u16, region: MemorySection },
+    /// A saved state couldn't be restored.
+    StateLoad(String),
+    /// Reading or writing a file failed.
+    Io(String),
+    /// A ROM patch couldn't be applied.
+    Patch(String),
+    /// An input movie couldn't be loaded, or playback went out of sync.
+    Movie(String),
+    /// A cheat code or cheat file couldn't be understood.
+    Cheat(String),
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EmuError::RomParse { offset, ref reason } => {
+                write!(f, "bad ROM at offset {:#06X}: {}", offset, reason)
+            }
+            EmuError::UnknownOpcode { opcode, pc, bank } => write!(
+                f,
+                "unknown opcode {:#04X} at {:02X}:{:04X}",
+                opcode, bank, pc
+            ),
+            EmuError::IllegalAccess { addr, region } => {
+                write!(f, "illegal access to {:#06X} ({:?})", addr, region)
+            }
+            EmuError::StateLoad(ref reason) => write!(f, "unable to load state: {}", reason),
+            EmuError::Io(ref reason) => write!(f, "I/O error: {}", reason),
+            EmuError::Patch(ref reason) => write!(f, "unable to patch ROM: {}", reason),
+            EmuError::Movie(ref reason) => write!(f, "movie: {}", reason),
+            EmuError::Cheat(ref reason) => write!(f, "bad cheat: {}", reason),
+        }
+    }
+}
+
+impl Error for EmuError {}
+
+/// How the machine reacts to code doing something the hardware doesn't
+/// allow, such as writing to unusable memory or executing an illegal opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Strictness {
+    /// Behave exactly like the console: bad writes are ignored and illegal
+    /// opcodes lock up the CPU.
+    #[default]
+    Hardware,
+    /// Behave like the console, but log every fault along with the PC.
+    Warn,
+    /// Stop with an error at the first fault.
+    Strict,
+}
+
+impl FromStr for Strictness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hardware" => Ok(Strictness::Hardware),
+            "warn" => Ok(Strictness::Warn),
+            "strict" => Ok(Strictness::Strict),
+            _ => Err(format!("unknown strictness: {}", s)),
+        }
+    }
+}
@@ -1,15 +1,94 @@
+use gb_error::EmuError;
+use gb_mem::{MemoryController, RamAddress};
+use gb_state::{StateReader, StateWriter};
+
+// Everything the CPU can reach. The CPU borrows the bus for the length of an
+// instruction, so the machine has one owner and no runtime borrow checks.
+pub trait Bus {
+    fn read(&mut self, addr: RamAddress) -> u8;
+    fn write(&mut self, addr: RamAddress, val: u8) -> Result<(), EmuError>;
+    fn sync(&mut self, count: u64);
+    // bank mapped at 0x4000-0x7FFF, for error reporting
+    fn rom_bank(&self) -> usize;
+    // Performs a CGB speed switch if KEY1 has one armed, for STOP
+    fn switch_speed(&mut self) -> bool;
+    // Cycles the CPU has to wait while DMA has the bus
+    fn take_stall(&mut self) -> u64;
+}
+
 #[derive(Debug)]
 pub struct HardwareBus {
+    mc: MemoryController,
     cycles: u64,
 }
 
 impl HardwareBus {
-    pub fn new() -> Self {
-        HardwareBus { cycles: 0u64 }
+    pub fn new(mc: MemoryController) -> Self {
+        HardwareBus { mc, cycles: 0u64 }
+    }
+
+    pub fn memory(&self) -> &MemoryController {
+        &self.mc
+    }
+
+    pub fn memory_mut(&mut self) -> &mut MemoryController {
+        &mut self.mc
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        self.mc.ppu().framebuffer()
+    }
+
+    pub fn rgb_framebuffer(&self) -> &[u16] {
+        match self.mc.sgb() {
+            Some(sgb) => sgb.screen(),
+            None => self.mc.ppu().rgb_framebuffer(),
+        }
+    }
+
+    pub fn take_audio_samples(&mut self) -> Vec<i16> {
+        self.mc.take_audio_samples()
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.mc.save_state(state);
+        state.put_u64(self.cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmuError> {
+        self.mc.load_state(state)?;
+        self.cycles = state.get_u64()?;
+        Ok(())
+    }
+}
+
+impl Bus for HardwareBus {
+    #[inline]
+    fn read(&mut self, addr: RamAddress) -> u8 {
+        self.mc.read(addr)
+    }
+
+    #[inline]
+    fn write(&mut self, addr: RamAddress, val: u8) -> Result<(), EmuError> {
+        self.mc.write(addr, val)
+    }
+
+    fn sync(&mut self, count: u64) {
+        // catch the rest of the hardware up to the CPU
+        let elapsed = count - self.cycles;
+        self.cycles = count;
+        self.mc.step(elapsed);
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.mc.cartridge().current_rom_bank()
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        self.mc.switch_speed()
     }
 
-    pub fn sync(&mut self, count: u64) {
-        // TODO: Update all the other things
-        self.cycles == count;
+    fn take_stall(&mut self) -> u64 {
+        self.mc.take_stall()
     }
 }
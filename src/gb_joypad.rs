@@ -0,0 +1,182 @@
+use gb_error::EmuError;
+use gb_state::{StateReader, StateWriter};
+
+// P1/JOYP select lines, active low
+const SELECT_DIRECTIONS: u8 = 1 << 4;
+const SELECT_BUTTONS: u8 = 1 << 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    /// Every button, in the order of the bits input movies store them in.
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    // bit in the low nibble of P1 once the matching group is selected
+    fn line(&self) -> u8 {
+        match *self {
+            Button::Right | Button::A => 1 << 0,
+            Button::Left | Button::B => 1 << 1,
+            Button::Up | Button::Select => 1 << 2,
+            Button::Down | Button::Start => 1 << 3,
+        }
+    }
+
+    fn is_direction(&self) -> bool {
+        matches!(
+            *self,
+            Button::Right | Button::Left | Button::Up | Button::Down
+        )
+    }
+}
+
+// the SGB can poll up to four controllers
+pub const MAX_PLAYERS: usize = 4;
+
+#[derive(Debug)]
+pub struct Joypad {
+    select: u8,
+    directions: [u8; MAX_PLAYERS], // pressed lines, active high
+    buttons: [u8; MAX_PLAYERS],
+
+    players: usize,
+    player: usize, // controller being read
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            select: SELECT_DIRECTIONS | SELECT_BUTTONS,
+            directions: [0; MAX_PLAYERS],
+            buttons: [0; MAX_PLAYERS],
+
+            players: 1,
+            player: 0,
+        }
+    }
+
+    // Set by the SGB's MLT_REQ: 1, 2 or 4 controllers
+    pub fn set_players(&mut self, players: usize) {
+        self.players = players.clamp(1, MAX_PLAYERS);
+        if self.player >= self.players {
+            self.player = 0;
+        }
+    }
+
+    pub fn set_button(&mut self, player: usize, button: Button, pressed: bool) {
+        let group = if button.is_direction() {
+            &mut self.directions[player]
+        } else {
+            &mut self.buttons[player]
+        };
+
+        if pressed {
+            *group |= button.line();
+        } else {
+            *group &= !button.line();
+        }
+    }
+
+    pub fn is_pressed(&self, player: usize, button: Button) -> bool {
+        let group = if button.is_direction() {
+            self.directions[player]
+        } else {
+            self.buttons[player]
+        };
+        group & button.line() != 0
+    }
+
+    pub fn read(&self) -> u8 {
+        // with nothing selected, a multiplayer SGB reports which controller
+        // is up next
+        if self.players > 1 && self.select == SELECT_DIRECTIONS | SELECT_BUTTONS {
+            return 0b1111_0000 | (0x0F - self.player as u8);
+        }
+
+        let mut pressed = 0u8;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            pressed |= self.directions[self.player];
+        }
+        if self.select & SELECT_BUTTONS == 0 {
+            pressed |= self.buttons[self.player];
+        }
+        0b1100_0000 | self.select | (!pressed & 0x0F)
+    }
+
+    pub fn write(&mut self, val: u8) {
+        let select = val & (SELECT_DIRECTIONS | SELECT_BUTTONS);
+        // P15 going high moves on to the next controller
+        if self.select & SELECT_BUTTONS == 0 && select & SELECT_BUTTONS != 0 {
+            self.player = (self.player + 1) % self.players;
+        }
+        self.select = select;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.put_u8(self.select);
+        state.put_bytes(&self.directions);
+        state.put_bytes(&self.buttons);
+        state.put_u64(self.players as u64);
+        state.put_u64(self.player as u64);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmuError> {
+        self.select = state.get_u8()?;
+        state.get_bytes_into(&mut self.directions)?;
+        state.get_bytes_into(&mut self.buttons)?;
+        self.players = state.get_index(MAX_PLAYERS + 1)?.max(1);
+        self.player = state.get_index(self.players)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn joypad_select_test() {
+    let mut joypad = Joypad::new();
+    joypad.set_button(0, Button::Start, true);
+    joypad.set_button(0, Button::Left, true);
+
+    assert!(joypad.read() == 0xFF);
+
+    joypad.write(!SELECT_BUTTONS);
+    assert!(joypad.read() == 0b1101_0111);
+
+    joypad.write(!SELECT_DIRECTIONS);
+    assert!(joypad.read() == 0b1110_1101);
+}
+
+#[test]
+fn joypad_multiplayer_test() {
+    let mut joypad = Joypad::new();
+    joypad.set_button(1, Button::A, true);
+    joypad.set_players(2);
+    assert!(joypad.read() == 0xFF);
+
+    joypad.write(!SELECT_BUTTONS);
+    assert!(joypad.read() & 0x0F == 0x0F);
+    joypad.write(0xFF);
+    assert!(joypad.read() == 0xFE);
+
+    joypad.write(!SELECT_BUTTONS);
+    assert!(joypad.read() & 0x0F == 0x0E);
+    joypad.write(0xFF);
+    assert!(joypad.read() == 0xFF);
+}
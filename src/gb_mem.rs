@@ -1,14 +1,40 @@
 use std::fmt;
 
+use cheat::Cheats;
+use gb_apu::Apu;
+use gb_cart::Cartridge;
+use gb_error::EmuError;
+use gb_joypad::{Button, Joypad};
+use gb_ppu::{compat_palettes, Ppu};
 use gb_rom::GbRom;
+use gb_serial::Serial;
+use gb_sgb::Sgb;
+use gb_state::{StateReader, StateWriter};
+use gb_system::Model;
+use gb_timer::Timer;
 
 const ADDR_MAX: u16 = 0xFFFF;
 
 pub const IE_ADDR: RamAddress = RamAddress { val: 0xFFFFu16 };
 pub const IF_ADDR: RamAddress = RamAddress { val: 0xFF0Fu16 };
 
-pub const SB_ADDR: RamAddress = RamAddress { val: 0xFF01u16 };
-pub const SC_ADDR: RamAddress = RamAddress { val: 0xFF02u16 };
+// IF/IE bits, in priority order
+pub const VBLANK_IF: u8 = 1;
+pub const LCDC_IF: u8 = 1 << 1;
+pub const TIMER_OVERFLOW_IF: u8 = 1 << 2;
+pub const SERIAL_IO_COMPLETE_IF: u8 = 1 << 3;
+pub const P10_P13_TERM_NEG_EDGE_IF: u8 = 1 << 4;
+
+// bank 0 plus the seven the CGB can switch into 0xD000-0xDFFF
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_BANKS: usize = 8;
+const HRAM_SIZE: usize = 0x7F;
+const OAM_DMA_LENGTH: u16 = 0xA0;
+
+// VRAM DMA moves 16 bytes in 8 single speed M-cycles, so twice as many CPU
+// cycles at double speed
+const VRAM_DMA_BLOCK: u16 = 0x10;
+const VRAM_DMA_BLOCK_CYCLES: u64 = 32;
 
 pub fn increment_16(high: &mut u8, low: &mut u8) {
     // does not affect flags
@@ -64,6 +90,7 @@ impl RamAddress {
         *self
     }
 
+    #[allow(dead_code)]
     pub fn post_dec(&mut self, val: u16) -> Self {
         let copy = *self;
         self.dec(val);
@@ -91,7 +118,8 @@ fn post_dec_test() {
     assert!(ra.get() == 9)
 }
 
-enum MemorySection {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemorySection {
     RestartInterrupts = 0x0000,
     Header = 0x0100,
     RomBank0 = 0x0150,
@@ -108,9 +136,100 @@ enum MemorySection {
     IERegister = 0xFFFF,
 }
 
+impl MemorySection {
+    pub fn from_address(addr: RamAddress) -> Self {
+        match addr.get() {
+            0x0000..=0x00FF => MemorySection::RestartInterrupts,
+            0x0100..=0x014F => MemorySection::Header,
+            0x0150..=0x3FFF => MemorySection::RomBank0,
+            0x4000..=0x7FFF => MemorySection::RomBankN,
+            0x8000..=0x9FFF => MemorySection::VRam,
+            0xA000..=0xBFFF => MemorySection::ExternalRam,
+            0xC000..=0xCFFF => MemorySection::WorkRam0,
+            0xD000..=0xDFFF => MemorySection::WorkRamN,
+            0xE000..=0xFDFF => MemorySection::Echo,
+            0xFE00..=0xFE9F => MemorySection::SpriteAttribute,
+            0xFEA0..=0xFEFF => MemorySection::Unusable,
+            0xFF00..=0xFF7F => MemorySection::IORegisters,
+            0xFF80..=0xFFFE => MemorySection::HighRam,
+            _ => MemorySection::IERegister,
+        }
+    }
+}
+
+/// The RAM a game keeps its variables in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RamRegion {
+    /// 0xC000-0xDFFF, with all seven switchable banks on a CGB.
+    WorkRam,
+    /// 0xFF80-0xFFFE.
+    HighRam,
+    /// 0xA000-0xBFFF, every bank the cart has.
+    CartRam,
+}
+
+impl RamRegion {
+    pub const ALL: [RamRegion; 3] = [RamRegion::WorkRam, RamRegion::HighRam, RamRegion::CartRam];
+
+    /// Bytes the CPU sees of the region at once. Offsets a bank apart can't
+    /// be read together.
+    pub fn bank_size(self) -> usize {
+        match self {
+            RamRegion::WorkRam => WRAM_BANK_SIZE,
+            RamRegion::HighRam => HRAM_SIZE,
+            RamRegion::CartRam => 0x2000,
+        }
+    }
+
+    /// Where `offset` into the region shows up for the CPU, as the bank it
+    /// needs switched in and the address.
+    pub fn address(self, offset: usize) -> (usize, u16) {
+        match self {
+            RamRegion::WorkRam if offset < WRAM_BANK_SIZE => (0, 0xC000 + offset as u16),
+            RamRegion::WorkRam => (
+                offset / WRAM_BANK_SIZE,
+                0xD000 + (offset % WRAM_BANK_SIZE) as u16,
+            ),
+            RamRegion::HighRam => (0, 0xFF80 + offset as u16),
+            RamRegion::CartRam => (
+                offset / self.bank_size(),
+                0xA000 + (offset % self.bank_size()) as u16,
+            ),
+        }
+    }
+}
+
+// Routes every CPU access to the component that owns that part of the map.
+// Work RAM and high RAM have no other owner, so they live here, as do the
+// CGB's WRAM bank and speed registers.
 pub struct MemoryController {
-    rom: GbRom,
-    ram: [u8; 0x10000], //65536 bytes
+    cart: Cartridge,
+    boot_rom: Option<Vec<u8>>, // mapped over the cart until 0xFF50 is written
+    wram: Vec<u8>,
+    hram: Vec<u8>,
+
+    cgb: bool, // CGB hardware running a CGB cart, not in DMG compatibility
+    wram_bank: usize,
+    double_speed: bool,
+    speed_armed: bool,
+
+    hdma_source: u16,
+    hdma_dest: u16,
+    hdma_blocks: u8, // blocks left to copy, less one, as HDMA5 shows it
+    hdma_active: bool,
+    stall: u64,
+
+    ppu: Ppu,
+    apu: Apu,
+    timer: Timer,
+    serial: Serial,
+    joypad: Joypad,
+    sgb: Option<Sgb>,
+    cheats: Cheats,
+
+    int_flag: u8,
+    int_enable: u8,
+    dma: u8,
 }
 
 impl fmt::Debug for MemoryController {
@@ -120,82 +239,580 @@ impl fmt::Debug for MemoryController {
 }
 
 impl MemoryController {
-    pub fn new(rom: GbRom) -> Self {
-        let mut mc = MemoryController {
-            rom: rom,
-            ram: [0u8; 0x10000],
-        };
+    pub fn new(rom: GbRom, model: Model) -> Self {
+        // the CGB boot ROM locks DMG carts out of the new registers
+        let cgb = model == Model::Cgb && rom.is_cgb();
+
+        let mut ppu = Ppu::new(cgb);
+        if model == Model::Cgb && !cgb {
+            ppu.set_compat_palettes(&compat_palettes(rom.data()));
+        }
+
+        MemoryController {
+            cart: Cartridge::new(rom),
+            boot_rom: None,
+            wram: vec![0u8; WRAM_BANK_SIZE * WRAM_BANKS],
+            hram: vec![0u8; HRAM_SIZE],
+
+            cgb,
+            wram_bank: 1,
+            double_speed: false,
+            speed_armed: false,
+
+            hdma_source: 0,
+            hdma_dest: 0x8000,
+            hdma_blocks: 0x7F,
+            hdma_active: false,
+            stall: 0,
+
+            ppu,
+            apu: Apu::new(),
+            timer: Timer::new(),
+            serial: Serial::new(),
+            joypad: Joypad::new(),
+            sgb: if model == Model::Sgb {
+                Some(Sgb::new())
+            } else {
+                None
+            },
+            cheats: Cheats::new(),
+
+            int_flag: VBLANK_IF,
+            int_enable: 0,
+            dma: 0xFF,
+        }
+    }
+
+    pub fn rom(&self) -> &GbRom {
+        self.cart.rom()
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cart
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cart
+    }
+
+    pub fn set_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn serial_output(&self) -> &[u8] {
+        self.serial.output()
+    }
 
-        {
-            let dest = &mut mc.ram[0x000..0x8000];
-            mc.rom.copy_current_slice(dest);
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.serial.take_output()
+    }
+
+    pub fn sgb(&self) -> Option<&Sgb> {
+        self.sgb.as_ref()
+    }
+
+    pub fn ram(&self, region: RamRegion) -> &[u8] {
+        match region {
+            // without CGB banking only the first two banks are ever mapped
+            RamRegion::WorkRam if self.cgb => &self.wram,
+            RamRegion::WorkRam => &self.wram[..WRAM_BANK_SIZE * 2],
+            RamRegion::HighRam => &self.hram,
+            RamRegion::CartRam => self.cart.ram(),
         }
+    }
 
-        mc
+    pub fn take_audio_samples(&mut self) -> Vec<i16> {
+        self.apu.take_samples()
+    }
+
+    pub fn cheats(&self) -> &Cheats {
+        &self.cheats
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut Cheats {
+        &mut self.cheats
+    }
+
+    pub fn is_pressed(&self, player: usize, button: Button) -> bool {
+        self.joypad.is_pressed(player, button)
+    }
+
+    pub fn set_button(&mut self, player: usize, button: Button, pressed: bool) {
+        let before = self.joypad.read();
+        self.joypad.set_button(player, button, pressed);
+        // any selected line going low requests the joypad interrupt
+        if before & !self.joypad.read() & 0x0F != 0 {
+            self.int_flag |= P10_P13_TERM_NEG_EDGE_IF;
+        }
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Called when the CPU executes STOP. Returns whether KEY1 had a switch
+    // armed, in which case the CPU carries on at the other speed.
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_armed {
+            return false;
+        }
+        self.speed_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    // Cycles the CPU loses to VRAM DMA since the last call
+    pub fn take_stall(&mut self) -> u64 {
+        std::mem::replace(&mut self.stall, 0)
+    }
+
+    // Advances every clocked component by `cycles` CPU cycles. At double
+    // speed the LCD still runs at the normal rate, so it sees half as many.
+    pub fn step(&mut self, cycles: u64) {
+        let dots = if self.double_speed {
+            cycles / 2
+        } else {
+            cycles
+        };
+        self.apu.step(dots);
+        let interrupts = self.ppu.step(dots);
+        if interrupts & VBLANK_IF != 0 {
+            if let Some(ref mut sgb) = self.sgb {
+                sgb.end_frame(self.ppu.framebuffer());
+            }
+            self.apply_ram_cheats();
+        }
+        self.int_flag |= interrupts;
+        if self.ppu.take_hblank() && self.hdma_active {
+            self.hdma_block();
+        }
+        self.int_flag |= self.timer.step(cycles);
+        self.int_flag |= self.serial.step(cycles);
     }
 
-    // Will panic if addr is outside of the size
     pub fn read(&self, addr: RamAddress) -> u8 {
-        self.ram[addr.get() as usize]
-        //self.rom.read_address(addr)
+        let a = addr.get();
+        match MemorySection::from_address(addr) {
+            MemorySection::RestartInterrupts
+            | MemorySection::Header
+            | MemorySection::RomBank0
+            | MemorySection::RomBankN => match self.boot_rom {
+                // the CGB boot ROM leaves a hole for the cart header
+                Some(ref boot) if (a as usize) < boot.len() && !(0x0100..0x0200).contains(&a) => {
+                    boot[a as usize]
+                }
+                _ => self.cheats.patch_rom(a, self.cart.read_rom(a)),
+            },
+            MemorySection::VRam => self.ppu.read_vram(a),
+            MemorySection::ExternalRam => self.cart.read_ram(a),
+            MemorySection::WorkRam0 | MemorySection::WorkRamN | MemorySection::Echo => {
+                self.wram[self.wram_index(a)]
+            }
+            MemorySection::SpriteAttribute => self.ppu.read_oam(a),
+            MemorySection::Unusable => 0x00,
+            MemorySection::IORegisters => self.read_io(a),
+            MemorySection::HighRam => self.hram[(a - 0xFF80) as usize],
+            MemorySection::IERegister => self.int_enable,
+        }
     }
 
-    // Will panic if addr is outside of the size
-    pub fn write(&mut self, addr: RamAddress, val: u8) -> Result<(), String> {
-        let idx = addr.get() as usize;
+    // Echo RAM mirrors 0xC000-0xDDFF, switched bank included
+    fn wram_index(&self, addr: u16) -> usize {
+        let offset = (addr as usize - 0xC000) & 0x1FFF;
+        if offset < WRAM_BANK_SIZE {
+            offset
+        } else {
+            self.wram_bank * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+        }
+    }
 
-        match idx {
-            0x0000...0x00FF => {
-                let err = format!("ERROR: trying to write to ROM bank 0: {}", idx);
-                // send this on to the ROM as it may cause a bank switch
-                return Err(err);
+    fn read_io(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF00 => self.joypad.read(),
+            0xFF01..=0xFF02 => self.serial.read(addr),
+            0xFF04..=0xFF07 => self.timer.read(addr),
+            0xFF0F => self.int_flag | 0xE0,
+            0xFF10..=0xFF3F => self.apu.read(addr),
+            0xFF46 => self.dma,
+            0xFF40..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6B => self.ppu.read(addr),
+            // the status bit reads 0 while an H-blank transfer is running
+            0xFF55 if self.cgb => {
+                if self.hdma_active {
+                    self.hdma_blocks
+                } else {
+                    0x80 | self.hdma_blocks
+                }
             }
-            0x4000...0x7FFF => {
-                let err = format!("ERROR: trying to write to switchable ROM bank: {}", idx);
-                // MAYBE send this to the ROM as well...?
-                return Err(err);
+            0xFF4D if self.cgb => 0x7E | (self.double_speed as u8) << 7 | self.speed_armed as u8,
+            0xFF70 if self.cgb => 0xF8 | self.wram_bank as u8,
+            // nothing drives the data bus for unmapped registers
+            _ => 0xFF,
+        }
+    }
+
+    // Illegal writes have the same effect they would on the console, which is
+    // usually none, and are then reported so the CPU can apply its policy.
+    pub fn write(&mut self, addr: RamAddress, val: u8) -> Result<(), EmuError> {
+        let a = addr.get();
+        match MemorySection::from_address(addr) {
+            MemorySection::RestartInterrupts
+            | MemorySection::Header
+            | MemorySection::RomBank0
+            | MemorySection::RomBankN => {
+                // the ROM itself can't be written, so anything landing here
+                // is a command for the bank controller. Without one the write
+                // goes nowhere, as plenty of ROM-only games expect.
+                self.cart.write_rom(a, val)
             }
-            0x8000...0x9FFF => {
-                // Video RAM...
+            MemorySection::VRam => self.ppu.write_vram(a, val),
+            MemorySection::ExternalRam => self.cart.write_ram(a, val),
+            MemorySection::WorkRam0 | MemorySection::WorkRamN | MemorySection::Echo => {
+                let idx = self.wram_index(a);
+                self.wram[idx] = val
             }
-            0xA000...0xBFFF => {
-                // Switchable RAM bank... (on cartridge, if available)
+            MemorySection::SpriteAttribute => self.ppu.write_oam(a, val),
+            MemorySection::Unusable => {
+                return Err(EmuError::IllegalAccess {
+                    addr: a,
+                    region: MemorySection::Unusable,
+                });
             }
-            0xC000...0xDFFF => {
-                // Internal RAM
-                // 0xD000...0xDFFF is switchable on CGB
-                self.ram[idx + 0x2000] = val;
+            MemorySection::IORegisters => self.write_io(a, val),
+            MemorySection::HighRam => self.hram[(a - 0xFF80) as usize] = val,
+            MemorySection::IERegister => self.int_enable = val,
+        }
+        Ok(())
+    }
+
+    // GameShark codes poke RAM once a frame, as the real one does from its
+    // V-blank handler
+    fn apply_ram_cheats(&mut self) {
+        let writes: Vec<_> = self.cheats.ram_writes().collect();
+        for (addr, val, wram_bank) in writes {
+            match (addr, wram_bank) {
+                (0xD000..=0xDFFF, Some(bank)) => {
+                    self.wram[bank * WRAM_BANK_SIZE + addr as usize - 0xD000] = val
+                }
+                (0xA000..=0xBFFF, _) => self.cart.write_ram(addr, val),
+                (0xC000..=0xDFFF, _) => {
+                    let idx = self.wram_index(addr);
+                    self.wram[idx] = val
+                }
+                (0xFF80..=0xFFFE, _) => self.hram[(addr - 0xFF80) as usize] = val,
+                _ => {}
             }
-            0xE000...0xFDFF => {
-                // mirror RAM -- probly shouldn't use...?
-                self.ram[idx - 0x2000] = val;
+        }
+    }
+
+    fn write_io(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF00 => {
+                self.joypad.write(val);
+                if let Some(ref mut sgb) = self.sgb {
+                    sgb.write_joypad(val);
+                    self.joypad.set_players(sgb.players());
+                }
             }
-            0xFE00...0xFE9F => {
-                //OAM -- Object attribute memory
+            0xFF01..=0xFF02 => self.serial.write(addr, val),
+            0xFF04..=0xFF07 => self.int_flag |= self.timer.write(addr, val),
+            0xFF0F => self.int_flag = val & 0x1F,
+            0xFF10..=0xFF3F => self.apu.write(addr, val),
+            0xFF46 => self.oam_dma(val),
+            // unmaps the boot ROM for good
+            0xFF50 if val != 0 => self.boot_rom = None,
+            0xFF40..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6B => {
+                self.int_flag |= self.ppu.write(addr, val)
             }
-            0xFEA0...0xFEFF => {
-                // Unusable Memory
-                let err = format!("ERROR: trying to write to unusable memory: {}", idx);
-                return Err(err);
+            0xFF51 if self.cgb => {
+                self.hdma_source = (self.hdma_source & 0x00FF) | (val as u16) << 8
             }
-            0xFF00...0xFF7F => {
-                // I/O ports
-                if addr == SC_ADDR {
-                    println!("Serial: {}", val);
-                }
+            0xFF52 if self.cgb => {
+                self.hdma_source = (self.hdma_source & 0xFF00) | (val & 0xF0) as u16
             }
-            0xFF80...0xFFFE => {
-                // High RAM
+            0xFF53 if self.cgb => {
+                self.hdma_dest = 0x8000 | (self.hdma_dest & 0x00FF) | ((val & 0x1F) as u16) << 8
             }
-            0xFFFF => {
-                // Interrupt enable register
+            0xFF54 if self.cgb => self.hdma_dest = (self.hdma_dest & 0xFF00) | (val & 0xF0) as u16,
+            0xFF55 if self.cgb => self.start_vram_dma(val),
+            0xFF4D if self.cgb => self.speed_armed = val & 0x01 != 0,
+            // bank 0 can't be selected, asking for it gives bank 1
+            0xFF70 if self.cgb => self.wram_bank = (val as usize & 0x07).max(1),
+            _ => (),
+        }
+    }
+
+    fn start_vram_dma(&mut self, val: u8) {
+        if self.hdma_active && val & 0x80 == 0 {
+            // writing 0 to bit 7 stops an H-blank transfer, leaving the
+            // blocks it didn't get to in HDMA5
+            self.hdma_active = false;
+            return;
+        }
+
+        self.hdma_blocks = val & 0x7F;
+        if val & 0x80 != 0 {
+            self.hdma_active = true;
+            // with the LCD off there are no H-blanks to wait for
+            if !self.ppu.enabled() {
+                self.hdma_block();
             }
-            _ => {
-                println!("WARNING: Unsupported memory write to {}", idx);
+        } else {
+            // general purpose DMA copies everything at once
+            for _ in 0..=self.hdma_blocks {
+                self.copy_vram_block();
             }
+            self.hdma_blocks = 0x7F;
+        }
+    }
+
+    fn copy_vram_block(&mut self) {
+        for _ in 0..VRAM_DMA_BLOCK {
+            let val = self.read(RamAddress::new(self.hdma_source));
+            self.ppu.write_vram(self.hdma_dest, val);
+            self.hdma_source = self.hdma_source.wrapping_add(1);
+            self.hdma_dest = 0x8000 | (self.hdma_dest.wrapping_add(1) & 0x1FFF);
+        }
+
+        self.stall += if self.double_speed {
+            VRAM_DMA_BLOCK_CYCLES * 2
+        } else {
+            VRAM_DMA_BLOCK_CYCLES
         };
+    }
+
+    // One H-blank's worth of an H-blank transfer
+    fn hdma_block(&mut self) {
+        self.copy_vram_block();
+        if self.hdma_blocks == 0 {
+            self.hdma_active = false;
+            self.hdma_blocks = 0x7F;
+        } else {
+            self.hdma_blocks -= 1;
+        }
+    }
+
+    // Copies a page into OAM. The transfer is done all at once rather than
+    // over 160 cycles.
+    fn oam_dma(&mut self, page: u8) {
+        self.dma = page;
+        let source = (page as u16) << 8;
+        for i in 0..OAM_DMA_LENGTH {
+            let val = self.read(RamAddress::new(source + i));
+            self.ppu.write_oam(0xFE00 + i, val);
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.cart.save_state(state);
+        state.put_bytes(self.boot_rom.as_deref().unwrap_or(&[]));
+        state.put_bytes(&self.wram);
+        state.put_bytes(&self.hram);
+
+        state.put_u64(self.wram_bank as u64);
+        state.put_bool(self.double_speed);
+        state.put_bool(self.speed_armed);
+        state.put_u16(self.hdma_source);
+        state.put_u16(self.hdma_dest);
+        state.put_u8(self.hdma_blocks);
+        state.put_bool(self.hdma_active);
+        state.put_u64(self.stall);
+
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+        self.timer.save_state(state);
+        self.serial.save_state(state);
+        self.joypad.save_state(state);
+        if let Some(ref sgb) = self.sgb {
+            sgb.save_state(state);
+        }
+
+        state.put_u8(self.int_flag);
+        state.put_u8(self.int_enable);
+        state.put_u8(self.dma);
+    }
+
+    // The state has to come from the same model, which decides whether
+    // there's an SGB
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmuError> {
+        self.cart.load_state(state)?;
+        let boot_rom = state.get_bytes()?;
+        self.boot_rom = if boot_rom.is_empty() {
+            None
+        } else {
+            Some(boot_rom.to_vec())
+        };
+        state.get_bytes_into(&mut self.wram)?;
+        state.get_bytes_into(&mut self.hram)?;
+
+        self.wram_bank = state.get_index(WRAM_BANKS)?;
+        self.double_speed = state.get_bool()?;
+        self.speed_armed = state.get_bool()?;
+        self.hdma_source = state.get_u16()?;
+        self.hdma_dest = state.get_u16()?;
+        self.hdma_blocks = state.get_u8()?;
+        self.hdma_active = state.get_bool()?;
+        self.stall = state.get_u64()?;
 
-        self.ram[idx] = val;
+        self.ppu.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.joypad.load_state(state)?;
+        if let Some(ref mut sgb) = self.sgb {
+            sgb.load_state(state)?;
+        }
+
+        self.int_flag = state.get_u8()?;
+        self.int_enable = state.get_u8()?;
+        self.dma = state.get_u8()?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+fn test_controller() -> MemoryController {
+    MemoryController::new(GbRom::from_bytes(vec![0u8; 0x8000]).unwrap(), Model::Dmg)
+}
+
+#[test]
+fn cgb_banking_test() {
+    let mut data = vec![0u8; 0x8000];
+    data[0x0143] = 0xC0;
+    let mut mc = MemoryController::new(GbRom::from_bytes(data).unwrap(), Model::Cgb);
+    let addr = RamAddress::new(0xD000);
+
+    assert!(mc.read_io(0xFF70) == 0xF9);
+    mc.write(addr, 0x11).unwrap();
+    mc.write_io(0xFF70, 0x03);
+    assert!(mc.read(addr) == 0x00);
+    mc.write(addr, 0x33).unwrap();
+    assert!(mc.read(RamAddress::new(0xF000)) == 0x33);
+    // bank 0 selects bank 1
+    mc.write_io(0xFF70, 0x00);
+    assert!(mc.read(addr) == 0x11);
+
+    let vram = RamAddress::new(0x8000);
+    mc.write(vram, 0x22).unwrap();
+    mc.write_io(0xFF4F, 0x01);
+    assert!(mc.read_io(0xFF4F) == 0xFF);
+    assert!(mc.read(vram) == 0x00);
+    mc.write_io(0xFF4F, 0x00);
+    assert!(mc.read(vram) == 0x22);
+
+    // none of it exists on a DMG
+    let mut mc = test_controller();
+    mc.write_io(0xFF70, 0x03);
+    assert!(mc.read_io(0xFF70) == 0xFF);
+    assert!(mc.read_io(0xFF4F) == 0xFF);
+}
+
+#[test]
+fn vram_dma_test() {
+    let mut data = vec![0u8; 0x8000];
+    data[0x0143] = 0x80;
+    for (i, b) in data[0x4000..0x4040].iter_mut().enumerate() {
+        *b = i as u8 + 1;
+    }
+    let mut mc = MemoryController::new(GbRom::from_bytes(data).unwrap(), Model::Cgb);
+
+    // general purpose: two blocks from 0x4000 to 0x8100, all at once
+    for &(addr, val) in [
+        (0xFF51, 0x40),
+        (0xFF52, 0x00),
+        (0xFF53, 0x01),
+        (0xFF54, 0x00),
+    ]
+    .iter()
+    {
+        mc.write_io(addr, val);
+    }
+    mc.write_io(0xFF55, 0x01);
+    assert!(mc.read(RamAddress::new(0x811F)) == 0x20);
+    assert!(mc.read_io(0xFF55) == 0xFF);
+    assert!(mc.take_stall() == 2 * VRAM_DMA_BLOCK_CYCLES);
+
+    // H-blank: the next two blocks, one per line
+    mc.write_io(0xFF55, 0x81);
+    assert!(mc.read_io(0xFF55) == 0x01);
+    mc.step(456);
+    assert!(mc.read(RamAddress::new(0x812F)) == 0x30);
+    assert!(mc.read(RamAddress::new(0x8130)) == 0x00);
+    assert!(mc.read_io(0xFF55) == 0x00);
+
+    // cancelled before the second block
+    mc.write_io(0xFF55, 0x00);
+    assert!(mc.read_io(0xFF55) == 0x80);
+    mc.step(456);
+    assert!(mc.read(RamAddress::new(0x8130)) == 0x00);
+}
+
+#[test]
+fn echo_ram_test() {
+    let mut mc = test_controller();
+    mc.write(RamAddress::new(0xC123), 0x42).unwrap();
+    assert!(mc.read(RamAddress::new(0xE123)) == 0x42);
+
+    mc.write(RamAddress::new(0xFDFF), 0x24).unwrap();
+    assert!(mc.read(RamAddress::new(0xDDFF)) == 0x24);
+}
+
+#[test]
+fn io_read_mask_test() {
+    let mut mc = test_controller();
+    mc.write(IF_ADDR, 0x00).unwrap();
+    assert!(mc.read(IF_ADDR) == 0xE0);
+
+    // unmapped registers float high no matter what was written
+    mc.write(RamAddress::new(0xFF03), 0x00).unwrap();
+    assert!(mc.read(RamAddress::new(0xFF03)) == 0xFF);
+    assert!(mc.read(RamAddress::new(0xFF4D)) == 0xFF);
+
+    mc.write(RamAddress::new(0xFF41), 0x00).unwrap();
+    assert!(mc.read(RamAddress::new(0xFF41)) & 0x80 == 0x80);
+}
+
+#[test]
+fn illegal_write_test() {
+    let mut mc = test_controller();
+    let err = mc.write(RamAddress::new(0xFEA0), 0x42).unwrap_err();
+    assert!(
+        err == EmuError::IllegalAccess {
+            addr: 0xFEA0,
+            region: MemorySection::Unusable,
+        }
+    );
+
+    // a stray bank select on a cart with no MBC is harmless
+    assert!(mc.write(RamAddress::new(0x2000), 0x01).is_ok());
+    assert!(mc.read(RamAddress::new(0x2000)) == 0x00);
+}
+
+#[test]
+fn cheats_test() {
+    use cheat::Cheat;
+
+    let mut data = vec![0u8; 0x8000];
+    data[0x4A17] = 0xC9u8.rotate_right(2) ^ 0xBA;
+    let mut mc = MemoryController::new(GbRom::from_bytes(data).unwrap(), Model::Dmg);
+    mc.cheats_mut().add(Cheat::new("00A-17B-C49").unwrap());
+    mc.cheats_mut().add(Cheat::new("3EA-0AF-C49").unwrap());
+    mc.cheats_mut().add(Cheat::new("01FF57D1").unwrap());
+    assert!(mc.read(RamAddress::new(0x4A17)) == 0x00);
+    // the compare byte doesn't match what's there
+    assert!(mc.read(RamAddress::new(0x0A0A)) == 0x00);
+
+    // RAM is only written at V-blank
+    mc.write(RamAddress::new(0xD157), 0x01).unwrap();
+    assert!(mc.read(RamAddress::new(0xD157)) == 0x01);
+    mc.step(70224);
+    assert!(mc.read(RamAddress::new(0xD157)) == 0xFF);
+
+    mc.cheats_mut().set_enabled(2, false);
+    mc.write(RamAddress::new(0xD157), 0x01).unwrap();
+    mc.step(70224);
+    assert!(mc.read(RamAddress::new(0xD157)) == 0x01);
+}
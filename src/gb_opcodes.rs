@@ -1,5 +1,4 @@
-use num::FromPrimitive;
-
+#[allow(dead_code)]
 struct OpCodeInfo {
     code: u8,
     cycles: usize,
@@ -250,8 +249,8 @@ pub enum OpCodes {
     NOP = 0x00,
     HALT = 0x76,
     STOP = 0x10,
-    EI = 0xF3,
-    DI = 0xFB,
+    DI = 0xF3,
+    EI = 0xFB,
 }
 }
 
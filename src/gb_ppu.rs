@@ -0,0 +1,977 @@
+use gb_error::EmuError;
+use gb_mem::{LCDC_IF, VBLANK_IF};
+use gb_state::{StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const DOTS_PER_LINE: u64 = 456;
+const OAM_SCAN_END: u64 = 80;
+const DRAWING_END: u64 = OAM_SCAN_END + 172;
+const VBLANK_START: u8 = SCREEN_HEIGHT as u8;
+const LINES_PER_FRAME: u8 = 154;
+
+const VRAM_BANK_SIZE: usize = 0x2000;
+const OAM_SIZE: usize = 0xA0;
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+const LCDC_ENABLE: u8 = 1 << 7;
+const LCDC_WINDOW_MAP: u8 = 1 << 6;
+const LCDC_WINDOW_ENABLE: u8 = 1 << 5;
+const LCDC_TILE_DATA: u8 = 1 << 4;
+const LCDC_BG_MAP: u8 = 1 << 3;
+const LCDC_OBJ_SIZE: u8 = 1 << 2;
+const LCDC_OBJ_ENABLE: u8 = 1 << 1;
+const LCDC_BG_ENABLE: u8 = 1;
+
+const STAT_LYC_INT: u8 = 1 << 6;
+const STAT_MODE2_INT: u8 = 1 << 5;
+const STAT_MODE1_INT: u8 = 1 << 4;
+const STAT_MODE0_INT: u8 = 1 << 3;
+const STAT_COINCIDENCE: u8 = 1 << 2;
+const STAT_WRITABLE: u8 = 0b0111_1000;
+
+const OBJ_BEHIND_BG: u8 = 1 << 7;
+const OBJ_Y_FLIP: u8 = 1 << 6;
+const OBJ_X_FLIP: u8 = 1 << 5;
+const OBJ_PALETTE: u8 = 1 << 4;
+const OBJ_CGB_BANK: u8 = 1 << 3;
+const OBJ_CGB_PALETTE: u8 = 0b111;
+
+// BG map attributes, kept in VRAM bank 1 alongside the tile numbers
+const BG_PRIORITY: u8 = 1 << 7;
+const BG_Y_FLIP: u8 = 1 << 6;
+const BG_X_FLIP: u8 = 1 << 5;
+const BG_BANK: u8 = 1 << 3;
+const BG_PALETTE: u8 = 0b111;
+
+// eight palettes of four little endian RGB555 colours
+const PALETTE_RAM_SIZE: usize = 64;
+const PALETTE_INDEX: u8 = 0x3F;
+const PALETTE_AUTO_INCREMENT: u8 = 1 << 7;
+
+// DMG shades from lightest to darkest
+const DMG_GREYS: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PpuMode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+// The LCD controller: owns VRAM, OAM, 0xFF40-0xFF4B (except DMA, which
+// needs the whole bus and lives in the memory controller) and the CGB's VBK.
+#[derive(Debug)]
+pub struct Ppu {
+    vram: Vec<u8>, // both CGB banks, bank 0 first
+    vram_bank: usize,
+    cgb: bool,
+    oam: Vec<u8>,
+
+    lcdc: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+
+    mode: PpuMode,
+    dot: u64,
+    window_line: u8,
+    stat_line: bool,
+    frames: u64,
+    hblank_started: bool,
+
+    bcps: u8,
+    ocps: u8,
+    bg_palettes: [u8; PALETTE_RAM_SIZE],
+    obj_palettes: [u8; PALETTE_RAM_SIZE],
+
+    framebuffer: Vec<u8>,
+    rgb_framebuffer: Vec<u16>,
+}
+
+fn palette_ram(colours: &[[u16; 4]]) -> [u8; PALETTE_RAM_SIZE] {
+    let mut ram = [0xFFu8; PALETTE_RAM_SIZE];
+    for (palette, chunk) in colours.iter().zip(ram.chunks_mut(8)) {
+        for (colour, bytes) in palette.iter().zip(chunk.chunks_mut(2)) {
+            bytes.copy_from_slice(&colour.to_le_bytes());
+        }
+    }
+    ram
+}
+
+impl Ppu {
+    pub fn new(cgb: bool) -> Self {
+        Ppu {
+            vram: vec![0u8; VRAM_BANK_SIZE * 2],
+            vram_bank: 0,
+            cgb,
+            oam: vec![0u8; OAM_SIZE],
+
+            lcdc: 0x91,
+            stat: 0x00,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+
+            mode: PpuMode::OamScan,
+            dot: 0,
+            window_line: 0,
+            stat_line: false,
+            frames: 0,
+            hblank_started: false,
+
+            // the DMG's shades go through the same palette RAM as colour,
+            // which the CGB boot ROM leaves white
+            bcps: 0,
+            ocps: 0,
+            bg_palettes: palette_ram(&[DMG_GREYS]),
+            obj_palettes: palette_ram(&[DMG_GREYS, DMG_GREYS]),
+
+            framebuffer: vec![0u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+            rgb_framebuffer: vec![0u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    // Sets the colours a DMG cart gets on CGB hardware, as the boot ROM does
+    pub fn set_compat_palettes(&mut self, palettes: &CompatPalettes) {
+        self.bg_palettes = palette_ram(&[palettes.bg]);
+        self.obj_palettes = palette_ram(&[palettes.obj0, palettes.obj1]);
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    pub fn rgb_framebuffer(&self) -> &[u16] {
+        &self.rgb_framebuffer
+    }
+
+    // number of frames finished since power on
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    // True once after each visible line finishes, for H-blank DMA
+    pub fn take_hblank(&mut self) -> bool {
+        std::mem::replace(&mut self.hblank_started, false)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.lcdc & LCDC_ENABLE != 0
+    }
+
+    // Advances by `cycles` dots and returns any interrupts raised
+    pub fn step(&mut self, cycles: u64) -> u8 {
+        if !self.enabled() {
+            return 0;
+        }
+
+        let mut interrupts = 0;
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let boundary = match self.mode {
+                PpuMode::OamScan => OAM_SCAN_END,
+                PpuMode::Drawing => DRAWING_END,
+                PpuMode::HBlank | PpuMode::VBlank => DOTS_PER_LINE,
+            };
+
+            let run = remaining.min(boundary - self.dot);
+            self.dot += run;
+            remaining -= run;
+
+            if self.dot == boundary {
+                interrupts |= self.advance_mode();
+            }
+        }
+        interrupts
+    }
+
+    fn advance_mode(&mut self) -> u8 {
+        let mut interrupts = 0;
+        match self.mode {
+            PpuMode::OamScan => self.mode = PpuMode::Drawing,
+            PpuMode::Drawing => {
+                let ly = self.ly;
+                self.render_scanline(ly);
+                self.mode = PpuMode::HBlank;
+                self.hblank_started = true;
+            }
+            PpuMode::HBlank => {
+                self.dot = 0;
+                self.ly += 1;
+                if self.ly == VBLANK_START {
+                    self.mode = PpuMode::VBlank;
+                    self.frames += 1;
+                    interrupts |= VBLANK_IF;
+                } else {
+                    self.mode = PpuMode::OamScan;
+                }
+            }
+            PpuMode::VBlank => {
+                self.dot = 0;
+                self.ly += 1;
+                if self.ly == LINES_PER_FRAME {
+                    self.ly = 0;
+                    self.window_line = 0;
+                    self.mode = PpuMode::OamScan;
+                }
+            }
+        }
+
+        interrupts | self.update_stat_line()
+    }
+
+    // The STAT interrupt fires on the rising edge of all its sources ORed
+    // together, so overlapping sources don't fire twice.
+    fn update_stat_line(&mut self) -> u8 {
+        let line = (self.stat & STAT_LYC_INT != 0 && self.ly == self.lyc)
+            || (self.stat & STAT_MODE0_INT != 0 && self.mode == PpuMode::HBlank)
+            || (self.stat & STAT_MODE1_INT != 0 && self.mode == PpuMode::VBlank)
+            || (self.stat & STAT_MODE2_INT != 0 && self.mode == PpuMode::OamScan);
+
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+        if rising {
+            LCDC_IF
+        } else {
+            0
+        }
+    }
+
+    fn tile_row(&self, bank: usize, addr: usize) -> (u8, u8) {
+        let addr = bank * VRAM_BANK_SIZE + addr;
+        (self.vram[addr], self.vram[addr + 1])
+    }
+
+    fn pixel_color(row: (u8, u8), col: usize) -> u8 {
+        let bit = 7 - col;
+        (((row.1 >> bit) & 1) << 1) | ((row.0 >> bit) & 1)
+    }
+
+    // address in VRAM of a BG/window tile row, honouring the addressing mode
+    fn bg_tile_addr(&self, tile: u8, row: usize) -> usize {
+        let base = if self.lcdc & LCDC_TILE_DATA != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + (tile as i8 as isize) * 16) as usize
+        };
+        base + row * 2
+    }
+
+    fn shade(palette: u8, color: u8) -> u8 {
+        (palette >> (color * 2)) & 0b11
+    }
+
+    fn rgb(ram: &[u8; PALETTE_RAM_SIZE], palette: u8, color: u8) -> u16 {
+        let i = palette as usize * 8 + color as usize * 2;
+        u16::from_le_bytes([ram[i], ram[i + 1]]) & 0x7FFF
+    }
+
+    // Fetches one BG or window pixel from the tile map at `map`, returning
+    // its color number and, on CGB, its map attributes.
+    fn bg_pixel(&self, map: usize, px: usize, py: usize) -> (u8, u8) {
+        let idx = map + (py / 8) * 32 + px / 8;
+        let tile = self.vram[idx];
+        let attr = if self.cgb {
+            self.vram[VRAM_BANK_SIZE + idx]
+        } else {
+            0
+        };
+
+        let (mut col, mut row) = (px % 8, py % 8);
+        if attr & BG_X_FLIP != 0 {
+            col = 7 - col;
+        }
+        if attr & BG_Y_FLIP != 0 {
+            row = 7 - row;
+        }
+        let bank = if attr & BG_BANK != 0 { 1 } else { 0 };
+        let data = self.tile_row(bank, self.bg_tile_addr(tile, row));
+        (Ppu::pixel_color(data, col), attr)
+    }
+
+    fn render_scanline(&mut self, ly: u8) {
+        let y = ly as usize;
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+        let mut bg_attrs = [0u8; SCREEN_WIDTH];
+
+        // on CGB the enable bit only takes away the background's priority
+        if self.cgb || self.lcdc & LCDC_BG_ENABLE != 0 {
+            let map = if self.lcdc & LCDC_BG_MAP != 0 {
+                0x1C00
+            } else {
+                0x1800
+            };
+            let py = ly.wrapping_add(self.scy) as usize;
+            for x in 0..SCREEN_WIDTH {
+                let px = (x as u8).wrapping_add(self.scx) as usize;
+                let (color, attr) = self.bg_pixel(map, px, py);
+                bg_colors[x] = color;
+                bg_attrs[x] = attr;
+            }
+
+            // the DMG window is hidden along with the background
+            let wx = self.wx as isize - 7;
+            if self.lcdc & LCDC_WINDOW_ENABLE != 0 && self.wy <= ly && wx < SCREEN_WIDTH as isize {
+                let map = if self.lcdc & LCDC_WINDOW_MAP != 0 {
+                    0x1C00
+                } else {
+                    0x1800
+                };
+                let py = self.window_line as usize;
+                for x in wx.max(0) as usize..SCREEN_WIDTH {
+                    let px = (x as isize - wx) as usize;
+                    let (color, attr) = self.bg_pixel(map, px, py);
+                    bg_colors[x] = color;
+                    bg_attrs[x] = attr;
+                }
+                self.window_line += 1;
+            }
+        }
+
+        let line = y * SCREEN_WIDTH;
+        for x in 0..SCREEN_WIDTH {
+            let color = bg_colors[x];
+            let (shade, palette) = if self.cgb {
+                (color, bg_attrs[x] & BG_PALETTE)
+            } else {
+                (Ppu::shade(self.bgp, color), 0)
+            };
+            self.framebuffer[line + x] = shade;
+            self.rgb_framebuffer[line + x] = Ppu::rgb(&self.bg_palettes, palette, shade);
+        }
+
+        if self.lcdc & LCDC_OBJ_ENABLE != 0 {
+            self.render_sprites(ly, &bg_colors, &bg_attrs);
+        }
+    }
+
+    fn render_sprites(
+        &mut self,
+        ly: u8,
+        bg_colors: &[u8; SCREEN_WIDTH],
+        bg_attrs: &[u8; SCREEN_WIDTH],
+    ) {
+        let height = if self.lcdc & LCDC_OBJ_SIZE != 0 {
+            16
+        } else {
+            8
+        };
+        let line_y = ly as isize + 16;
+
+        let mut sprites: Vec<usize> = (0..OAM_SIZE / 4)
+            .map(|i| i * 4)
+            .filter(|&i| {
+                let sy = self.oam[i] as isize;
+                line_y >= sy && line_y < sy + height
+            })
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+        // lower X wins, then lower OAM index (the sort is stable). The CGB
+        // only goes by OAM index.
+        if !self.cgb {
+            sprites.sort_by_key(|&i| self.oam[i + 1]);
+        }
+
+        // with the CGB's master priority bit clear, sprites go over everything
+        let master_priority = !self.cgb || self.lcdc & LCDC_BG_ENABLE != 0;
+
+        let mut drawn = [false; SCREEN_WIDTH];
+        let y = ly as usize;
+        for i in sprites {
+            let sy = self.oam[i] as isize - 16;
+            let sx = self.oam[i + 1] as isize - 8;
+            let attr = self.oam[i + 3];
+            let mut tile = self.oam[i + 2] as usize;
+            if height == 16 {
+                tile &= 0xFE;
+            }
+
+            let mut row = (ly as isize - sy) as usize;
+            if attr & OBJ_Y_FLIP != 0 {
+                row = height as usize - 1 - row;
+            }
+            let bank = if self.cgb && attr & OBJ_CGB_BANK != 0 {
+                1
+            } else {
+                0
+            };
+            let data = self.tile_row(bank, tile * 16 + row * 2);
+
+            for col in 0..8 {
+                let x = sx + col as isize;
+                if x < 0 || x >= SCREEN_WIDTH as isize || drawn[x as usize] {
+                    continue;
+                }
+                let x = x as usize;
+
+                let bit = if attr & OBJ_X_FLIP != 0 { 7 - col } else { col };
+                let color = Ppu::pixel_color(data, bit);
+                if color == 0 {
+                    continue;
+                }
+                drawn[x] = true;
+
+                let behind = attr & OBJ_BEHIND_BG != 0 || bg_attrs[x] & BG_PRIORITY != 0;
+                if master_priority && behind && bg_colors[x] != 0 {
+                    continue;
+                }
+
+                let (shade, palette) = if self.cgb {
+                    (color, attr & OBJ_CGB_PALETTE)
+                } else if attr & OBJ_PALETTE != 0 {
+                    (Ppu::shade(self.obp1, color), 1)
+                } else {
+                    (Ppu::shade(self.obp0, color), 0)
+                };
+                self.framebuffer[y * SCREEN_WIDTH + x] = shade;
+                self.rgb_framebuffer[y * SCREEN_WIDTH + x] =
+                    Ppu::rgb(&self.obj_palettes, palette, shade);
+            }
+        }
+    }
+
+    fn vram_index(&self, addr: u16) -> usize {
+        self.vram_bank * VRAM_BANK_SIZE + (addr as usize & (VRAM_BANK_SIZE - 1))
+    }
+
+    pub fn read_vram(&self, addr: u16) -> u8 {
+        self.vram[self.vram_index(addr)]
+    }
+
+    pub fn write_vram(&mut self, addr: u16, val: u8) {
+        let idx = self.vram_index(addr);
+        self.vram[idx] = val;
+    }
+
+    pub fn read_oam(&self, addr: u16) -> u8 {
+        self.oam[(addr - 0xFE00) as usize]
+    }
+
+    pub fn write_oam(&mut self, addr: u16, val: u8) {
+        self.oam[(addr - 0xFE00) as usize] = val;
+    }
+
+    // A write to BCPD/OCPD, moving the index on if its spec asks for it
+    fn write_palette(ram: &mut [u8; PALETTE_RAM_SIZE], spec: &mut u8, val: u8) {
+        ram[(*spec & PALETTE_INDEX) as usize] = val;
+        if *spec & PALETTE_AUTO_INCREMENT != 0 {
+            *spec = PALETTE_AUTO_INCREMENT | ((*spec + 1) & PALETTE_INDEX);
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF40 => self.lcdc,
+            0xFF41 => {
+                let coincidence = if self.ly == self.lyc {
+                    STAT_COINCIDENCE
+                } else {
+                    0
+                };
+                let mode = if self.enabled() { self.mode as u8 } else { 0 };
+                0x80 | (self.stat & STAT_WRITABLE) | coincidence | mode
+            }
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF4F if self.cgb => self.vram_bank as u8 | 0xFE,
+            0xFF68 if self.cgb => self.bcps | 0x40,
+            0xFF69 if self.cgb => self.bg_palettes[(self.bcps & PALETTE_INDEX) as usize],
+            0xFF6A if self.cgb => self.ocps | 0x40,
+            0xFF6B if self.cgb => self.obj_palettes[(self.ocps & PALETTE_INDEX) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) -> u8 {
+        match addr {
+            0xFF40 => {
+                let was_enabled = self.enabled();
+                self.lcdc = val;
+                if was_enabled && !self.enabled() {
+                    self.ly = 0;
+                    self.dot = 0;
+                    self.window_line = 0;
+                    self.mode = PpuMode::HBlank;
+                    for pixel in self.framebuffer.iter_mut() {
+                        *pixel = 0;
+                    }
+                } else if !was_enabled && self.enabled() {
+                    self.mode = PpuMode::OamScan;
+                }
+            }
+            0xFF41 => self.stat = val & STAT_WRITABLE,
+            0xFF42 => self.scy = val,
+            0xFF43 => self.scx = val,
+            0xFF44 => (), // LY is read only
+            0xFF45 => self.lyc = val,
+            0xFF47 => self.bgp = val,
+            0xFF48 => self.obp0 = val,
+            0xFF49 => self.obp1 = val,
+            0xFF4A => self.wy = val,
+            0xFF4B => self.wx = val,
+            0xFF4F if self.cgb => self.vram_bank = (val & 0x01) as usize,
+            0xFF68 if self.cgb => self.bcps = val & 0xBF,
+            0xFF69 if self.cgb => Ppu::write_palette(&mut self.bg_palettes, &mut self.bcps, val),
+            0xFF6A if self.cgb => self.ocps = val & 0xBF,
+            0xFF6B if self.cgb => Ppu::write_palette(&mut self.obj_palettes, &mut self.ocps, val),
+            _ => (),
+        }
+
+        if self.enabled() {
+            self.update_stat_line()
+        } else {
+            0
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.put_bytes(&self.vram);
+        state.put_u64(self.vram_bank as u64);
+        state.put_bytes(&self.oam);
+        for &reg in [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0,
+            self.obp1, self.wy, self.wx,
+        ]
+        .iter()
+        {
+            state.put_u8(reg);
+        }
+
+        state.put_u8(self.mode as u8);
+        state.put_u64(self.dot);
+        state.put_u8(self.window_line);
+        state.put_bool(self.stat_line);
+        state.put_u64(self.frames);
+        state.put_bool(self.hblank_started);
+
+        state.put_u8(self.bcps);
+        state.put_u8(self.ocps);
+        state.put_bytes(&self.bg_palettes);
+        state.put_bytes(&self.obj_palettes);
+
+        state.put_bytes(&self.framebuffer);
+        state.put_words(&self.rgb_framebuffer);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmuError> {
+        state.get_bytes_into(&mut self.vram)?;
+        self.vram_bank = state.get_index(self.vram.len() / VRAM_BANK_SIZE)?;
+        state.get_bytes_into(&mut self.oam)?;
+        for reg in [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+        ] {
+            *reg = state.get_u8()?;
+        }
+
+        self.mode = match state.get_u8()? {
+            0 => PpuMode::HBlank,
+            1 => PpuMode::VBlank,
+            2 => PpuMode::OamScan,
+            3 => PpuMode::Drawing,
+            mode => return Err(EmuError::StateLoad(format!("bad PPU mode {}", mode))),
+        };
+        self.dot = state.get_u64()?;
+        self.window_line = state.get_u8()?;
+        self.stat_line = state.get_bool()?;
+        self.frames = state.get_u64()?;
+        self.hblank_started = state.get_bool()?;
+
+        self.bcps = state.get_u8()?;
+        self.ocps = state.get_u8()?;
+        state.get_bytes_into(&mut self.bg_palettes)?;
+        state.get_bytes_into(&mut self.obj_palettes)?;
+
+        state.get_bytes_into(&mut self.framebuffer)?;
+        state.get_words_into(&mut self.rgb_framebuffer)
+    }
+}
+
+// 0xRRGGBB down to the CGB's five bits a channel
+pub(crate) const fn rgb555(rgb: u32) -> u16 {
+    (((rgb >> 19) & 0x1F) | ((rgb >> 6) & 0x3E0) | ((rgb << 7) & 0x7C00)) as u16
+}
+
+/// Expands an RGB555 colour, red in the low bits, to 8 bits a channel.
+pub fn rgb888(colour: u16) -> [u8; 3] {
+    let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+    [
+        expand(colour & 0x1F),
+        expand((colour >> 5) & 0x1F),
+        expand((colour >> 10) & 0x1F),
+    ]
+}
+
+// The colours the CGB boot ROM gives a DMG cart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompatPalettes {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+// The boot ROM's palettes in RGB555, red in the low bits.
+const COMPAT_COLOURS: [[u16; 4]; 30] = [
+    [0x7FFF, 0x32BF, 0x00D0, 0x0000],
+    [0x639F, 0x4279, 0x15B0, 0x04CB],
+    [0x7FFF, 0x6E31, 0x454A, 0x0000],
+    [0x7FFF, 0x1BEF, 0x0200, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+    [0x7FFF, 0x5294, 0x294A, 0x0000],
+    [0x7FFF, 0x03FF, 0x012F, 0x0000],
+    [0x7FFF, 0x03EF, 0x01D6, 0x0000],
+    [0x7FFF, 0x42B5, 0x3DC8, 0x0000],
+    [0x7E74, 0x03FF, 0x0180, 0x0000],
+    [0x67FF, 0x77AC, 0x1A13, 0x2D6B],
+    [0x7ED6, 0x4BFF, 0x2175, 0x0000],
+    [0x53FF, 0x4A5F, 0x7E52, 0x0000],
+    [0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0],
+    [0x03ED, 0x7FFF, 0x255F, 0x0000],
+    [0x036A, 0x021F, 0x03FF, 0x7FFF],
+    [0x7FFF, 0x01DF, 0x0112, 0x0000],
+    [0x231F, 0x035F, 0x00F2, 0x0009],
+    [0x7FFF, 0x03EA, 0x011F, 0x0000],
+    [0x299F, 0x001A, 0x000C, 0x0000],
+    [0x7FFF, 0x027F, 0x001F, 0x0000],
+    [0x7FFF, 0x03E0, 0x0206, 0x0120],
+    [0x7FFF, 0x7EEB, 0x001F, 0x7C00],
+    [0x7FFF, 0x3FFF, 0x7E00, 0x001F],
+    [0x7FFF, 0x03FF, 0x001F, 0x0000],
+    [0x03FF, 0x001F, 0x000C, 0x0000],
+    [0x7FFF, 0x033F, 0x0193, 0x0000],
+    [0x0000, 0x4200, 0x037F, 0x7FFF],
+    [0x7FFF, 0x7E8C, 0x7C00, 0x0000],
+    [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+];
+
+// Where OBJ0, OBJ1 and BG start in COMPAT_COLOURS, counted in colours: a few
+// start a colour early and run into the next palette. Holding a direction,
+// and maybe A or B, on the boot logo picks one of the marked combinations.
+const COMPAT_COMBOS: [[u8; 3]; 51] = [
+    [16, 16, 116], // Right + A, and the default
+    [72, 72, 72],  // Right
+    [80, 80, 80],
+    [96, 96, 96], // Down + A
+    [36, 36, 36],
+    [0, 0, 0],       // Up
+    [108, 108, 108], // Right + B
+    [20, 20, 20],    // Left + B
+    [48, 48, 48],    // Down
+    [104, 104, 104],
+    [64, 32, 32],
+    [16, 112, 112],
+    [16, 8, 8],
+    [12, 16, 16],
+    [16, 116, 116],
+    [112, 16, 112],
+    [8, 68, 8],
+    [64, 64, 32],
+    [16, 16, 28],
+    [16, 16, 72],
+    [16, 16, 80],
+    [76, 76, 36],
+    [15, 15, 44],
+    [68, 68, 8],
+    [16, 16, 8],
+    [16, 16, 12],
+    [112, 112, 0],
+    [12, 12, 0],
+    [0, 0, 4], // Up + B
+    [72, 88, 72],
+    [80, 88, 80],
+    [96, 88, 96],
+    [64, 88, 32],
+    [68, 16, 52],
+    [111, 0, 56],
+    [111, 16, 60],
+    [76, 91, 36],
+    [64, 112, 40],
+    [16, 92, 112],
+    [68, 88, 8],
+    [16, 0, 8], // Left + A
+    [16, 112, 12],
+    [112, 12, 0],
+    [12, 112, 16], // Up + A
+    [84, 112, 16],
+    [12, 112, 0],
+    [100, 12, 112],
+    [0, 112, 32],
+    [16, 12, 112], // Left
+    [112, 12, 24], // Down + B
+    [16, 112, 116],
+];
+
+// Title checksums and the combination each gets.
+const COMPAT_TITLES: [(u8, u8); 64] = [
+    (0x88, 4),  // ALLEY WAY
+    (0x16, 5),  // YAKUMAN
+    (0x36, 35), // BASEBALL
+    (0xD1, 34), // TENNIS
+    (0xDB, 3),  // TETRIS
+    (0xF2, 31), // QIX
+    (0x3C, 15), // DR.MARIO
+    (0x8C, 10), // RADARMISSION
+    (0x92, 5),  // F1RACE
+    (0x3D, 19), // YOSSY NO TAMAGO
+    (0x5C, 36),
+    (0x58, 7),  // X
+    (0xC9, 37), // MARIOLAND2
+    (0x3E, 30), // YOSSY NO COOKIE
+    (0x70, 44), // ZELDA
+    (0x1D, 21),
+    (0x59, 32),
+    (0x69, 31), // TETRIS FLASH
+    (0x19, 20), // DONKEY KONG
+    (0x35, 5),  // MARIO'S PICROSS
+    (0xA8, 33),
+    (0x14, 13), // POKEMON RED
+    (0xAA, 14), // POKEMON GREEN
+    (0x75, 5),  // PICROSS 2
+    (0x95, 29), // YOSSY NO PANEPON
+    (0x99, 5),  // KIRAKIRA KIDS
+    (0x34, 18), // GAMEBOY GALLERY
+    (0x6F, 9),  // POCKETCAMERA
+    (0x15, 3),
+    (0xFF, 2),  // BALLOON KID
+    (0x97, 26), // KINGOFTHEZOO
+    (0x4B, 25), // DMG FOOTBALL
+    (0x90, 25), // WORLD CUP
+    (0x17, 41), // OTHELLO
+    (0x10, 42), // SUPER RC PRO-AM
+    (0x39, 26), // DYNABLASTER
+    (0xF7, 45), // BOY AND BLOB GB2
+    (0xF6, 42), // MEGAMAN
+    (0xA2, 45), // STAR WARS-NOA
+    (0x49, 36),
+    (0x4E, 38), // WAVERACE
+    (0x43, 26),
+    (0x68, 42), // LOLO2
+    (0xE0, 30), // YOSHI'S COOKIE
+    (0x8B, 41), // MYSTIC QUEST
+    (0xF0, 34),
+    (0xCE, 34), // TOPRANKINGTENNIS
+    (0x0C, 5),  // MANSELL
+    (0x29, 42), // MEGAMAN3
+    (0xE8, 6),  // SPACE INVADERS
+    (0xB7, 5),  // GAME&WATCH
+    (0x86, 33), // DONKEYKONGLAND95
+    (0x9A, 25), // ASTEROIDS/MISCMD
+    (0x52, 42), // STREET FIGHTER 2
+    (0x01, 42), // DEFENDER/JOUST
+    (0x9D, 40), // KILLERINSTINCT95
+    (0x71, 2),  // TETRIS BLAST
+    (0x9C, 16), // PINOCCHIO
+    (0xBD, 25),
+    (0x5D, 42), // BA.TOSHINDEN
+    (0x6D, 42), // NETTOU KOF 95
+    (0x67, 5),
+    (0x3F, 0),  // TETRIS PLUS
+    (0x6B, 39), // DONKEYKONGLAND 3
+];
+
+// Checksums more than one game shares, told apart by the fourth letter of
+// the title.
+const COMPAT_LETTERS: [(u8, u8, u8); 29] = [
+    (0xB3, b'B', 36),
+    (0x46, b'E', 22), // SUPER MARIOLAND
+    (0x28, b'F', 25), // GOLF
+    (0xA5, b'A', 6),  // SOLARSTRIKER
+    (0xC6, b'A', 32), // GBWARS
+    (0xD3, b'R', 12), // KAERUNOTAMENI
+    (0x27, b'B', 36),
+    (0x61, b'E', 11), // POKEMON BLUE
+    (0x18, b'K', 39), // DONKEYKONGLAND
+    (0x66, b'E', 18), // GAMEBOY GALLERY2
+    (0x6A, b'K', 39), // DONKEYKONGLAND 2
+    (0xBF, b' ', 24), // KID ICARUS
+    (0x0D, b'R', 31), // TETRIS2
+    (0xF4, b'-', 50),
+    (0xB3, b'U', 17), // MOGURANYA
+    (0x46, b'R', 46),
+    (0x28, b'A', 6),  // GALAGA&GALAXIAN
+    (0xA5, b'R', 27), // BT2RAGNAROKWORLD
+    (0xC6, b' ', 0),  // KEN GRIFFEY JR
+    (0xD3, b'I', 47),
+    (0x27, b'N', 41), // MAGNETIC SOCCER
+    (0x61, b'A', 41), // VEGAS STAKES
+    (0x18, b'I', 0),
+    (0x66, b'L', 0),  // MILLI/CENTI/PEDE
+    (0x6A, b'I', 34), // MARIO & YOSHI
+    (0xBF, b'C', 23), // SOCCER
+    (0x0D, b'E', 18), // POKEBOM
+    (0xF4, b' ', 29), // G&W GALLERY
+    (0xB3, b'R', 28), // TETRIS ATTACK
+];
+
+impl CompatPalettes {
+    fn combo(combo: u8) -> CompatPalettes {
+        let palette = |start: u8| {
+            let mut colours = [0; 4];
+            for (i, colour) in colours.iter_mut().enumerate() {
+                let at = start as usize + i;
+                *colour = COMPAT_COLOURS[at / 4][at % 4];
+            }
+            colours
+        };
+        let [obj0, obj1, bg] = COMPAT_COMBOS[combo as usize];
+        CompatPalettes {
+            bg: palette(bg),
+            obj0: palette(obj0),
+            obj1: palette(obj1),
+        }
+    }
+}
+
+// Picks palettes for a DMG cart from its header the way the boot ROM does.
+// Only Nintendo's own games are looked up, everything else gets the default.
+pub fn compat_palettes(rom: &[u8]) -> CompatPalettes {
+    let old_licensee = rom.get(0x014B).cloned();
+    let nintendo = old_licensee == Some(0x01)
+        || (old_licensee == Some(0x33) && rom.get(0x0144..0x0146) == Some(&b"01"[..]));
+    if !nintendo {
+        return CompatPalettes::combo(0);
+    }
+
+    let checksum = rom
+        .get(0x0134..0x0144)
+        .unwrap_or_default()
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b));
+    let fourth = rom.get(0x0137).cloned();
+    let combo = COMPAT_TITLES
+        .iter()
+        .find(|(sum, _)| *sum == checksum)
+        .map(|(_, combo)| *combo)
+        .or_else(|| {
+            COMPAT_LETTERS
+                .iter()
+                .find(|(sum, letter, _)| *sum == checksum && Some(*letter) == fourth)
+                .map(|(_, _, combo)| *combo)
+        });
+    CompatPalettes::combo(combo.unwrap_or(0))
+}
+
+#[test]
+fn ppu_frame_timing_test() {
+    let mut ppu = Ppu::new(false);
+    let mut interrupts = 0;
+
+    interrupts |= ppu.step(DOTS_PER_LINE * VBLANK_START as u64 - 1);
+    assert!(interrupts & VBLANK_IF == 0);
+    assert!(ppu.read(0xFF44) == VBLANK_START - 1);
+
+    interrupts |= ppu.step(1);
+    assert!(interrupts & VBLANK_IF != 0);
+    assert!(ppu.read(0xFF41) & 0b11 == PpuMode::VBlank as u8);
+    assert!(ppu.frame_count() == 1);
+
+    ppu.step(DOTS_PER_LINE * (LINES_PER_FRAME - VBLANK_START) as u64);
+    assert!(ppu.read(0xFF44) == 0);
+    assert!(ppu.read(0xFF41) & 0b11 == PpuMode::OamScan as u8);
+}
+
+#[test]
+fn ppu_background_test() {
+    let mut ppu = Ppu::new(false);
+    // tile 1: top row is color 3 on the left half, color 1 on the right
+    ppu.write_vram(0x8010, 0xFF);
+    ppu.write_vram(0x8011, 0xF0);
+    ppu.write_vram(0x9800, 0x01);
+    ppu.write(0xFF47, 0b11_10_01_00);
+
+    ppu.step(DOTS_PER_LINE);
+    assert!(ppu.framebuffer()[0..8] == [3, 3, 3, 3, 1, 1, 1, 1]);
+    assert!(ppu.framebuffer()[8] == 0);
+    assert!(ppu.rgb_framebuffer()[0] == 0x0000);
+    assert!(ppu.rgb_framebuffer()[8] == 0x7FFF);
+}
+
+#[test]
+fn ppu_cgb_color_test() {
+    let mut ppu = Ppu::new(true);
+    // palette 2, colors 1 and 3, through the auto-incrementing index
+    ppu.write(0xFF68, PALETTE_AUTO_INCREMENT | 0x12);
+    for b in [0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C].iter() {
+        ppu.write(0xFF69, *b);
+    }
+    assert!(ppu.read(0xFF68) == 0xC0 | 0x18);
+    ppu.write(0xFF68, 0x12);
+    assert!(ppu.read(0xFF69) == 0x1F);
+
+    // tile 1 in bank 1: color 1 on the left half, color 3 on the right
+    ppu.write(0xFF4F, 0x01);
+    ppu.write_vram(0x8010, 0xFF);
+    ppu.write_vram(0x8011, 0x0F);
+    // attributes: palette 2, bank 1, flipped horizontally
+    ppu.write_vram(0x9800, 0x02 | BG_BANK | BG_X_FLIP);
+    ppu.write(0xFF4F, 0x00);
+    ppu.write_vram(0x9800, 0x01);
+
+    ppu.step(DOTS_PER_LINE);
+    assert!(ppu.framebuffer()[0..8] == [3, 3, 3, 3, 1, 1, 1, 1]);
+    assert!(ppu.rgb_framebuffer()[0] == 0x7C00);
+    assert!(ppu.rgb_framebuffer()[4] == 0x001F);
+}
+
+#[test]
+fn compat_palettes_test() {
+    let with_title = |licensee: u8, title: &[u8]| {
+        let mut rom = vec![0u8; 0x150];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        rom[0x014B] = licensee;
+        compat_palettes(&rom)
+    };
+    let default = CompatPalettes::combo(0);
+    assert!(with_title(0x00, b"POKEMON RED") == default);
+    assert!(rgb888(default.bg[2]) == rgb888(rgb555(0x0063C5)));
+
+    let red = with_title(0x01, b"POKEMON RED");
+    assert!(red.bg == [0x7FFF, 0x421F, 0x1CF2, 0x0000]);
+    assert!(red.obj0 == [0x7FFF, 0x1BEF, 0x0200, 0x0000]);
+    assert!(rgb555(0xFF8484) == 0x421F);
+
+    // MOGURANYA and TETRIS ATTACK both sum to 0xB3
+    let moguranya = with_title(0x01, b"MOGURANYA");
+    let tetris_attack = with_title(0x01, b"TETRIS ATTACK");
+    assert!(moguranya == CompatPalettes::combo(17));
+    assert!(tetris_attack == CompatPalettes::combo(28));
+    assert!(moguranya != tetris_attack);
+    assert!(with_title(0x01, b"MOUGRANYA") == default);
+
+    // Mario's sprites start a colour early, on the black of the palette before
+    let mario = with_title(0x01, b"SUPER MARIOLAND");
+    assert!(mario.obj0 == [0x0000, 0x7FFF, 0x421F, 0x1CF2]);
+    assert!(mario.bg == COMPAT_COLOURS[11]);
+}
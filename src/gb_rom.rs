@@ -1,16 +1,20 @@
-use std::cell::RefCell;
+use std::fmt;
 use std::fs;
 use std::io::prelude::*;
-use std::path::PathBuf;
-use std::str;
-use std::vec::Vec;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
 use std::result::Result;
+use std::vec::Vec;
 
+use flate2::read::GzDecoder;
 use num::FromPrimitive;
+use serde_json::Value;
+use zip::ZipArchive;
 
-use gb_mem::RamAddress;
+use gb_error::EmuError;
+use patch;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum CgbFlag {
     None,
     Supported,
@@ -18,20 +22,17 @@ enum CgbFlag {
 }
 
 impl CgbFlag {
-    fn from_u8(val: u8) -> Result<Self, String> {
-        Ok(match val {
+    fn from_u8(val: u8) -> Self {
+        match val {
             0x80 => CgbFlag::Supported,
             0xC0 => CgbFlag::Exclusive,
-            0x00 => CgbFlag::None,
-            f @ _ => {
-                println!("Unexpected CGB support flag value: {}", f);
-                CgbFlag::None
-            }
-        })
+            _ => CgbFlag::None,
+        }
     }
 }
 
 #[allow(non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 enum NewLicenseCode {
     None,
@@ -167,8 +168,9 @@ impl NewLicenseCode {
 }
 
 enum_from_primitive! {
-#[derive(Debug)]
-enum CartType {
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CartType {
     ROM_ONLY = 0x00,
     MBC1 = 0x01,
     MBC1_RAM = 0x02,
@@ -201,7 +203,9 @@ enum CartType {
 }
 
 enum_from_primitive! {
-#[derive(Debug)]
+#[allow(non_camel_case_types)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy)]
 enum RomSize {
     RS_32KByte = 0x00,  // (no ROM banking)
     RS_64KByte = 0x01,  // (4 banks)
@@ -218,8 +222,9 @@ enum RomSize {
 }}
 
 enum_from_primitive! {
-#[derive(Debug)]
-enum CartRamSize {
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CartRamSize {
     CR_None = 0x00,
     CR_2KB = 0x01,
     CR_8KB = 0x02,
@@ -228,6 +233,31 @@ enum CartRamSize {
     CR_64KB = 0x05,  // (8 banks of 8KBytes each)
 }}
 
+impl RomSize {
+    fn bytes(&self) -> usize {
+        let banks = match *self {
+            RomSize::RS_1_1MByte => 72,
+            RomSize::RS_1_2MByte => 80,
+            RomSize::RS_1_5MByte => 96,
+            size => 2 << size as usize,
+        };
+        banks * 0x4000
+    }
+}
+
+impl CartRamSize {
+    pub fn bytes(&self) -> usize {
+        match *self {
+            CartRamSize::CR_None => 0,
+            CartRamSize::CR_2KB => 0x800,
+            CartRamSize::CR_8KB => 0x2000,
+            CartRamSize::CR_32KB => 0x8000,
+            CartRamSize::CR_128KB => 0x20000,
+            CartRamSize::CR_64KB => 0x10000,
+        }
+    }
+}
+
 #[derive(Debug)]
 enum DestinationCode {
     Japan = 0x00,
@@ -388,9 +418,194 @@ enum OldLicenseCode {
     ljn3 = 0xFF,
 }}
 
+// The boot ROM refuses to start a cartridge unless this is at 0x0104
+pub(crate) const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// What to do with a ROM that fails header validation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValidationPolicy {
+    /// Refuse to load it.
+    Reject,
+    /// Load it, handing back each problem to be reported.
+    Warn,
+    /// Load it quietly.
+    Accept,
+}
+
+/// The result of checking a ROM's header against its contents.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationReport {
+    pub logo_valid: bool,
+    pub header_checksum: u8,
+    pub computed_header_checksum: u8,
+    pub global_checksum: u16,
+    pub computed_global_checksum: u16,
+    pub expected_size: usize,
+    pub actual_size: usize,
+}
+
+impl ValidationReport {
+    fn new(buf: &[u8], rom_size: RomSize) -> Self {
+        // the same sum the boot ROM does before it will start the cartridge
+        let computed_header_checksum = buf[0x0134..0x014D]
+            .iter()
+            .fold(0u8, |x, b| x.wrapping_sub(*b).wrapping_sub(1));
+
+        // nothing checks this one, it's the sum of every byte except itself
+        let computed_global_checksum = buf
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != 0x014E && i != 0x014F)
+            .fold(0u16, |sum, (_, b)| sum.wrapping_add(*b as u16));
+
+        ValidationReport {
+            logo_valid: buf[0x0104..0x0134] == NINTENDO_LOGO[..],
+            header_checksum: buf[0x014D],
+            computed_header_checksum,
+            global_checksum: (buf[0x014E] as u16) << 8 | buf[0x014F] as u16,
+            computed_global_checksum,
+            expected_size: rom_size.bytes(),
+            actual_size: buf.len(),
+        }
+    }
+
+    pub fn header_checksum_valid(&self) -> bool {
+        self.header_checksum == self.computed_header_checksum
+    }
+
+    pub fn global_checksum_valid(&self) -> bool {
+        self.global_checksum == self.computed_global_checksum
+    }
+
+    pub fn size_valid(&self) -> bool {
+        self.expected_size == self.actual_size
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.problems().is_empty()
+    }
+
+    /// Each failed check, with the header offset it concerns.
+    pub fn problems(&self) -> Vec<(usize, String)> {
+        let mut problems = Vec::new();
+        if !self.logo_valid {
+            problems.push((0x0104, String::from("Nintendo logo doesn't match")));
+        }
+        if !self.header_checksum_valid() {
+            problems.push((
+                0x014D,
+                format!(
+                    "header checksum is {:#04X}, should be {:#04X}",
+                    self.header_checksum, self.computed_header_checksum
+                ),
+            ));
+        }
+        if !self.global_checksum_valid() {
+            problems.push((
+                0x014E,
+                format!(
+                    "global checksum is {:#06X}, should be {:#06X}",
+                    self.global_checksum, self.computed_global_checksum
+                ),
+            ));
+        }
+        if !self.size_valid() {
+            problems.push((
+                0x0148,
+                format!(
+                    "header says {} bytes but the file has {}",
+                    self.expected_size, self.actual_size
+                ),
+            ));
+        }
+        problems
+    }
+}
+
+// Everything up to and including the global checksum
+const HEADER_END: usize = 0x0150;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZIP_MAGIC: [u8; 4] = [b'P', b'K', 0x03, 0x04];
+
+// The biggest cart there is. Stops a small archive unpacking to gigabytes.
+pub(crate) const MAX_UNPACKED: u64 = 8 * 1024 * 1024;
+
+fn has_logo(buf: &[u8]) -> bool {
+    buf.len() >= 0x0134 && buf[0x0104..0x0134] == NINTENDO_LOGO[..]
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, EmuError> {
+    fs::read(path).map_err(|err| EmuError::Io(format!("{}: {}", path.display(), err)))
+}
+
+// Pulls the ROM out of a gzip or zip file. Anything else is assumed to
+// already be a ROM.
+fn unpack(buf: Vec<u8>) -> Result<Vec<u8>, EmuError> {
+    // a ROM can start with anything, so trust the logo over the magic
+    if has_logo(&buf) {
+        return Ok(buf);
+    }
+
+    let archive_error = |err: &dyn fmt::Display| EmuError::RomParse {
+        offset: 0,
+        reason: format!("unpacking archive: {}", err),
+    };
+
+    let read_rom = |reader: &mut dyn Read| {
+        let mut rom = Vec::new();
+        reader
+            .take(MAX_UNPACKED + 1)
+            .read_to_end(&mut rom)
+            .map_err(|err| archive_error(&err))?;
+        if rom.len() as u64 > MAX_UNPACKED {
+            return Err(archive_error(&format!(
+                "unpacks to over {} MiB",
+                MAX_UNPACKED / 1024 / 1024
+            )));
+        }
+        Ok(rom)
+    };
+
+    if buf.starts_with(&GZIP_MAGIC) {
+        read_rom(&mut GzDecoder::new(&buf[..]))
+    } else if buf.starts_with(&ZIP_MAGIC) {
+        let mut archive = ZipArchive::new(Cursor::new(buf)).map_err(|err| archive_error(&err))?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).map_err(|err| archive_error(&err))?;
+            let name = entry.name().to_lowercase();
+            if name.ends_with(".gb") || name.ends_with(".gbc") {
+                return read_rom(&mut entry);
+            }
+        }
+        Err(archive_error(&"no .gb or .gbc file in zip"))
+    } else {
+        Ok(buf)
+    }
+}
+
+// Later carts took the last four title bytes for a manufacturer code. There's
+// no flag for it, but they're all CGB carts and the code is always upper case.
+fn has_mfg_code(buf: &[u8]) -> bool {
+    buf[0x0143] & 0x80 != 0
+        && buf[0x013F..0x0143]
+            .iter()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+}
+
+// Header text is zero padded and not always valid ASCII
+fn header_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
 #[derive(Debug)]
 pub struct GbRom {
-    data: RefCell<Vec<u8>>,
+    data: Vec<u8>,
     title: String,
     mfg_code: String,
     color_support: CgbFlag,
@@ -403,127 +618,184 @@ pub struct GbRom {
     old_license_code: OldLicenseCode,
     mask_rom_version: u8,
     complement_checksum: u8,
-    checksum: [u8; 2],
+    validation: ValidationReport,
+    patch: Option<PathBuf>,
 }
 
 impl GbRom {
-    pub fn new(path: PathBuf) -> Result<Self, String> {
-        let mut romfile = match fs::File::open(&path) {
-            Ok(r) => r,
-            Err(err) => {
-                println!("Error opening file: {}", err);
-                panic!();;
-            }
+    /// Loads the ROM at `path`. The file may also be gzipped, or a zip
+    /// archive holding the ROM. An IPS, BPS or UPS patch with the same name
+    /// as the ROM, like `game.ips` next to `game.gb`, is applied to it.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, EmuError> {
+        GbRom::with_patch(path, None)
+    }
+
+    /// Loads the ROM at `path` and applies the patch at `patch`, or the one
+    /// next to the ROM if none is given.
+    pub fn with_patch<P: AsRef<Path>>(path: P, patch: Option<&Path>) -> Result<Self, EmuError> {
+        let path = path.as_ref();
+        let buf = unpack(read_file(path)?)?;
+
+        let patch = match patch {
+            Some(p) => Some(p.to_path_buf()),
+            None => patch::EXTENSIONS
+                .iter()
+                .map(|ext| path.with_extension(ext))
+                .find(|p| p.is_file()),
         };
+        let buf = match patch {
+            Some(ref p) => patch::apply(&buf, &read_file(p)?)?,
+            None => buf,
+        };
+
+        let mut rom = GbRom::parse(buf)?;
+        rom.patch = patch;
+        Ok(rom)
+    }
 
+    /// Reads a whole ROM, or an archive holding one, from `reader`.
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self, EmuError> {
         let mut buf = Vec::new();
-        let size = match romfile.read_to_end(&mut buf) {
-            Ok(s) => s,
-            Err(err) => {
-                println!("Error reading bytes from rom file: {}", err);
-                panic!();;
+        if let Err(err) = reader.read_to_end(&mut buf) {
+            return Err(EmuError::Io(format!("reading rom: {}", err)));
+        }
+        GbRom::from_bytes(buf)
+    }
+
+    /// Parses a ROM image, or an archive holding one.
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self, EmuError> {
+        GbRom::parse(unpack(buf)?)
+    }
+
+    fn parse(buf: Vec<u8>) -> Result<Self, EmuError> {
+        if buf.len() < HEADER_END {
+            return Err(EmuError::RomParse {
+                offset: buf.len(),
+                reason: format!("rom is {} bytes, too short to hold a header", buf.len()),
+            });
+        }
+
+        let rom_size = {
+            let code = buf[0x0148];
+            match RomSize::from_u8(code) {
+                Some(val) => val,
+                None => {
+                    return Err(EmuError::RomParse {
+                        offset: 0x0148,
+                        reason: format!("unrecognised rom size: {}", code),
+                    })
+                }
             }
         };
 
-        println!("Read {} bytes", size);
-
         let rom = GbRom {
-            title: match str::from_utf8(&buf[0x0134..0x0143]) {
-                Ok(s) => String::from(s),
-                Err(err) => return Err(format!("ERROR: reading rom title: {}", err)),
-            },
-            mfg_code: match str::from_utf8(&buf[0x013F..0x0143]) {
-                Ok(s) => String::from(s),
-                Err(err) => return Err(format!("ERROR: reading manufacturer code: {}", err)),
-            },
-            color_support: match CgbFlag::from_u8(buf[0x0143]) {
-                Ok(val) => val,
-                Err(e) => return Err(e),
-            },
-            new_license_code: match NewLicenseCode::decode(&buf[0x0144..0x0146]) {
-                Ok(val) => val,
-                Err(e) => {
-                    println!("{}", e);
-                    NewLicenseCode::None
-                }
-            },
-            sgb_compatible: match buf[0x0146] {
-                0x03 => true,
-                0x00 => false,
-                f @ _ => {
-                    println!("Unexpected SGB support flag valye: {}", f);
-                    false
-                }
+            title: header_string(if has_mfg_code(&buf) {
+                &buf[0x0134..0x013F]
+            } else {
+                &buf[0x0134..0x0143]
+            }),
+            mfg_code: header_string(&buf[0x013F..0x0143]),
+            color_support: CgbFlag::from_u8(buf[0x0143]),
+            new_license_code: {
+                // stored as two ASCII characters
+                let code: Vec<u8> = buf[0x0144..0x0146]
+                    .iter()
+                    .map(|c| (*c as char).to_digit(16).unwrap_or(0xFF) as u8)
+                    .collect();
+                NewLicenseCode::decode(&code).unwrap_or(NewLicenseCode::None)
             },
+            sgb_compatible: buf[0x0146] == 0x03,
             cart_type: {
                 let code = buf[0x0147];
                 match CartType::from_u8(code) {
                     Some(val) => val,
-                    None => return Err(format!("ERROR: Unrecognised cart type: {}", code)),
-                }
-            },
-            rom_size: {
-                let code = buf[0x0148];
-                match RomSize::from_u8(code) {
-                    Some(val) => val,
-                    None => return Err(format!("ERROR: Unregocnised rom size: {}", code)),
+                    None => {
+                        return Err(EmuError::RomParse {
+                            offset: 0x0147,
+                            reason: format!("unrecognised cart type: {}", code),
+                        })
+                    }
                 }
             },
             ram_size: {
                 let code = buf[0x0149];
                 match CartRamSize::from_u8(code) {
                     Some(val) => val,
-                    None => return Err(format!("ERROR: Unregocnised cart ram size: {}", code)),
+                    None => {
+                        return Err(EmuError::RomParse {
+                            offset: 0x0149,
+                            reason: format!("unrecognised cart ram size: {}", code),
+                        })
+                    }
                 }
             },
             dest_code: match buf[0x014A] {
                 0x00 => DestinationCode::Japan,
                 0x01 => DestinationCode::NonJapan,
-                f @ _ => {
-                    println!("ERROR: Unrecognised destination code: {}", f);
-                    DestinationCode::Unknown
-                }
-            },
-            old_license_code: {
-                let code = buf[0x014B];
-                match OldLicenseCode::from_u8(code) {
-                    Some(val) => val,
-                    None => {
-                        println!("WARNING: Unregocnised old license code: {}", code);
-                        OldLicenseCode::none
-                    }
-                }
+                _ => DestinationCode::Unknown,
             },
+            old_license_code: OldLicenseCode::from_u8(buf[0x014B]).unwrap_or(OldLicenseCode::none),
             mask_rom_version: buf[0x014C],
             complement_checksum: buf[0x014D],
-            checksum: [buf[0x014E], buf[0x014F]],
-            data: RefCell::new(buf), // put last to avoid getting data after moving
+            validation: ValidationReport::new(&buf, rom_size),
+            patch: None,
+            rom_size,
+            data: buf, // put last to avoid getting data after moving
         };
 
-        // TODO: Do the checksum and offer to reject the ROM if it seems too bad
-
-        rom.print_info();
         Ok(rom)
     }
 
+    /// Applies `policy` to the header validation results. Returns the
+    /// problems to warn about, which is none unless the policy is `Warn`.
+    pub fn validate(&self, policy: ValidationPolicy) -> Result<Vec<String>, EmuError> {
+        let problems = self.validation.problems();
+        match policy {
+            ValidationPolicy::Accept => Ok(Vec::new()),
+            ValidationPolicy::Warn => Ok(problems.into_iter().map(|(_, p)| p).collect()),
+            ValidationPolicy::Reject => match problems.into_iter().next() {
+                Some((offset, reason)) => Err(EmuError::RomParse { offset, reason }),
+                None => Ok(Vec::new()),
+            },
+        }
+    }
+
+    pub fn validation(&self) -> &ValidationReport {
+        &self.validation
+    }
+
+    /// The patch applied to this ROM when it was loaded.
+    pub fn patch(&self) -> Option<&Path> {
+        self.patch.as_deref()
+    }
+
+    // Newer carts keep the licensee in two ASCII characters instead
+    fn licensee(&self) -> String {
+        match self.old_license_code {
+            OldLicenseCode::GBC_use_new => format!("{:?}", self.new_license_code),
+            ref code => format!("{:?}", code),
+        }
+    }
+
     pub fn print_info(&self) {
         let w = 26;
+        let ok = |valid: bool| if valid { "ok" } else { "BAD" };
+        let v = &self.validation;
         println!("=== ROM Info ===============");
         println!("+ {:2$}: {}", "Loaded rom", self.title, w);
         println!("+ {:2$}: {}", "Version", self.mask_rom_version, w);
-        println!("+ {:2$}: {}", "Size", self.data.borrow().len(), w);
+        println!("+ {:2$}: {}", "Size", self.data.len(), w);
         println!("+ {:2$}: {}", "Mfg code", self.mfg_code, w);
-        println!(
-            "+ {:2$}: {:?}",
-            "Old license code", self.old_license_code, w
-        );
-        println!(
-            "+ {:2$}: {:?}",
-            "New license code", self.new_license_code, w
-        );
+        println!("+ {:2$}: {}", "Licensee", self.licensee(), w);
         println!("+ {:2$}: {:?}", "Region", self.dest_code, w);
         println!("+ {:2$}: {:?}", "Cart type", self.cart_type, w);
-        println!("+ {:2$}: {:?}", "ROM size", self.rom_size, w);
+        println!(
+            "+ {:3$}: {:?} ({})",
+            "ROM size",
+            self.rom_size,
+            ok(v.size_valid()),
+            w
+        );
         println!("+ {:2$}: {:?}", "RAM size", self.ram_size, w);
         println!(
             "+ {:2$}: {}",
@@ -532,21 +804,140 @@ impl GbRom {
             w
         );
         println!("+ {:2$}: {:?}", "Color support", self.color_support, w);
+        println!("+ {:2$}: {}", "Nintendo logo", ok(v.logo_valid), w);
         println!(
-            "+ {:2$}: {}",
-            "Complement checksum", self.complement_checksum, w
+            "+ {:3$}: {:#04X} ({})",
+            "Header checksum",
+            self.complement_checksum,
+            ok(v.header_checksum_valid()),
+            w
         );
         println!(
-            "+ {:2$}: {:?}",
-            "Encoded (whole) checksum", self.checksum, w
+            "+ {:3$}: {:#06X} ({})",
+            "Global checksum",
+            v.global_checksum,
+            ok(v.global_checksum_valid()),
+            w
         );
     }
 
-    pub fn copy_current_slice(&self, dest: &mut [u8]) {
-        dest.copy_from_slice(&self.data.borrow()[0x0000..0x8000]);
+    /// The same details as `print_info`, as a JSON object.
+    pub fn info_json(&self) -> Value {
+        let v = &self.validation;
+        json!({
+            "title": self.title,
+            "version": self.mask_rom_version,
+            "size": self.data.len(),
+            "mfg_code": self.mfg_code,
+            "licensee": self.licensee(),
+            "region": format!("{:?}", self.dest_code),
+            "cart_type": format!("{:?}", self.cart_type),
+            "rom_size": v.expected_size,
+            "ram_size": self.ram_size.bytes(),
+            "sgb": self.sgb_compatible,
+            "cgb": format!("{:?}", self.color_support),
+            "logo_valid": v.logo_valid,
+            "header_checksum": v.header_checksum,
+            "header_checksum_valid": v.header_checksum_valid(),
+            "global_checksum": v.global_checksum,
+            "global_checksum_valid": v.global_checksum_valid(),
+            "size_valid": v.size_valid(),
+        })
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn cart_type(&self) -> CartType {
+        self.cart_type
     }
 
-    pub fn read_address(&self, addr: RamAddress) -> u8 {
-        self.data.borrow()[addr.get() as usize]
+    /// True if the header asks for CGB features, whether or not the game
+    /// also runs on a DMG.
+    pub fn is_cgb(&self) -> bool {
+        self.color_support != CgbFlag::None
+    }
+
+    pub fn ram_size(&self) -> CartRamSize {
+        self.ram_size
+    }
+}
+
+#[test]
+fn header_validation_test() {
+    let mut buf = vec![0u8; 0x8000];
+    buf[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+    buf[0x0134..0x0138].copy_from_slice(b"TEST");
+    buf[0x014D] = 0xA7;
+    buf[0x014E] = 0x17;
+    buf[0x014F] = 0x2D;
+
+    let rom = GbRom::from_bytes(buf.clone()).unwrap();
+    assert!(rom.validation().is_valid());
+    assert!(rom.validate(ValidationPolicy::Reject).is_ok());
+
+    buf[0x0134] = b'B';
+    buf.truncate(0x4000);
+    let rom = GbRom::from_bytes(buf).unwrap();
+    let report = rom.validation();
+    assert!(report.logo_valid);
+    assert!(!report.header_checksum_valid());
+    assert!(!report.global_checksum_valid());
+    assert!(!report.size_valid());
+    assert!(rom.validate(ValidationPolicy::Warn).unwrap().len() == 3);
+    assert!(rom.validate(ValidationPolicy::Accept).unwrap().is_empty());
+    match rom.validate(ValidationPolicy::Reject) {
+        Err(EmuError::RomParse { offset, .. }) => assert!(offset == 0x014D),
+        r => panic!("expected the header checksum to fail, got {:?}", r),
+    }
+}
+
+#[test]
+fn truncated_rom_test() {
+    match GbRom::from_bytes(vec![0u8; 0x0140]) {
+        Err(EmuError::RomParse { offset, .. }) => assert!(offset == 0x0140),
+        r => panic!("expected a short rom to fail, got {:?}", r),
+    }
+}
+
+#[test]
+fn archive_loading_test() {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    let mut buf = vec![0u8; 0x8000];
+    buf[0x0134..0x0138].copy_from_slice(b"GZIP");
+
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(&buf).unwrap();
+    let rom = GbRom::from_reader(&gz.finish().unwrap()[..]).unwrap();
+    assert!(rom.title() == "GZIP");
+    assert!(rom.data().len() == 0x8000);
+
+    buf[0x0134..0x0138].copy_from_slice(b"ZIP\xFF");
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file("readme.txt", SimpleFileOptions::default())
+        .unwrap();
+    zip.write_all(b"not a rom").unwrap();
+    zip.start_file("Game.GB", SimpleFileOptions::default())
+        .unwrap();
+    zip.write_all(&buf).unwrap();
+    let rom = GbRom::from_bytes(zip.finish().unwrap().into_inner()).unwrap();
+    assert!(rom.title() == "ZIP\u{FFFD}");
+
+    // anything bigger than a real cart is refused
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(&vec![0u8; MAX_UNPACKED as usize + 1]).unwrap();
+    match GbRom::from_reader(&gz.finish().unwrap()[..]) {
+        Err(EmuError::RomParse { reason, .. }) => assert!(reason.contains("8 MiB")),
+        _ => panic!("oversized archive loaded"),
     }
 }
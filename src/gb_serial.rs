@@ -0,0 +1,105 @@
+use gb_error::EmuError;
+use gb_mem::SERIAL_IO_COMPLETE_IF;
+use gb_state::{StateReader, StateWriter};
+
+const SC_START: u8 = 1 << 7;
+const SC_INTERNAL_CLOCK: u8 = 1;
+
+// 8 bits shifted out at 8192 Hz
+const TRANSFER_CYCLES: u64 = 8 * 512;
+
+// SB and SC (0xFF01-0xFF02). Nothing is ever plugged into the link port, so
+// every byte sent out is captured and the byte shifted in is 0xFF.
+#[derive(Debug)]
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    remaining: u64,
+    output: Vec<u8>,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            sb: 0,
+            sc: 0x7E,
+            remaining: 0,
+            output: Vec::new(),
+        }
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    pub fn step(&mut self, cycles: u64) -> u8 {
+        if self.remaining == 0 {
+            return 0;
+        }
+
+        if cycles < self.remaining {
+            self.remaining -= cycles;
+            return 0;
+        }
+
+        self.remaining = 0;
+        self.sb = 0xFF;
+        self.sc &= !SC_START;
+        SERIAL_IO_COMPLETE_IF
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.sb,
+            _ => self.sc | 0x7E,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF01 => self.sb = val,
+            _ => {
+                self.sc = val;
+                if val & SC_START != 0 {
+                    self.output.push(self.sb);
+                    // with an external clock and no partner the transfer
+                    // never finishes
+                    if val & SC_INTERNAL_CLOCK != 0 {
+                        self.remaining = TRANSFER_CYCLES;
+                    }
+                }
+            }
+        }
+    }
+
+    // The output log belongs to the host, so it isn't part of the state
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.put_u8(self.sb);
+        state.put_u8(self.sc);
+        state.put_u64(self.remaining);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmuError> {
+        self.sb = state.get_u8()?;
+        self.sc = state.get_u8()?;
+        self.remaining = state.get_u64()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn serial_transfer_test() {
+    let mut serial = Serial::new();
+    serial.write(0xFF01, 0x42);
+    serial.write(0xFF02, 0x81);
+
+    assert!(serial.output() == [0x42]);
+    assert!(serial.step(TRANSFER_CYCLES - 1) == 0);
+    assert!(serial.step(1) == SERIAL_IO_COMPLETE_IF);
+    assert!(serial.read(0xFF01) == 0xFF);
+    assert!(serial.read(0xFF02) == 0x7F);
+}
@@ -0,0 +1,578 @@
+use gb_error::EmuError;
+use gb_joypad::MAX_PLAYERS;
+use gb_ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use gb_state::{StateReader, StateWriter};
+
+/// Size of the SGB picture, border included.
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+
+// where the Game Boy screen sits inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+const MAX_PACKETS: usize = 7;
+
+// the palettes apply to 8x8 cells of the screen
+const CELLS_X: usize = SCREEN_WIDTH / 8;
+const CELLS_Y: usize = SCREEN_HEIGHT / 8;
+
+// VRAM transfers send 4 KiB as 256 tiles read off the screen
+const TRANSFER_SIZE: usize = 0x1000;
+const SYSTEM_PALETTES: usize = 512;
+const ATTR_FILES: usize = 45;
+const ATTR_FILE_SIZE: usize = CELLS_X * CELLS_Y / 4;
+
+const BORDER_TILES: usize = 256;
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_MAP_HEIGHT: usize = 28;
+const BORDER_PALETTE_BASE: usize = 4;
+
+// the SGB's own default, palette 1-A
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mask {
+    Off,
+    Freeze,
+    Black,
+    Color0,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transfer {
+    Palettes,
+    Tiles(usize),
+    Border,
+    Attributes,
+}
+
+// The Super Game Boy: the SNES watches P1 for command packets, colours the
+// Game Boy's picture with four palettes and draws a border around it.
+#[derive(Debug)]
+pub struct Sgb {
+    // packet being clocked in, one bit per P1 pulse
+    packets: [u8; PACKET_SIZE * MAX_PACKETS],
+    bit: usize,
+    received: usize,
+    receiving: bool,
+    last_p1: u8,
+
+    palettes: [[u16; 4]; 4],
+    attributes: [u8; CELLS_X * CELLS_Y],
+    system_palettes: Vec<u16>,
+    attr_files: Vec<u8>,
+    mask: Mask,
+    players: usize,
+    transfer: Option<Transfer>,
+
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    border_palettes: [[u16; 16]; 4],
+
+    screen: Vec<u16>,
+    output: Vec<u16>,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Sgb {
+            packets: [0u8; PACKET_SIZE * MAX_PACKETS],
+            bit: 0,
+            received: 0,
+            receiving: false,
+            last_p1: 0x30,
+
+            palettes: [DEFAULT_PALETTE; 4],
+            attributes: [0u8; CELLS_X * CELLS_Y],
+            system_palettes: vec![0u16; SYSTEM_PALETTES * 4],
+            attr_files: vec![0u8; ATTR_FILES * ATTR_FILE_SIZE],
+            mask: Mask::Off,
+            players: 1,
+            transfer: None,
+
+            border_tiles: vec![0u8; BORDER_TILES * BORDER_TILE_SIZE],
+            border_map: vec![0u16; BORDER_MAP_WIDTH * BORDER_MAP_HEIGHT],
+            border_palettes: [[0u16; 16]; 4],
+
+            screen: vec![0u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+            output: vec![0u16; SGB_WIDTH * SGB_HEIGHT],
+        }
+    }
+
+    // Controllers MLT_REQ asked for
+    pub fn players(&self) -> usize {
+        self.players
+    }
+
+    // The coloured 160x144 Game Boy picture
+    pub fn screen(&self) -> &[u16] {
+        &self.screen
+    }
+
+    // The full 256x224 picture with the border
+    pub fn output(&self) -> &[u16] {
+        &self.output
+    }
+
+    // Watches writes to P1. Both lines low starts a packet, then each pulse
+    // of P14 sends a 0 and each pulse of P15 a 1.
+    pub fn write_joypad(&mut self, val: u8) {
+        let lines = val & 0x30;
+        let pulse = self.last_p1 == 0x30;
+        self.last_p1 = lines;
+
+        match lines {
+            0x00 => {
+                self.receiving = true;
+                self.bit = 0;
+            }
+            0x10 | 0x20 if self.receiving && pulse => {
+                if self.bit == PACKET_BITS {
+                    // the stop bit
+                    self.receiving = false;
+                    self.packet_done();
+                    return;
+                }
+
+                let byte = self.received * PACKET_SIZE + self.bit / 8;
+                let mask = 1 << (self.bit % 8);
+                if lines == 0x10 {
+                    self.packets[byte] |= mask;
+                } else {
+                    self.packets[byte] &= !mask;
+                }
+                self.bit += 1;
+            }
+            _ => (),
+        }
+    }
+
+    fn packet_done(&mut self) {
+        self.received += 1;
+        let length = (self.packets[0] & 0x07).max(1) as usize;
+        if self.received >= length {
+            self.received = 0;
+            self.command();
+        }
+    }
+
+    fn command(&mut self) {
+        let data = self.packets;
+        match data[0] >> 3 {
+            0x00 => self.set_palette_pair(0, 1, &data[1..]),
+            0x01 => self.set_palette_pair(2, 3, &data[1..]),
+            0x02 => self.set_palette_pair(0, 3, &data[1..]),
+            0x03 => self.set_palette_pair(1, 2, &data[1..]),
+            0x04 => self.attr_blk(&data[1..]),
+            0x05 => self.attr_lin(&data[1..]),
+            0x06 => self.attr_div(data[1], data[2]),
+            0x07 => self.attr_chr(&data[1..]),
+            0x0A => self.pal_set(&data[1..]),
+            0x0B => self.transfer = Some(Transfer::Palettes),
+            0x11 => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                }
+            }
+            0x13 => self.transfer = Some(Transfer::Tiles((data[1] & 0x01) as usize)),
+            0x14 => self.transfer = Some(Transfer::Border),
+            0x15 => self.transfer = Some(Transfer::Attributes),
+            0x16 => self.attr_set(data[1]),
+            0x17 => {
+                self.mask = match data[1] & 0x03 {
+                    0 => Mask::Off,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                }
+            }
+            // sound, SNES code and the rest have nothing to show
+            _ => (),
+        }
+    }
+
+    // PAL01 and friends: a shared color 0, then three colors for each
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |i: usize| u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]) & 0x7FFF;
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn fill_cells(&mut self, palette: u8, inside: impl Fn(usize, usize) -> bool) {
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                if inside(x, y) {
+                    self.attributes[y * CELLS_X + x] = palette & 0x03;
+                }
+            }
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let sets = (data[0] as usize).min(18);
+        for set in data[1..].chunks(6).take(sets) {
+            let (control, palettes) = (set[0] & 0x07, set[1]);
+            let (x1, y1, x2, y2) = (
+                set[2] as usize,
+                set[3] as usize,
+                set[4] as usize,
+                set[5] as usize,
+            );
+            let inside = palettes & 0x03;
+            let outside = (palettes >> 4) & 0x03;
+            // with only one side given, the border line goes with it
+            let border = match control {
+                0x01 => inside,
+                0x04 => outside,
+                _ => (palettes >> 2) & 0x03,
+            };
+
+            let within = |x: usize, y: usize| x >= x1 && x <= x2 && y >= y1 && y <= y2;
+            let on_edge =
+                |x: usize, y: usize| within(x, y) && (x == x1 || x == x2 || y == y1 || y == y2);
+            if control & 0x01 != 0 {
+                self.fill_cells(inside, |x, y| within(x, y) && !on_edge(x, y));
+            }
+            if control & 0x02 != 0 || control == 0x01 || control == 0x04 {
+                self.fill_cells(border, on_edge);
+            }
+            if control & 0x04 != 0 {
+                self.fill_cells(outside, |x, y| !within(x, y));
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let sets = data[0] as usize;
+        for &line in data[1..].iter().take(sets) {
+            let n = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                self.fill_cells(palette, |_, y| y == n);
+            } else {
+                self.fill_cells(palette, |x, _| x == n);
+            }
+        }
+    }
+
+    fn attr_div(&mut self, control: u8, at: u8) {
+        let at = at as usize;
+        let after = control & 0x03;
+        let before = (control >> 2) & 0x03;
+        let line = (control >> 4) & 0x03;
+        let pos = move |x: usize, y: usize| if control & 0x40 != 0 { y } else { x };
+
+        self.fill_cells(before, |x, y| pos(x, y) < at);
+        self.fill_cells(line, |x, y| pos(x, y) == at);
+        self.fill_cells(after, |x, y| pos(x, y) > at);
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[0] as usize % CELLS_X, data[1] as usize % CELLS_Y);
+        let count = (u16::from_le_bytes([data[2], data[3]]) as usize).min(CELLS_X * CELLS_Y);
+        let vertical = data[4] & 0x01 != 0;
+
+        let bytes = &data[5..];
+        for i in 0..count.min(bytes.len() * 4) {
+            // four palettes a byte, first in the top bits
+            let palette = (bytes[i / 4] >> (6 - (i % 4) * 2)) & 0x03;
+            self.attributes[y * CELLS_X + x] = palette;
+
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x = (x + 1) % CELLS_X;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y = (y + 1) % CELLS_Y;
+                }
+            }
+        }
+    }
+
+    fn pal_set(&mut self, data: &[u8]) {
+        for (i, palette) in self.palettes.iter_mut().enumerate() {
+            let n = u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]) as usize % SYSTEM_PALETTES;
+            palette.copy_from_slice(&self.system_palettes[n * 4..n * 4 + 4]);
+        }
+        // color 0 of the first palette is shared by all of them
+        let shared = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = shared;
+        }
+
+        let flags = data[8];
+        if flags & 0x80 != 0 {
+            self.attr_set(flags & 0x3F);
+        }
+        if flags & 0x40 != 0 {
+            self.mask = Mask::Off;
+        }
+    }
+
+    fn attr_set(&mut self, flags: u8) {
+        let file = (flags & 0x3F) as usize % ATTR_FILES;
+        let start = file * ATTR_FILE_SIZE;
+        for i in 0..CELLS_X * CELLS_Y {
+            let byte = self.attr_files[start + i / 4];
+            self.attributes[i] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+        }
+        if flags & 0x40 != 0 {
+            self.mask = Mask::Off;
+        }
+    }
+
+    // Turns the shades on screen back into the 2bpp tile data that was
+    // drawn, the way the SNES sees a VRAM transfer.
+    fn read_transfer(shades: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; TRANSFER_SIZE];
+        for (tile, bytes) in data.chunks_mut(16).enumerate() {
+            let (tx, ty) = (tile % CELLS_X, tile / CELLS_X);
+            for row in 0..8 {
+                let y = ty * 8 + row;
+                if y >= SCREEN_HEIGHT {
+                    break;
+                }
+                for col in 0..8 {
+                    let shade = shades[y * SCREEN_WIDTH + tx * 8 + col];
+                    let bit = 7 - col;
+                    bytes[row * 2] |= (shade & 0x01) << bit;
+                    bytes[row * 2 + 1] |= ((shade >> 1) & 0x01) << bit;
+                }
+            }
+        }
+        data
+    }
+
+    fn finish_transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        let word = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        match transfer {
+            Transfer::Palettes => {
+                for (i, color) in self.system_palettes.iter_mut().enumerate() {
+                    *color = word(i * 2) & 0x7FFF;
+                }
+            }
+            Transfer::Tiles(half) => {
+                let start = half * TRANSFER_SIZE;
+                self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(data);
+            }
+            Transfer::Border => {
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = word(i * 2);
+                }
+                for (p, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (c, color) in palette.iter_mut().enumerate() {
+                        *color = word(0x800 + (p * 16 + c) * 2) & 0x7FFF;
+                    }
+                }
+            }
+            Transfer::Attributes => {
+                self.attr_files
+                    .copy_from_slice(&data[..ATTR_FILES * ATTR_FILE_SIZE]);
+            }
+        }
+    }
+
+    // Called once the Game Boy has drawn a frame, with its shades.
+    pub fn end_frame(&mut self, shades: &[u8]) {
+        if let Some(transfer) = self.transfer.take() {
+            let data = Sgb::read_transfer(shades);
+            self.finish_transfer(transfer, &data);
+        }
+
+        match self.mask {
+            Mask::Off => {
+                for (i, (color, shade)) in self.screen.iter_mut().zip(shades).enumerate() {
+                    let cell = (i / SCREEN_WIDTH / 8) * CELLS_X + (i % SCREEN_WIDTH) / 8;
+                    *color = self.palettes[self.attributes[cell] as usize][*shade as usize];
+                }
+            }
+            Mask::Freeze => (),
+            Mask::Black => self.screen.iter_mut().for_each(|c| *c = 0x0000),
+            Mask::Color0 => {
+                let color = self.palettes[0][0];
+                self.screen.iter_mut().for_each(|c| *c = color);
+            }
+        }
+
+        self.draw_output();
+    }
+
+    // color of a border pixel, or None where it's see-through
+    fn border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = self.border_map[(y / 8) * BORDER_MAP_WIDTH + x / 8];
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0x07) as usize;
+        let (mut col, mut row) = (x % 8, y % 8);
+        if entry & 0x4000 != 0 {
+            col = 7 - col;
+        }
+        if entry & 0x8000 != 0 {
+            row = 7 - row;
+        }
+
+        // SNES 4bpp: planes 0 and 1 interleaved by row, then planes 2 and 3
+        let data = &self.border_tiles[tile * BORDER_TILE_SIZE..(tile + 1) * BORDER_TILE_SIZE];
+        let bit = 7 - col;
+        let color = ((data[row * 2] >> bit) & 1)
+            | (((data[row * 2 + 1] >> bit) & 1) << 1)
+            | (((data[16 + row * 2] >> bit) & 1) << 2)
+            | (((data[16 + row * 2 + 1] >> bit) & 1) << 3);
+        if color == 0 || palette < BORDER_PALETTE_BASE {
+            return None;
+        }
+        Some(self.border_palettes[palette - BORDER_PALETTE_BASE][color as usize])
+    }
+
+    fn draw_output(&mut self) {
+        let backdrop = self.palettes[0][0];
+        for y in 0..SGB_HEIGHT {
+            for x in 0..SGB_WIDTH {
+                let on_screen = (SCREEN_X..SCREEN_X + SCREEN_WIDTH).contains(&x)
+                    && (SCREEN_Y..SCREEN_Y + SCREEN_HEIGHT).contains(&y);
+                let under = if on_screen {
+                    self.screen[(y - SCREEN_Y) * SCREEN_WIDTH + x - SCREEN_X]
+                } else {
+                    backdrop
+                };
+                self.output[y * SGB_WIDTH + x] = self.border_pixel(x, y).unwrap_or(under);
+            }
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.put_bytes(&self.packets);
+        state.put_u64(self.bit as u64);
+        state.put_u64(self.received as u64);
+        state.put_bool(self.receiving);
+        state.put_u8(self.last_p1);
+
+        for palette in self.palettes.iter() {
+            state.put_words(palette);
+        }
+        state.put_bytes(&self.attributes);
+        state.put_words(&self.system_palettes);
+        state.put_bytes(&self.attr_files);
+        state.put_u8(match self.mask {
+            Mask::Off => 0,
+            Mask::Freeze => 1,
+            Mask::Black => 2,
+            Mask::Color0 => 3,
+        });
+        state.put_u64(self.players as u64);
+        let (transfer, bank) = match self.transfer {
+            None => (0, 0),
+            Some(Transfer::Palettes) => (1, 0),
+            Some(Transfer::Tiles(bank)) => (2, bank),
+            Some(Transfer::Border) => (3, 0),
+            Some(Transfer::Attributes) => (4, 0),
+        };
+        state.put_u8(transfer);
+        state.put_u8(bank as u8);
+
+        state.put_bytes(&self.border_tiles);
+        state.put_words(&self.border_map);
+        for palette in self.border_palettes.iter() {
+            state.put_words(palette);
+        }
+
+        state.put_words(&self.screen);
+        state.put_words(&self.output);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmuError> {
+        state.get_bytes_into(&mut self.packets)?;
+        self.bit = state.get_index(PACKET_BITS + 1)?;
+        self.received = state.get_index(MAX_PACKETS)?;
+        self.receiving = state.get_bool()?;
+        self.last_p1 = state.get_u8()?;
+
+        for palette in self.palettes.iter_mut() {
+            state.get_words_into(palette)?;
+        }
+        state.get_bytes_into(&mut self.attributes)?;
+        state.get_words_into(&mut self.system_palettes)?;
+        state.get_bytes_into(&mut self.attr_files)?;
+        self.mask = match state.get_u8()? {
+            0 => Mask::Off,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            mask => return Err(EmuError::StateLoad(format!("bad SGB mask {}", mask))),
+        };
+        self.players = state.get_index(MAX_PLAYERS + 1)?.max(1);
+        let (transfer, bank) = (state.get_u8()?, state.get_u8()? as usize & 1);
+        self.transfer = match transfer {
+            0 => None,
+            1 => Some(Transfer::Palettes),
+            2 => Some(Transfer::Tiles(bank)),
+            3 => Some(Transfer::Border),
+            4 => Some(Transfer::Attributes),
+            t => return Err(EmuError::StateLoad(format!("bad SGB transfer {}", t))),
+        };
+
+        state.get_bytes_into(&mut self.border_tiles)?;
+        state.get_words_into(&mut self.border_map)?;
+        for palette in self.border_palettes.iter_mut() {
+            state.get_words_into(palette)?;
+        }
+
+        state.get_words_into(&mut self.screen)?;
+        state.get_words_into(&mut self.output)
+    }
+}
+
+#[cfg(test)]
+fn send_packet(sgb: &mut Sgb, packet: &[u8]) {
+    sgb.write_joypad(0x00);
+    sgb.write_joypad(0x30);
+    for i in 0..PACKET_BITS + 1 {
+        let one = i < PACKET_BITS && packet.get(i / 8).is_some_and(|b| b >> (i % 8) & 1 != 0);
+        sgb.write_joypad(if one { 0x10 } else { 0x20 });
+        sgb.write_joypad(0x30);
+    }
+}
+
+#[test]
+fn sgb_palette_test() {
+    let mut sgb = Sgb::new();
+    // PAL01: white, then red, green, blue for palette 0 and black for 1
+    send_packet(
+        &mut sgb,
+        &[
+            0x01, 0xFF, 0x7F, 0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C, 0, 0, 0, 0, 0, 0,
+        ],
+    );
+    assert!(sgb.palettes[0] == [0x7FFF, 0x001F, 0x03E0, 0x7C00]);
+    assert!(sgb.palettes[1] == [0x7FFF, 0, 0, 0]);
+
+    // ATTR_DIV: palette 1 right of column 10, 0 on and left of it
+    send_packet(&mut sgb, &[0x31, 0x01, 10]);
+    let mut shades = vec![0u8; SCREEN_WIDTH * SCREEN_HEIGHT];
+    shades[8 * 10] = 1;
+    shades[8 * 11] = 3;
+    sgb.end_frame(&shades);
+    assert!(sgb.screen()[8 * 10] == 0x001F);
+    assert!(sgb.screen()[8 * 11] == 0x0000);
+    assert!(sgb.output()[SCREEN_Y * SGB_WIDTH + SCREEN_X + 8 * 10] == 0x001F);
+    assert!(sgb.output()[0] == 0x7FFF);
+
+    // MLT_REQ for two players
+    send_packet(&mut sgb, &[0x89, 0x01]);
+    assert!(sgb.players() == 2);
+}
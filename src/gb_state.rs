@@ -0,0 +1,142 @@
+use gb_error::EmuError;
+
+// Save states are each component's fields one after another, little endian,
+// with the length before anything variable sized. Loading checks those
+// lengths against the machine being loaded into.
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn put_u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn put_bool(&mut self, val: bool) {
+        self.data.push(val as u8);
+    }
+
+    pub fn put_u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn put_u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, val: u64) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.put_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn put_words(&mut self, words: &[u16]) {
+        self.put_u32(words.len() as u32);
+        for &w in words {
+            self.put_u16(w);
+        }
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, pos: 0 }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], EmuError> {
+        match self.data.get(self.pos..self.pos + len) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => Err(EmuError::StateLoad(String::from("state is cut short"))),
+        }
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, EmuError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_bool(&mut self) -> Result<bool, EmuError> {
+        Ok(self.get_u8()? != 0)
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16, EmuError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, EmuError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, EmuError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    // A usize that has to be below `limit` to be any use
+    pub fn get_index(&mut self, limit: usize) -> Result<usize, EmuError> {
+        let val = self.get_u64()? as usize;
+        if val >= limit {
+            return Err(EmuError::StateLoad(format!("{} is out of range", val)));
+        }
+        Ok(val)
+    }
+
+    pub fn get_bytes(&mut self) -> Result<&'a [u8], EmuError> {
+        let len = self.get_u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn get_bytes_into(&mut self, bytes: &mut [u8]) -> Result<(), EmuError> {
+        let saved = self.get_bytes()?;
+        if saved.len() != bytes.len() {
+            return Err(EmuError::StateLoad(format!(
+                "expected {} bytes but the state has {}",
+                bytes.len(),
+                saved.len()
+            )));
+        }
+        bytes.copy_from_slice(saved);
+        Ok(())
+    }
+
+    pub fn get_words_into(&mut self, words: &mut [u16]) -> Result<(), EmuError> {
+        let len = self.get_u32()? as usize;
+        if len != words.len() {
+            return Err(EmuError::StateLoad(format!(
+                "expected {} words but the state has {}",
+                words.len(),
+                len
+            )));
+        }
+        for w in words.iter_mut() {
+            *w = self.get_u16()?;
+        }
+        Ok(())
+    }
+}
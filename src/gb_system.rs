@@ -0,0 +1,678 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use crc32fast::hash as crc32;
+
+use cheat::Cheats;
+use gb_cpu::{DmgCpu, Registers};
+use gb_error::{EmuError, Strictness};
+use gb_hw_bus::{Bus, HardwareBus};
+use gb_joypad::{Button, MAX_PLAYERS};
+use gb_mem::{MemoryController, RamAddress, RamRegion};
+use gb_rom::GbRom;
+use gb_state::{StateReader, StateWriter};
+use tracelog::TraceLog;
+
+/// Machine cycles (4.194304 MHz dots) in one full LCD frame.
+pub const CYCLES_PER_FRAME: u64 = 70224;
+
+/// Sample rate of the sound from `GameBoy::take_audio_samples`.
+pub const AUDIO_SAMPLE_RATE: u32 = 48_000;
+
+const STATE_MAGIC: &[u8] = b"BBST";
+const STATE_VERSION: u8 = 2;
+
+/// Which console to emulate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    /// The original Game Boy.
+    Dmg,
+    /// A Game Boy in a Super Game Boy, which colours the picture and adds a
+    /// border. Only chosen when asked for.
+    Sgb,
+    /// The Game Boy Color. DMG carts run in its compatibility mode.
+    Cgb,
+}
+
+impl Model {
+    /// The model a cartridge would pick: CGB if the header asks for it.
+    pub fn for_rom(rom: &GbRom) -> Self {
+        if rom.is_cgb() {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        }
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dmg" => Ok(Model::Dmg),
+            "sgb" => Ok(Model::Sgb),
+            "cgb" => Ok(Model::Cgb),
+            _ => Err(format!("unknown model: {}", s)),
+        }
+    }
+}
+
+/// A complete machine: CPU, memory, cartridge and hardware.
+///
+/// This is the entry point for tools built on bugboy. Everything a frontend
+/// or test harness needs goes through here, so the internal modules are free
+/// to change shape.
+pub struct GameBoy {
+    cpu: DmgCpu,
+    bus: HardwareBus,
+    log: Vec<TraceLog>,
+    model: Model,
+}
+
+impl GameBoy {
+    /// Powers on a machine with an already loaded cartridge, picking the
+    /// model from its header.
+    pub fn new(rom: GbRom) -> Self {
+        let model = Model::for_rom(&rom);
+        GameBoy::with_model(rom, model)
+    }
+
+    /// Powers on a specific model, whatever the cartridge asks for.
+    pub fn with_model(rom: GbRom, model: Model) -> Self {
+        GameBoy {
+            cpu: DmgCpu::new(model),
+            bus: HardwareBus::new(MemoryController::new(rom, model)),
+            log: Vec::new(),
+            model,
+        }
+    }
+
+    /// Powers on with a boot ROM mapped over the cartridge, starting from
+    /// the state the CPU comes out of reset in. DMG and SGB boot ROMs are
+    /// 256 bytes and CGB ones 2304.
+    pub fn with_boot_rom(rom: GbRom, model: Model, boot_rom: Vec<u8>) -> Result<Self, EmuError> {
+        let size = match model {
+            Model::Dmg | Model::Sgb => 0x100,
+            Model::Cgb => 0x900,
+        };
+        if boot_rom.len() != size {
+            return Err(EmuError::RomParse {
+                offset: boot_rom.len(),
+                reason: format!("a {:?} boot ROM should be {} bytes", model, size),
+            });
+        }
+
+        let mut gb = GameBoy::with_model(rom, model);
+        gb.cpu = DmgCpu::at_reset(model);
+        let mc = gb.bus.memory_mut();
+        mc.set_boot_rom(boot_rom);
+        // the boot ROM turns the LCD on itself
+        mc.write(RamAddress::new(0xFF40), 0x00)?;
+        Ok(gb)
+    }
+
+    /// Parses `data` as a cartridge image and powers on with it.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, EmuError> {
+        GbRom::from_bytes(data).map(GameBoy::new)
+    }
+
+    /// Loads the cartridge image at `path` and powers on with it.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, EmuError> {
+        GbRom::new(path).map(GameBoy::new)
+    }
+
+    /// Executes a single instruction and returns the cycles it took.
+    ///
+    /// A stopped CPU executes nothing and reports zero cycles.
+    pub fn step_instruction(&mut self) -> Result<u64, EmuError> {
+        let start = self.cpu.get_clock();
+        self.log.clear();
+        self.cpu.tick(&mut self.bus, &mut self.log)?;
+        Ok(self.cpu.get_clock() - start)
+    }
+
+    /// Runs whole instructions until at least `cycles` cycles have elapsed,
+    /// or the CPU stops. Returns the cycles actually run, which may overshoot
+    /// by part of an instruction.
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<u64, EmuError> {
+        let start = self.cpu.get_clock();
+        while self.cpu.get_clock() - start < cycles {
+            if self.step_instruction()? == 0 {
+                break;
+            }
+        }
+        Ok(self.cpu.get_clock() - start)
+    }
+
+    /// Runs until the LCD has finished drawing a frame, so the screen is
+    /// whole, or the CPU stops. With the LCD off, runs for one frame's
+    /// worth of cycles instead, twice as many CPU cycles at double speed.
+    /// Returns the cycles run.
+    pub fn run_frame(&mut self) -> Result<u64, EmuError> {
+        let start = self.cpu.get_clock();
+        let mut clock = FrameClock::new(self);
+        while self.step_instruction()? > 0 && !clock.tick(self) {}
+        Ok(self.cpu.get_clock() - start)
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// True once a CGB program has switched the CPU to 8 MHz.
+    pub fn is_double_speed(&self) -> bool {
+        self.bus.memory().is_double_speed()
+    }
+
+    /// Total cycles executed since power on.
+    pub fn cycles(&self) -> u64 {
+        self.cpu.get_clock()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.cpu.is_stopped()
+    }
+
+    /// True once the CPU has hung on an illegal opcode. Only a power cycle
+    /// brings it back.
+    pub fn is_locked(&self) -> bool {
+        self.cpu.is_locked()
+    }
+
+    /// Sets how illegal memory accesses and opcodes are handled. Defaults to
+    /// `Strictness::Hardware`.
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.cpu.set_strictness(strictness);
+    }
+
+    pub fn strictness(&self) -> Strictness {
+        self.cpu.strictness()
+    }
+
+    /// Drains the faults noted under `Strictness::Warn`, each with the PC
+    /// of the instruction that made it. Only the first few hundred since
+    /// the last call are kept, followed by a count of the rest.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        self.cpu.take_warnings()
+    }
+
+    /// True while the CPU is waiting in HALT for an interrupt.
+    pub fn is_halted(&self) -> bool {
+        self.cpu.is_halted()
+    }
+
+    /// Frames the LCD has finished drawing since power on.
+    pub fn frame_count(&self) -> u64 {
+        self.bus.memory().ppu().frame_count()
+    }
+
+    /// The 160x144 screen, one shade index (0-3) per pixel, row major. In
+    /// CGB mode this is the color number within each pixel's palette.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.bus.framebuffer()
+    }
+
+    /// The 160x144 screen as the LCD would show it, one RGB555 color per
+    /// pixel with red in the low bits, row major. DMG shades come out grey,
+    /// and an SGB colours them with its palettes once each frame is done.
+    pub fn rgb_framebuffer(&self) -> &[u16] {
+        self.bus.rgb_framebuffer()
+    }
+
+    /// The whole SGB_WIDTH x SGB_HEIGHT picture an SGB sends to the TV,
+    /// border included, in the same format as `rgb_framebuffer`. None for
+    /// other models.
+    pub fn sgb_framebuffer(&self) -> Option<&[u16]> {
+        self.bus.memory().sgb().map(|sgb| sgb.output())
+    }
+
+    /// Drains the interleaved stereo samples generated since the last call,
+    /// left first, at AUDIO_SAMPLE_RATE. Call it at least once a second:
+    /// older samples are dropped rather than piling up for a host that
+    /// never takes them.
+    pub fn take_audio_samples(&mut self) -> Vec<i16> {
+        self.bus.take_audio_samples()
+    }
+
+    /// Every byte sent over the link port since power on.
+    pub fn serial_output(&self) -> &[u8] {
+        self.bus.memory().serial_output()
+    }
+
+    /// Drains the bytes sent over the link port since the last call.
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.bus.memory_mut().take_serial_output()
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.bus.memory_mut().set_button(0, button, pressed);
+    }
+
+    /// Presses or releases a button on one of the four controllers an SGB
+    /// can poll. Player 0 is the same controller `set_button` uses.
+    pub fn set_player_button(&mut self, player: usize, button: Button, pressed: bool) {
+        self.bus
+            .memory_mut()
+            .set_button(player % MAX_PLAYERS, button, pressed);
+    }
+
+    /// Whether a button on player 0's controller is held down.
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.bus.memory().is_pressed(0, button)
+    }
+
+    /// Player 0's buttons as a bit each, in `Button::ALL` order.
+    pub fn buttons(&self) -> u8 {
+        Button::ALL
+            .iter()
+            .enumerate()
+            .filter(|&(_, &b)| self.is_pressed(b))
+            .fold(0, |bits, (i, _)| bits | 1 << i)
+    }
+
+    /// Sets all of player 0's buttons from bits as `buttons` returns them.
+    pub fn set_buttons(&mut self, bits: u8) {
+        for (i, &button) in Button::ALL.iter().enumerate() {
+            self.set_button(button, bits & (1 << i) != 0);
+        }
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    /// Reads memory as the CPU would see it, without using any cycles.
+    pub fn read_memory(&self, addr: u16) -> u8 {
+        self.bus.memory().read(RamAddress::new(addr))
+    }
+
+    /// Writes memory as the CPU would, without using any cycles.
+    pub fn write_memory(&mut self, addr: u16, val: u8) -> Result<(), EmuError> {
+        self.bus.write(RamAddress::new(addr), val)
+    }
+
+    /// The trace of the most recently executed instruction.
+    pub fn last_trace(&self) -> &[TraceLog] {
+        &self.log
+    }
+
+    /// The ROM bank currently mapped at 0x4000-0x7FFF.
+    pub fn rom_bank(&self) -> usize {
+        self.bus.memory().cartridge().current_rom_bank()
+    }
+
+    /// The cart's RAM if it has a battery to keep it, for saving to disk.
+    pub fn save_ram(&self) -> Option<&[u8]> {
+        self.bus.memory().cartridge().battery_ram()
+    }
+
+    /// Restores battery backed RAM from an earlier `save_ram`.
+    pub fn load_save_ram(&mut self, data: &[u8]) -> Result<(), EmuError> {
+        self.bus.memory_mut().cartridge_mut().load_ram(data)
+    }
+
+    /// The raw contents of a RAM region, banks and all.
+    pub fn ram(&self, region: RamRegion) -> &[u8] {
+        self.bus.memory().ram(region)
+    }
+
+    /// Game Genie and GameShark codes applied while running.
+    pub fn cheats(&self) -> &Cheats {
+        self.bus.memory().cheats()
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut Cheats {
+        self.bus.memory_mut().cheats_mut()
+    }
+
+    pub fn rom_title(&self) -> &str {
+        self.bus.memory().rom().title()
+    }
+
+    /// CRC32 of the cartridge image, which save states are tied to.
+    pub fn rom_crc32(&self) -> u32 {
+        crc32(self.bus.memory().rom().data())
+    }
+
+    /// Everything needed to put the machine back the way it is now, apart
+    /// from the cartridge ROM and settings like strictness.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        for &b in STATE_MAGIC {
+            state.put_u8(b);
+        }
+        state.put_u8(STATE_VERSION);
+        state.put_u8(self.model as u8);
+        state.put_u32(self.rom_crc32());
+        self.cpu.save_state(&mut state);
+        self.bus.save_state(&mut state);
+        state.finish()
+    }
+
+    /// Restores a state from `save_state`, which has to come from the same
+    /// model running the same ROM. Nothing changes if it can't be loaded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), EmuError> {
+        let backup = self.save_state();
+        let loaded = self.load_state_unchecked(data);
+        if loaded.is_err() {
+            // a state this machine just saved always loads
+            let _ = self.load_state_unchecked(&backup);
+        }
+        loaded
+    }
+
+    // Loads `data`, leaving the machine half loaded if it fails partway
+    fn load_state_unchecked(&mut self, data: &[u8]) -> Result<(), EmuError> {
+        let mut state = StateReader::new(data);
+        for &b in STATE_MAGIC {
+            if state.get_u8()? != b {
+                return Err(EmuError::StateLoad(String::from("not a bugboy save state")));
+            }
+        }
+        if state.get_u8()? != STATE_VERSION {
+            return Err(EmuError::StateLoad(String::from(
+                "saved by a different version of bugboy",
+            )));
+        }
+        if state.get_u8()? != self.model as u8 {
+            return Err(EmuError::StateLoad(String::from(
+                "saved from a different model",
+            )));
+        }
+        if state.get_u32()? != self.rom_crc32() {
+            return Err(EmuError::StateLoad(String::from(
+                "saved with a different ROM",
+            )));
+        }
+
+        self.cpu.load_state(&mut state)?;
+        self.bus.load_state(&mut state)?;
+        if !state.is_done() {
+            return Err(EmuError::StateLoad(String::from("state is too long")));
+        }
+        Ok(())
+    }
+}
+
+/// Splits running into frames the same way `GameBoy::run_frame` does, for
+/// loops that step an instruction at a time.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameClock {
+    frames: u64,
+    // the LCD's frame count and the cycle count when the last frame ended
+    lcd_frames: u64,
+    start: u64,
+}
+
+impl FrameClock {
+    /// Starts counting from where `gb` is now.
+    pub fn new(gb: &GameBoy) -> Self {
+        FrameClock {
+            frames: 0,
+            lcd_frames: gb.frame_count(),
+            start: gb.cycles(),
+        }
+    }
+
+    /// Frames finished since the clock started.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Call after each instruction. Returns true if it finished a frame:
+    /// the LCD entered V-blank, or a frame's worth of cycles went by with
+    /// it off.
+    pub fn tick(&mut self, gb: &GameBoy) -> bool {
+        let cycles = if gb.is_double_speed() {
+            CYCLES_PER_FRAME * 2
+        } else {
+            CYCLES_PER_FRAME
+        };
+        if gb.frame_count() == self.lcd_frames && gb.cycles() - self.start < cycles {
+            return false;
+        }
+        self.frames += 1;
+        self.lcd_frames = gb.frame_count();
+        self.start = gb.cycles();
+        true
+    }
+}
+
+#[cfg(test)]
+pub fn test_rom(program: &[u8]) -> Vec<u8> {
+    // 32 KiB ROM-only image with `program` at the entry point
+    let mut data = vec![0u8; 0x8000];
+    data[0x0100..0x0100 + program.len()].copy_from_slice(program);
+    data
+}
+
+// A program that adds the joypad up into $C000 every loop, so any difference
+// in input changes the state from then on.
+#[cfg(test)]
+pub fn joypad_test_rom() -> Vec<u8> {
+    // LD A,$20; LDH [$00],A; LDH A,[$00]; LD B,A; LD A,[$C000]; ADD A,B;
+    // LD [$C000],A; JR -16
+    test_rom(&[
+        0x3E, 0x20, 0xE0, 0x00, 0xF0, 0x00, 0x47, 0xFA, 0x00, 0xC0, 0x80, 0xEA, 0x00, 0xC0, 0x18,
+        0xF0,
+    ])
+}
+
+#[test]
+fn step_instruction_test() {
+    // LD A,$42; LD B,A; NOP
+    let mut gb = GameBoy::from_bytes(test_rom(&[0x3E, 0x42, 0x47, 0x00])).unwrap();
+
+    assert!(gb.step_instruction().unwrap() == 8);
+    assert!(gb.step_instruction().unwrap() == 4);
+
+    let regs = gb.registers();
+    assert!(regs.a == 0x42);
+    assert!(regs.b == 0x42);
+    assert!(regs.pc == 0x0103);
+    assert!(gb.cycles() == 12);
+}
+
+#[test]
+fn run_frame_test() {
+    // JR -2
+    let mut gb = GameBoy::from_bytes(test_rom(&[0x18, 0xFE])).unwrap();
+
+    // each frame ends as the LCD enters V-blank, with the screen drawn
+    for _ in 0..3 {
+        let frames = gb.frame_count();
+        gb.run_frame().unwrap();
+        assert!(gb.frame_count() == frames + 1);
+        assert!(gb.read_memory(0xFF44) == 144);
+    }
+
+    // with the LCD off, a frame's worth of cycles is a frame
+    gb.write_memory(0xFF40, 0x00).unwrap();
+    let frames = gb.frame_count();
+    let cycles = gb.run_frame().unwrap();
+    assert!((CYCLES_PER_FRAME..CYCLES_PER_FRAME + 12).contains(&cycles));
+    assert!(gb.frame_count() == frames);
+}
+
+#[test]
+fn audio_samples_test() {
+    use runloop::CPU_HZ;
+
+    // channel 2 at full volume: LD A,$F0; LDH [$17],A; LD A,$80;
+    // LDH [$19],A; JR -2
+    let program = [0x3E, 0xF0, 0xE0, 0x17, 0x3E, 0x80, 0xE0, 0x19, 0x18, 0xFE];
+    let mut gb = GameBoy::from_bytes(test_rom(&program)).unwrap();
+    gb.take_audio_samples();
+
+    let mut cycles = 0;
+    for _ in 0..4 {
+        cycles += gb.run_frame().unwrap();
+    }
+    let samples = gb.take_audio_samples();
+    let expected = cycles * AUDIO_SAMPLE_RATE as u64 / CPU_HZ;
+    assert!((samples.len() as u64 / 2).abs_diff(expected) <= 1);
+    assert!(samples.len().is_multiple_of(2));
+    assert!(samples.iter().any(|&s| s.abs() > 1000));
+    assert!(gb.take_audio_samples().is_empty());
+}
+
+#[test]
+fn serial_output_test() {
+    // LD A,'k'; LD ($FF01),A; LD A,$81; LD ($FF02),A
+    let program = [0x3E, b'k', 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02];
+    let mut gb = GameBoy::from_bytes(test_rom(&program)).unwrap();
+    for _ in 0..4 {
+        gb.step_instruction().unwrap();
+    }
+
+    assert!(gb.serial_output() == b"k");
+    assert!(gb.take_serial_output() == b"k".to_vec());
+    assert!(gb.serial_output().is_empty());
+}
+
+#[test]
+fn cgb_speed_switch_test() {
+    // LD A,1; LDH ($4D),A; STOP; NOP
+    let mut data = test_rom(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x00]);
+    data[0x0143] = 0x80;
+    let mut gb = GameBoy::from_bytes(data).unwrap();
+    assert!(gb.model() == Model::Cgb);
+    assert!(gb.registers().a == 0x11);
+    assert!(gb.read_memory(0xFF4D) == 0x7E);
+
+    for _ in 0..3 {
+        gb.step_instruction().unwrap();
+    }
+    assert!(gb.is_double_speed());
+    assert!(!gb.is_stopped());
+    assert!(gb.read_memory(0xFF4D) == 0xFE);
+    assert!(gb.registers().pc == 0x0106);
+
+    // the same cart forced onto a DMG stops for good
+    let mut data = test_rom(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00]);
+    data[0x0143] = 0x80;
+    let mut gb = GameBoy::with_model(GbRom::from_bytes(data).unwrap(), Model::Dmg);
+    assert!(gb.registers().a == 0x01);
+    for _ in 0..3 {
+        gb.step_instruction().unwrap();
+    }
+    assert!(!gb.is_double_speed());
+    assert!(gb.is_stopped());
+}
+
+#[test]
+fn boot_rom_test() {
+    // LD A,1; LDH ($50),A, then the cart's JR -2 at 0x0100
+    let mut boot = vec![0u8; 0x100];
+    boot[0xFC..0x100].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+    let rom = || GbRom::from_bytes(test_rom(&[0x18, 0xFE])).unwrap();
+    assert!(GameBoy::with_boot_rom(rom(), Model::Dmg, vec![0u8; 0x900]).is_err());
+
+    let mut gb = GameBoy::with_boot_rom(rom(), Model::Dmg, boot).unwrap();
+    assert!(gb.registers().pc == 0x0000);
+    assert!(gb.read_memory(0x00FC) == 0x3E);
+    while gb.registers().pc < 0x0100 {
+        gb.step_instruction().unwrap();
+    }
+    assert!(gb.read_memory(0x00FC) == 0x00);
+    assert!(gb.read_memory(0x0100) == 0x18);
+}
+
+#[test]
+fn gameboy_is_send_test() {
+    fn assert_send<T: Send>() {}
+    assert_send::<GameBoy>();
+}
+
+#[test]
+fn vblank_interrupt_test() {
+    // clear IF, enable only VBlank, then EI and HALT in a loop
+    let program = [
+        0x3E, 0x00, 0xE0, 0x0F, 0x3E, 0x01, 0xE0, 0xFF, 0xFB, 0x76, 0x18, 0xFD,
+    ];
+    let mut data = test_rom(&program);
+    // VBlank handler: LD A,$99; LD ($C000),A; RETI
+    data[0x0040..0x0046].copy_from_slice(&[0x3E, 0x99, 0xEA, 0x00, 0xC0, 0xD9]);
+
+    let mut gb = GameBoy::from_bytes(data).unwrap();
+    gb.run_frame().unwrap();
+    gb.run_frame().unwrap();
+
+    assert!(gb.read_memory(0xC000) == 0x99);
+    assert!(gb.frame_count() >= 1);
+    let pc = gb.registers().pc;
+    assert!((0x0109..=0x010B).contains(&pc));
+}
+
+#[test]
+fn unknown_opcode_test() {
+    let mut gb = GameBoy::from_bytes(test_rom(&[0x00, 0xD3])).unwrap();
+    gb.set_strictness(Strictness::Strict);
+    gb.step_instruction().unwrap();
+
+    match gb.step_instruction() {
+        Err(EmuError::UnknownOpcode { opcode, pc, bank }) => {
+            assert!(opcode == 0xD3);
+            assert!(pc == 0x0101);
+            assert!(bank == 0);
+        }
+        r => panic!("expected an unknown opcode, got {:?}", r),
+    }
+}
+
+#[test]
+fn hardware_strictness_test() {
+    // LD A,$42; LD ($FEA0),A; LD ($2000),A; then an illegal opcode. The
+    // write to ROM is a no-op on a cart without an MBC, not a fault.
+    let program = [0x3E, 0x42, 0xEA, 0xA0, 0xFE, 0xEA, 0x00, 0x20, 0xDB, 0x3C];
+    let mut gb = GameBoy::from_bytes(test_rom(&program)).unwrap();
+    for _ in 0..4 {
+        gb.step_instruction().unwrap();
+    }
+
+    assert!(gb.is_locked());
+    let pc = gb.registers().pc;
+    assert!(gb.step_instruction().unwrap() == 4);
+    assert!(gb.registers().pc == pc);
+    assert!(gb.registers().a == 0x42);
+    assert!(gb.take_warnings().is_empty());
+
+    // the same faults, noted rather than ignored
+    let mut gb = GameBoy::from_bytes(test_rom(&program)).unwrap();
+    gb.set_strictness(Strictness::Warn);
+    for _ in 0..4 {
+        gb.step_instruction().unwrap();
+    }
+    let warnings = gb.take_warnings();
+    assert!(warnings.len() == 2);
+    assert!(warnings[0].ends_with("(PC 0x0102)"));
+    assert!(warnings[1].ends_with("(PC 0x0108)"));
+    assert!(gb.take_warnings().is_empty());
+}
+
+#[test]
+fn save_state_test() {
+    // INC A; LDH [$80],A; JR -5
+    let program = [0x3C, 0xE0, 0x80, 0x18, 0xFB];
+    let mut gb = GameBoy::from_bytes(test_rom(&program)).unwrap();
+    gb.run_frame().unwrap();
+    let state = gb.save_state();
+
+    gb.run_frame().unwrap();
+    let (a, cycles) = (gb.registers().a, gb.cycles());
+    let later = gb.save_state();
+
+    gb.load_state(&state).unwrap();
+    assert!(gb.save_state() == state);
+    gb.run_frame().unwrap();
+    assert!(gb.registers().a == a && gb.cycles() == cycles);
+    assert!(gb.save_state() == later);
+
+    // a bad state leaves the machine alone
+    assert!(gb.load_state(&state[..state.len() - 1]).is_err());
+    assert!(gb.load_state(&state[..20]).is_err());
+    assert!(gb.save_state() == later);
+    let mut other = GameBoy::from_bytes(test_rom(&[0x00])).unwrap();
+    assert!(other.load_state(&state).is_err());
+}
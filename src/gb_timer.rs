@@ -0,0 +1,120 @@
+use gb_error::EmuError;
+use gb_mem::TIMER_OVERFLOW_IF;
+use gb_state::{StateReader, StateWriter};
+
+const TAC_ENABLE: u8 = 1 << 2;
+
+// DIV, TIMA, TMA and TAC (0xFF04-0xFF07)
+#[derive(Debug)]
+pub struct Timer {
+    counter: u16, // DIV is the upper byte of this free running counter
+    tima: u8,
+    tma: u8,
+    tac: u8,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Timer {
+            // value the DMG boot ROM leaves behind
+            counter: 0xABCC,
+            tima: 0,
+            tma: 0,
+            tac: 0xF8,
+        }
+    }
+
+    // the counter bit whose falling edge clocks TIMA
+    fn tima_bit(&self) -> u16 {
+        match self.tac & 0b11 {
+            0b00 => 1 << 9, // 4096 Hz
+            0b01 => 1 << 3, // 262144 Hz
+            0b10 => 1 << 5, // 65536 Hz
+            _ => 1 << 7,    // 16384 Hz
+        }
+    }
+
+    fn timer_input(&self) -> bool {
+        self.tac & TAC_ENABLE != 0 && self.counter & self.tima_bit() != 0
+    }
+
+    fn increment_tima(&mut self) -> u8 {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        if overflow {
+            self.tima = self.tma;
+            TIMER_OVERFLOW_IF
+        } else {
+            self.tima = tima;
+            0
+        }
+    }
+
+    // Advances by `cycles` and returns any interrupts raised
+    pub fn step(&mut self, cycles: u64) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..cycles {
+            let before = self.timer_input();
+            self.counter = self.counter.wrapping_add(1);
+            if before && !self.timer_input() {
+                interrupts |= self.increment_tima();
+            }
+        }
+        interrupts
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            _ => self.tac | 0xF8,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) -> u8 {
+        // resetting DIV or changing TAC can drop the timer input, which
+        // counts as a falling edge
+        let before = self.timer_input();
+        match addr {
+            0xFF04 => self.counter = 0,
+            0xFF05 => self.tima = val,
+            0xFF06 => self.tma = val,
+            _ => self.tac = val | 0xF8,
+        }
+
+        if before && !self.timer_input() {
+            self.increment_tima()
+        } else {
+            0
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.put_u16(self.counter);
+        state.put_u8(self.tima);
+        state.put_u8(self.tma);
+        state.put_u8(self.tac);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), EmuError> {
+        self.counter = state.get_u16()?;
+        self.tima = state.get_u8()?;
+        self.tma = state.get_u8()?;
+        self.tac = state.get_u8()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn timer_overflow_test() {
+    let mut timer = Timer::new();
+    timer.write(0xFF04, 0);
+    timer.write(0xFF06, 0xF0);
+    timer.write(0xFF05, 0xFF);
+    timer.write(0xFF07, TAC_ENABLE | 0b01);
+
+    assert!(timer.step(15) == 0);
+    assert!(timer.step(1) == TIMER_OVERFLOW_IF);
+    assert!(timer.read(0xFF05) == 0xF0);
+    assert!(timer.read(0xFF07) == 0xFD);
+}
@@ -0,0 +1,140 @@
+//! Checks rendering against known-good screens, for test ROMs like
+//! dmg-acid2 and mealybug-tearoom that show their result rather than
+//! reporting it.
+
+use gb_error::EmuError;
+use gb_ppu::rgb888;
+use gb_system::GameBoy;
+use png::Image;
+use screenshot::Screenshot;
+
+// FNV-1a, which unlike the std hashers is fixed forever
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+const MISMATCH: [u8; 3] = [0xFF, 0x00, 0x00];
+
+/// A hash of a screen that's the same on every run, platform and version,
+/// so it can be written down in a test.
+pub fn frame_hash(screen: &Screenshot) -> u64 {
+    let mut hash = FNV_OFFSET;
+    for b in screen.pixels().iter().flat_map(|p| p.to_le_bytes()) {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Runs up to `cycles` more CPU cycles, stopping early before executing
+/// `trigger` if given. Test ROMs commonly use LD B,B (0x40) to say they're
+/// done. Returns whether the trigger was reached.
+pub fn run_until(gb: &mut GameBoy, cycles: u64, trigger: Option<u8>) -> Result<bool, EmuError> {
+    let end = gb.cycles() + cycles;
+    while gb.cycles() < end && !gb.is_stopped() {
+        if let Some(opcode) = trigger {
+            let pc = gb.registers().pc;
+            if gb.read_memory(pc) == opcode {
+                return Ok(true);
+            }
+        }
+        gb.step_instruction()?;
+    }
+    Ok(false)
+}
+
+/// How a screen measured up to its reference.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    /// Pixels that don't match. When the sizes differ, pixels that only one
+    /// of the two has count too.
+    pub mismatched: usize,
+    /// The reference dimmed, with mismatched pixels in red.
+    pub diff: Image,
+}
+
+impl Comparison {
+    pub fn passed(&self) -> bool {
+        self.mismatched == 0
+    }
+}
+
+/// Compares `screen` with a reference image pixel by pixel. Colours are
+/// compared at the five bits a channel the hardware has, so references
+/// saved from other emulators' RGB555 to RGB888 expansion still match.
+/// A reference of a different size never passes.
+pub fn compare(screen: &Screenshot, reference: &Image) -> Comparison {
+    let mut mismatched = 0;
+    let mut pixels = Vec::with_capacity(screen.pixels().len());
+    for (i, &colour) in screen.pixels().iter().enumerate() {
+        let (x, y) = (i % screen.width(), i / screen.width());
+        let expected = if x < reference.width && y < reference.height {
+            Some(reference.pixels[y * reference.width + x])
+        } else {
+            None
+        };
+
+        let actual = rgb888(colour);
+        match expected {
+            Some(e) if e.iter().zip(&actual).all(|(e, a)| e >> 3 == a >> 3) => {
+                let grey = (e.iter().map(|&c| c as u16).sum::<u16>() / 9) as u8;
+                pixels.push([grey; 3]);
+            }
+            _ => {
+                mismatched += 1;
+                pixels.push(MISMATCH);
+            }
+        }
+    }
+
+    // the reference's pixels past the screen's edges
+    let overlap = screen.width().min(reference.width) * screen.height().min(reference.height);
+    mismatched += reference.width * reference.height - overlap;
+
+    Comparison {
+        mismatched,
+        diff: Image {
+            width: screen.width(),
+            height: screen.height(),
+            pixels,
+        },
+    }
+}
+
+#[test]
+fn golden_test() {
+    use gb_system::test_rom;
+    use png;
+
+    // LD A,A; LD A,A; LD B,B; JR -2
+    let mut gb = GameBoy::from_bytes(test_rom(&[0x7F, 0x7F, 0x40, 0x18, 0xFE])).unwrap();
+    assert!(run_until(&mut gb, 1000, Some(0x40)).unwrap());
+    assert!(gb.registers().pc == 0x102);
+    assert!(!run_until(&mut gb, 1000, Some(0x76)).unwrap());
+
+    let screen = Screenshot::capture(&gb, None);
+    assert!(frame_hash(&screen) == frame_hash(&screen.clone()));
+    assert!(frame_hash(&screen) != frame_hash(&screen.scaled(2)));
+
+    let mut reference = png::decode(&screen.to_png(false)).unwrap();
+    assert!(compare(&screen, &reference).passed());
+    reference.pixels[5] = [0x12, 0x34, 0x56];
+    let comparison = compare(&screen, &reference);
+    assert!(comparison.mismatched == 1);
+    assert!(comparison.diff.pixels[5] == MISMATCH);
+
+    // a reference bigger or smaller than the screen never passes
+    let reference = png::decode(&screen.to_png(false)).unwrap();
+    let mut wider = reference.clone();
+    wider.width += 1;
+    wider.pixels = (0..wider.width * wider.height)
+        .map(|i| {
+            let (x, y) = (i % wider.width, i / wider.width);
+            reference.pixels[y * reference.width + x.min(reference.width - 1)]
+        })
+        .collect();
+    assert!(compare(&screen, &wider).mismatched == reference.height);
+    let mut shorter = reference.clone();
+    shorter.height -= 1;
+    shorter.pixels.truncate(shorter.width * shorter.height);
+    assert!(compare(&screen, &shorter).mismatched == reference.width);
+}
@@ -0,0 +1,63 @@
+//! bugboy is a Game Boy emulator built around being testable and extensible.
+//!
+//! Tools should drive the emulator through [`GameBoy`](struct.GameBoy.html),
+//! which owns the whole machine and exposes stepping, input, video, audio,
+//! serial and debugging access.
+
+extern crate crc32fast;
+#[macro_use]
+extern crate enum_primitive;
+extern crate flate2;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(feature = "romdb")]
+extern crate md5;
+extern crate num;
+#[cfg(feature = "romdb")]
+extern crate roxmltree;
+#[macro_use]
+extern crate serde_json;
+#[cfg(feature = "romdb")]
+extern crate sha1;
+extern crate zip;
+
+pub mod cheat;
+pub mod disasm;
+pub mod fix;
+mod gb_apu;
+mod gb_cart;
+mod gb_cpu;
+mod gb_error;
+mod gb_hw_bus;
+mod gb_joypad;
+mod gb_mem;
+pub mod gb_opcodes;
+mod gb_ppu;
+mod gb_rom;
+mod gb_serial;
+mod gb_sgb;
+mod gb_state;
+mod gb_system;
+mod gb_timer;
+pub mod golden;
+pub mod movie;
+pub mod patch;
+pub mod png;
+pub mod ramsearch;
+pub mod record;
+pub mod rewind;
+#[cfg(feature = "romdb")]
+pub mod romdb;
+pub mod runloop;
+pub mod screenshot;
+pub mod term;
+pub mod tracelog;
+
+pub use gb_cpu::Registers;
+pub use gb_error::{EmuError, Strictness};
+pub use gb_joypad::Button;
+pub use gb_mem::{MemorySection, RamRegion};
+pub use gb_ppu::{rgb888, SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use gb_rom::{GbRom, ValidationPolicy, ValidationReport};
+pub use gb_sgb::{SGB_HEIGHT, SGB_WIDTH};
+pub use gb_system::{FrameClock, GameBoy, Model, AUDIO_SAMPLE_RATE, CYCLES_PER_FRAME};
@@ -0,0 +1,306 @@
+//! Input movies: the joypad state for every frame of a run, so it can be
+//! played back exactly.
+//!
+//! Every so often the movie also keeps a hash of the whole machine state.
+//! Playback compares against those and stops at the first difference, so a
+//! change in emulation shows up at the frame it happened instead of as a
+//! run that quietly wanders off.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crc32fast::hash as crc32;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use gb_error::EmuError;
+use gb_state::{StateReader, StateWriter};
+use gb_system::{GameBoy, Model};
+
+const MAGIC: &[u8] = b"BBMV";
+const FORMAT_VERSION: u8 = 1;
+
+/// Frames between state hashes unless asked otherwise.
+pub const DEFAULT_HASH_INTERVAL: u32 = 60;
+
+fn bad<T>(reason: &str) -> Result<T, EmuError> {
+    Err(EmuError::Movie(String::from(reason)))
+}
+
+/// Where a movie starts from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Start {
+    /// A machine just powered on, without a boot ROM.
+    PowerOn,
+    /// A save state from `GameBoy::save_state`.
+    State(Vec<u8>),
+}
+
+/// A recorded run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    /// The bugboy version that recorded it.
+    pub version: String,
+    pub rom_crc32: u32,
+    pub model: Model,
+    pub start: Start,
+    /// Frames between state hashes, 0 for none.
+    pub hash_interval: u32,
+    // player 0's buttons during each frame, a bit each in Button::ALL order
+    inputs: Vec<u8>,
+    // (frames run, CRC32 of the state after them)
+    hashes: Vec<(u64, u32)>,
+}
+
+fn state_hash(gb: &GameBoy) -> u32 {
+    crc32(&gb.save_state())
+}
+
+impl Movie {
+    /// Starts a movie of `gb` from where it is now. Unless `gb` has just
+    /// powered on without a boot ROM, pass `Start::State` so playback can
+    /// get back here.
+    pub fn new(gb: &GameBoy, start: Start) -> Self {
+        Movie {
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            rom_crc32: gb.rom_crc32(),
+            model: gb.model(),
+            start,
+            hash_interval: DEFAULT_HASH_INTERVAL,
+            inputs: Vec::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    /// Frames recorded.
+    pub fn len(&self) -> u64 {
+        self.inputs.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// Adds the frame `gb` has just run, with the buttons it held.
+    pub fn record_frame(&mut self, gb: &GameBoy) {
+        self.inputs.push(gb.buttons());
+        let frames = self.len();
+        if self.hash_interval > 0 && frames.is_multiple_of(self.hash_interval as u64) {
+            self.hashes.push((frames, state_hash(gb)));
+        }
+    }
+
+    /// Forgets everything after the first `frames` frames, to record them
+    /// again.
+    pub fn truncate(&mut self, frames: u64) {
+        self.inputs.truncate(frames as usize);
+        self.hashes.retain(|&(frame, _)| frame <= frames);
+    }
+
+    /// Checks `gb` can play this movie and puts it at the start.
+    pub fn play(self, gb: &mut GameBoy) -> Result<Playback, EmuError> {
+        if gb.rom_crc32() != self.rom_crc32 {
+            return bad("recorded with a different ROM");
+        }
+        if gb.model() != self.model {
+            return Err(EmuError::Movie(format!(
+                "recorded on a {:?} but this is a {:?}",
+                self.model,
+                gb.model()
+            )));
+        }
+        if let Start::State(ref state) = self.start {
+            gb.load_state(state)?;
+        }
+        Ok(Playback {
+            movie: self,
+            frame: 0,
+            hash: 0,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = StateWriter::new();
+        for &b in MAGIC {
+            data.put_u8(b);
+        }
+        data.put_u8(FORMAT_VERSION);
+        data.put_bytes(self.version.as_bytes());
+        data.put_u32(self.rom_crc32);
+        data.put_u8(self.model as u8);
+        match self.start {
+            Start::PowerOn => data.put_bytes(&[]),
+            Start::State(ref state) => data.put_bytes(state),
+        }
+        data.put_u32(self.hash_interval);
+        data.put_bytes(&self.inputs);
+        data.put_u32(self.hashes.len() as u32);
+        for &(frame, hash) in &self.hashes {
+            data.put_u64(frame);
+            data.put_u32(hash);
+        }
+
+        // long runs of the same input squash down well
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        let _ = gz.write_all(&data.finish());
+        gz.finish().unwrap_or_default()
+    }
+
+    pub fn from_bytes(compressed: &[u8]) -> Result<Self, EmuError> {
+        let mut data = Vec::new();
+        if GzDecoder::new(compressed).read_to_end(&mut data).is_err() {
+            return bad("not a bugboy movie");
+        }
+        Movie::parse(&mut StateReader::new(&data)).map_err(|e| match e {
+            EmuError::StateLoad(_) => EmuError::Movie(String::from("movie is cut short")),
+            e => e,
+        })
+    }
+
+    fn parse(data: &mut StateReader) -> Result<Self, EmuError> {
+        for &b in MAGIC {
+            if data.get_u8()? != b {
+                return bad("not a bugboy movie");
+            }
+        }
+        if data.get_u8()? != FORMAT_VERSION {
+            return bad("made by an incompatible version of bugboy");
+        }
+        let version = String::from_utf8_lossy(data.get_bytes()?).into_owned();
+        let rom_crc32 = data.get_u32()?;
+        let model = match data.get_u8()? {
+            m if m == Model::Dmg as u8 => Model::Dmg,
+            m if m == Model::Sgb as u8 => Model::Sgb,
+            m if m == Model::Cgb as u8 => Model::Cgb,
+            _ => return bad("unknown model"),
+        };
+        let start = match data.get_bytes()? {
+            [] => Start::PowerOn,
+            state => Start::State(state.to_vec()),
+        };
+        let hash_interval = data.get_u32()?;
+        let inputs = data.get_bytes()?.to_vec();
+        let count = data.get_u32()? as usize;
+        let mut hashes = Vec::with_capacity(count.min(inputs.len()));
+        for _ in 0..count {
+            hashes.push((data.get_u64()?, data.get_u32()?));
+        }
+        if !data.is_done() {
+            return bad("movie has trailing data");
+        }
+
+        Ok(Movie {
+            version,
+            rom_crc32,
+            model,
+            start,
+            hash_interval,
+            inputs,
+            hashes,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), EmuError> {
+        let path = path.as_ref();
+        fs::write(path, self.to_bytes())
+            .map_err(|e| EmuError::Io(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, EmuError> {
+        let path = path.as_ref();
+        let data =
+            fs::read(path).map_err(|e| EmuError::Io(format!("{}: {}", path.display(), e)))?;
+        Movie::from_bytes(&data)
+    }
+}
+
+/// Plays a movie back a frame at a time.
+pub struct Playback {
+    movie: Movie,
+    frame: u64,
+    // next entry in the movie's hashes to check
+    hash: usize,
+}
+
+impl Playback {
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.len()
+    }
+
+    /// Frames played so far.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Holds the buttons for the next frame. Call before running it.
+    pub fn press(&self, gb: &mut GameBoy) {
+        let bits = self
+            .movie
+            .inputs
+            .get(self.frame as usize)
+            .cloned()
+            .unwrap_or(0);
+        gb.set_buttons(bits);
+    }
+
+    /// Moves on once the frame has run, checking the state hash if there's
+    /// one for it.
+    pub fn frame_done(&mut self, gb: &GameBoy) -> Result<(), EmuError> {
+        self.frame += 1;
+        if let Some(&(frame, hash)) = self.movie.hashes.get(self.hash) {
+            if frame == self.frame {
+                self.hash += 1;
+                if state_hash(gb) != hash {
+                    return Err(EmuError::Movie(format!("desynced by frame {}", frame)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn movie_test() {
+    use gb_joypad::Button;
+    use gb_system::joypad_test_rom;
+
+    let rom = joypad_test_rom();
+    let mut gb = GameBoy::from_bytes(rom.clone()).unwrap();
+    let mut movie = Movie::new(&gb, Start::PowerOn);
+    movie.hash_interval = 2;
+    for frame in 0..6 {
+        gb.set_button(Button::Right, frame % 3 == 0);
+        gb.run_frame().unwrap();
+        movie.record_frame(&gb);
+    }
+    let end = gb.save_state();
+
+    let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+    assert!(movie.len() == 6 && movie.hashes.len() == 3);
+
+    let mut gb = GameBoy::from_bytes(rom.clone()).unwrap();
+    let mut playback = movie.clone().play(&mut gb).unwrap();
+    while !playback.is_finished() {
+        playback.press(&mut gb);
+        gb.run_frame().unwrap();
+        playback.frame_done(&gb).unwrap();
+    }
+    assert!(gb.save_state() == end);
+
+    // a different input shows up at the next hash
+    let mut gb = GameBoy::from_bytes(rom).unwrap();
+    let mut playback = movie.play(&mut gb).unwrap();
+    playback.press(&mut gb);
+    gb.set_button(Button::Left, true);
+    gb.run_frame().unwrap();
+    playback.frame_done(&gb).unwrap();
+    playback.press(&mut gb);
+    gb.run_frame().unwrap();
+    assert!(playback.frame_done(&gb).is_err());
+}
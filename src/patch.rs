@@ -0,0 +1,388 @@
+//! IPS, BPS and UPS soft-patching.
+
+use crc32fast::hash as crc32;
+
+use gb_error::EmuError;
+use gb_rom::MAX_UNPACKED;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+const UPS_MAGIC: &[u8] = b"UPS1";
+
+// BPS and UPS both end in source, target and patch CRC32s
+const FOOTER_SIZE: usize = 12;
+
+/// File extensions of the patch formats we understand.
+pub const EXTENSIONS: [&str; 3] = ["ips", "bps", "ups"];
+
+fn bad(reason: &str) -> EmuError {
+    EmuError::Patch(String::from(reason))
+}
+
+fn error<T>(reason: &str) -> Result<T, EmuError> {
+    Err(bad(reason))
+}
+
+/// Applies `patch` to `rom`, working out the format from its header.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, EmuError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(rom, patch)
+    } else {
+        error("not an IPS, BPS or UPS patch")
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], EmuError> {
+        if self.data.len() - self.pos < count {
+            return error("patch ends unexpectedly");
+        }
+        let bytes = &self.data[self.pos..self.pos + count];
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, EmuError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn big_endian(&mut self, count: usize) -> Result<usize, EmuError> {
+        Ok(self
+            .bytes(count)?
+            .iter()
+            .fold(0, |val, b| val << 8 | *b as usize))
+    }
+
+    // The variable length integers BPS and UPS share: seven bits at a time,
+    // last byte flagged by the top bit, with an offset so every value has
+    // exactly one encoding.
+    fn number(&mut self) -> Result<usize, EmuError> {
+        let mut val = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let b = self.byte()?;
+            val = (b as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|v| v.checked_add(val))
+                .ok_or_else(|| bad("number too large"))?;
+            if b & 0x80 != 0 {
+                return Ok(val);
+            }
+            shift = shift
+                .checked_mul(0x80)
+                .ok_or_else(|| bad("number too large"))?;
+            val = val
+                .checked_add(shift)
+                .ok_or_else(|| bad("number too large"))?;
+        }
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, EmuError> {
+    let mut out = rom.to_vec();
+    let mut reader = Reader::new(patch, IPS_MAGIC.len());
+
+    loop {
+        let record = reader.bytes(3)?;
+        if record == IPS_EOF {
+            break;
+        }
+
+        let offset = record.iter().fold(0, |val, b| val << 8 | *b as usize);
+        let size = reader.big_endian(2)?;
+        let (size, data) = if size == 0 {
+            // run length encoded record
+            let size = reader.big_endian(2)?;
+            (size, vec![reader.byte()?; size])
+        } else {
+            (size, reader.bytes(size)?.to_vec())
+        };
+
+        if out.len() < offset + size {
+            out.resize(offset + size, 0);
+        }
+        out[offset..offset + size].copy_from_slice(&data);
+    }
+
+    // some patches also shrink the file
+    if let Ok(length) = reader.big_endian(3) {
+        out.truncate(length);
+    }
+    Ok(out)
+}
+
+// Splits off the BPS/UPS footer, checking the patch against its own CRC and
+// the ROM against the source CRC. Returns the body and the target CRC.
+fn check_footer<'a>(rom: &[u8], patch: &'a [u8]) -> Result<(&'a [u8], u32), EmuError> {
+    if patch.len() < 4 + FOOTER_SIZE {
+        return error("patch ends unexpectedly");
+    }
+
+    let (body, footer) = patch.split_at(patch.len() - FOOTER_SIZE);
+    let crc = |i: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&footer[i..i + 4]);
+        u32::from_le_bytes(bytes)
+    };
+
+    if crc32(&patch[..patch.len() - 4]) != crc(8) {
+        return error("patch is corrupt, its CRC doesn't match");
+    }
+    if crc32(rom) != crc(0) {
+        return error("patch was made for a different ROM, the source CRC doesn't match");
+    }
+    Ok((body, crc(4)))
+}
+
+// A patch can ask for any size it likes, so hold it to the biggest cart
+fn check_size(target_size: usize) -> Result<(), EmuError> {
+    if target_size as u64 > MAX_UNPACKED {
+        return Err(EmuError::Patch(format!(
+            "patched ROM would be over {} MiB",
+            MAX_UNPACKED / 1024 / 1024
+        )));
+    }
+    Ok(())
+}
+
+fn check_target(out: Vec<u8>, target_crc: u32) -> Result<Vec<u8>, EmuError> {
+    if crc32(&out) != target_crc {
+        return error("patched ROM doesn't match the target CRC");
+    }
+    Ok(out)
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, EmuError> {
+    let (body, target_crc) = check_footer(rom, patch)?;
+    let mut reader = Reader::new(body, UPS_MAGIC.len());
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    if source_size != rom.len() {
+        return error("patch was made for a ROM of a different size");
+    }
+    check_size(target_size)?;
+
+    let mut out = rom.to_vec();
+    out.resize(target_size, 0);
+
+    // runs of bytes XORed with the source, each ended by a zero
+    let mut pos = 0usize;
+    while reader.pos < body.len() {
+        pos = pos
+            .checked_add(reader.number()?)
+            .ok_or_else(|| bad("offset out of range"))?;
+        loop {
+            let x = reader.byte()?;
+            if pos < target_size {
+                out[pos] ^= x;
+            }
+            pos = pos.saturating_add(1);
+            if x == 0 {
+                break;
+            }
+        }
+    }
+
+    check_target(out, target_crc)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, EmuError> {
+    let (body, target_crc) = check_footer(rom, patch)?;
+    let mut reader = Reader::new(body, BPS_MAGIC.len());
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return error("patch was made for a ROM of a different size");
+    }
+    check_size(target_size)?;
+
+    let mut out: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_pos = 0usize;
+    let mut target_pos = 0usize;
+
+    // relative copy offsets carry a sign in their lowest bit
+    let seek = |reader: &mut Reader, pos: usize| -> Result<usize, EmuError> {
+        let data = reader.number()?;
+        let moved = if data & 1 != 0 {
+            pos.checked_sub(data >> 1)
+        } else {
+            pos.checked_add(data >> 1)
+        };
+        moved.ok_or_else(|| bad("copy offset out of range"))
+    };
+
+    while reader.pos < body.len() {
+        let action = reader.number()?;
+        let length = (action >> 2) + 1;
+        if length > target_size - out.len() {
+            return error("patch writes past the end of the target");
+        }
+
+        match action & 3 {
+            // copy from the same place in the source
+            0 => {
+                let start = out.len();
+                match rom.get(start..start + length) {
+                    Some(bytes) => out.extend_from_slice(bytes),
+                    None => return error("source read out of range"),
+                }
+            }
+            // bytes stored in the patch
+            1 => out.extend_from_slice(reader.bytes(length)?),
+            // copy from anywhere in the source
+            2 => {
+                source_pos = seek(&mut reader, source_pos)?;
+                let end = source_pos
+                    .checked_add(length)
+                    .ok_or_else(|| bad("source copy out of range"))?;
+                match rom.get(source_pos..end) {
+                    Some(bytes) => out.extend_from_slice(bytes),
+                    None => return error("source copy out of range"),
+                }
+                source_pos = end;
+            }
+            // copy from earlier in the output, which may overlap what's
+            // being written so it has to go a byte at a time
+            _ => {
+                target_pos = seek(&mut reader, target_pos)?;
+                for _ in 0..length {
+                    match out.get(target_pos) {
+                        Some(b) => {
+                            let b = *b;
+                            out.push(b);
+                        }
+                        None => return error("target copy out of range"),
+                    }
+                    target_pos += 1;
+                }
+            }
+        }
+    }
+
+    if out.len() != target_size {
+        return error("patched ROM is the wrong size");
+    }
+    check_target(out, target_crc)
+}
+
+#[cfg(test)]
+fn number(mut val: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let x = (val & 0x7F) as u8;
+        val >>= 7;
+        if val == 0 {
+            out.push(0x80 | x);
+            return out;
+        }
+        out.push(x);
+        val -= 1;
+    }
+}
+
+#[cfg(test)]
+fn with_footer(rom: &[u8], target: &[u8], mut patch: Vec<u8>) -> Vec<u8> {
+    patch.extend_from_slice(&crc32(rom).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    let crc = crc32(&patch);
+    patch.extend_from_slice(&crc.to_le_bytes());
+    patch
+}
+
+#[test]
+fn ips_patch_test() {
+    let rom = [0u8; 8];
+    let mut patch = b"PATCH".to_vec();
+    patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+    // RLE record running past the end
+    patch.extend_from_slice(&[0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+    patch.extend_from_slice(b"EOF");
+
+    let out = apply(&rom, &patch).unwrap();
+    assert!(out == [0x00, 0xAA, 0xBB, 0x00, 0x00, 0x00, 0x00, 0xCC, 0xCC, 0xCC]);
+
+    patch.extend_from_slice(&[0x00, 0x00, 0x04]);
+    assert!(apply(&rom, &patch).unwrap() == [0x00, 0xAA, 0xBB, 0x00]);
+}
+
+#[test]
+fn ups_patch_test() {
+    let rom = [1u8, 2, 3, 4];
+    let target = [1u8, 5, 3, 4, 9];
+
+    let mut body = b"UPS1".to_vec();
+    body.extend(number(4));
+    body.extend(number(5));
+    body.extend(number(1));
+    body.extend_from_slice(&[2 ^ 5, 0x00]);
+    body.extend(number(1));
+    body.extend_from_slice(&[9, 0x00]);
+    let patch = with_footer(&rom, &target, body);
+
+    assert!(apply(&rom, &patch).unwrap() == target);
+
+    match apply(&target, &patch) {
+        Err(EmuError::Patch(_)) => (),
+        r => panic!("expected a source CRC mismatch, got {:?}", r),
+    }
+}
+
+#[test]
+fn bps_patch_test() {
+    let rom = b"ABCDEFGH";
+    let target = b"ABCDxyxyxyGH";
+
+    let mut body = b"BPS1".to_vec();
+    body.extend(number(rom.len()));
+    body.extend(number(target.len()));
+    body.extend(number(0));
+    // SourceRead 4
+    body.extend(number(3 << 2));
+    // TargetRead "xy"
+    body.extend(number((1 << 2) | 1));
+    body.extend_from_slice(b"xy");
+    // TargetCopy 4 from offset 4, overlapping its own output
+    body.extend(number((3 << 2) | 3));
+    body.extend(number(4 << 1));
+    // SourceCopy 2 from offset 6
+    body.extend(number((1 << 2) | 2));
+    body.extend(number(6 << 1));
+    let patch = with_footer(rom, target, body);
+
+    assert!(apply(rom, &patch).unwrap() == target[..]);
+
+    let mut corrupt = patch.clone();
+    corrupt[5] ^= 0xFF;
+    assert!(apply(rom, &corrupt).is_err());
+
+    // a well-formed patch asking for a huge target, or with a number that
+    // doesn't fit, is refused rather than allocated or wrapped
+    let mut huge = b"BPS1".to_vec();
+    huge.extend(number(rom.len()));
+    huge.extend(number(usize::MAX >> 1));
+    huge.extend(number(0));
+    match apply(rom, &with_footer(rom, target, huge)) {
+        Err(EmuError::Patch(_)) => (),
+        r => panic!("expected an oversized target to fail, got {:?}", r),
+    }
+    let mut overflow = b"BPS1".to_vec();
+    overflow.extend_from_slice(&[0x7F; 12]);
+    overflow.push(0x80);
+    assert!(apply(rom, &with_footer(rom, target, overflow)).is_err());
+}
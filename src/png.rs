@@ -0,0 +1,296 @@
+//! Just enough PNG for screenshots and reference images. Writes 8-bit RGB or
+//! indexed colour without filtering, and reads any non-interlaced image.
+
+use std::io::{Read, Write};
+
+use crc32fast::Hasher;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use gb_error::EmuError;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+const COLOUR_GREY: u8 = 0;
+const COLOUR_RGB: u8 = 2;
+const COLOUR_INDEXED: u8 = 3;
+const COLOUR_GREY_ALPHA: u8 = 4;
+const COLOUR_RGBA: u8 = 6;
+
+// Far past anything a reference screenshot needs, and small enough that the
+// sizes worked out from it can't overflow.
+const MAX_SIDE: usize = 16384;
+
+/// A decoded image, alpha dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// Row major.
+    pub pixels: Vec<[u8; 3]>,
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let mut crc = Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    out.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+// `data` is split evenly into `height` rows, each given a leading 0 for no
+// filter
+fn encode(
+    width: usize,
+    height: usize,
+    colour: u8,
+    palette: Option<&[[u8; 3]]>,
+    data: &[u8],
+) -> Vec<u8> {
+    let mut out = SIGNATURE.to_vec();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // bit depth, colour type, compression, filter, interlace
+    header.extend_from_slice(&[8, colour, 0, 0, 0]);
+    chunk(&mut out, b"IHDR", &header);
+
+    if let Some(palette) = palette {
+        let entries: Vec<u8> = palette.iter().flat_map(|c| c.iter().cloned()).collect();
+        chunk(&mut out, b"PLTE", &entries);
+    }
+
+    let row = data.len().checked_div(height).unwrap_or(0);
+    let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+    for line in data.chunks(row.max(1)) {
+        // writing to a Vec can't fail
+        let _ = zlib.write_all(&[0]);
+        let _ = zlib.write_all(line);
+    }
+    let compressed = zlib.finish().unwrap_or_default();
+    chunk(&mut out, b"IDAT", &compressed);
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/// A truecolour PNG of `pixels`, row major.
+pub fn encode_rgb(width: usize, height: usize, pixels: &[[u8; 3]]) -> Vec<u8> {
+    let data: Vec<u8> = pixels.iter().flat_map(|c| c.iter().cloned()).collect();
+    encode(width, height, COLOUR_RGB, None, &data)
+}
+
+/// An indexed PNG where each of `indices` picks a colour from `palette`,
+/// which can have up to 256 entries.
+pub fn encode_indexed(width: usize, height: usize, palette: &[[u8; 3]], indices: &[u8]) -> Vec<u8> {
+    encode(width, height, COLOUR_INDEXED, Some(palette), indices)
+}
+
+fn bad<T>(reason: &str) -> Result<T, EmuError> {
+    Err(EmuError::Io(format!("bad PNG: {}", reason)))
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Reads a PNG into RGB. Interlaced images aren't supported.
+pub fn decode(data: &[u8]) -> Result<Image, EmuError> {
+    if !data.starts_with(&SIGNATURE) {
+        return bad("missing signature");
+    }
+
+    let mut header = None;
+    let mut palette = Vec::new();
+    let mut compressed = Vec::new();
+    let mut pos = SIGNATURE.len();
+    while pos + 8 <= data.len() {
+        let len =
+            u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let kind = &data[pos + 4..pos + 8];
+        let body = match data.get(pos + 8..pos + 8 + len) {
+            Some(body) => body,
+            None => return bad("chunk runs past the end"),
+        };
+        match kind {
+            b"IHDR" if len == 13 => header = Some(body.to_vec()),
+            b"PLTE" if len.is_multiple_of(3) => {
+                palette = body.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect()
+            }
+            b"PLTE" => return bad("palette isn't whole colours"),
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {}
+        }
+        // skip the CRC too
+        pos += 12 + len;
+    }
+
+    let header = match header {
+        Some(h) => h,
+        None => return bad("no IHDR"),
+    };
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let (depth, colour) = (header[8] as usize, header[9]);
+    if header[12] != 0 {
+        return bad("interlaced images aren't supported");
+    }
+    let channels = match colour {
+        COLOUR_GREY | COLOUR_INDEXED => 1,
+        COLOUR_GREY_ALPHA => 2,
+        COLOUR_RGB => 3,
+        COLOUR_RGBA => 4,
+        _ => return bad("unknown colour type"),
+    };
+    if ![1, 2, 4, 8, 16].contains(&depth) {
+        return bad("unknown bit depth");
+    }
+    if width > MAX_SIDE || height > MAX_SIDE {
+        return bad("image is too big");
+    }
+
+    // undo the filter on each row, which works on whole bytes
+    let bits = depth * channels;
+    let stride = match width.checked_mul(bits) {
+        Some(row_bits) => row_bits.div_ceil(8),
+        None => return bad("image is too big"),
+    };
+    let bpp = bits.div_ceil(8);
+    let size = match height.checked_mul(stride + 1) {
+        Some(size) => size,
+        None => return bad("image is too big"),
+    };
+
+    let mut raw = Vec::new();
+    ZlibDecoder::new(&compressed[..])
+        .take(size as u64)
+        .read_to_end(&mut raw)
+        .map_err(|e| EmuError::Io(format!("bad PNG: {}", e)))?;
+    if raw.len() < size {
+        return bad("not enough image data");
+    }
+    let mut rows = vec![0u8; height * stride];
+    for y in 0..height {
+        let filter = raw[y * (stride + 1)];
+        let line = &raw[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+        let (done, rest) = rows.split_at_mut(y * stride);
+        let above = if y > 0 {
+            &done[(y - 1) * stride..]
+        } else {
+            &[][..]
+        };
+        let row = &mut rest[..stride];
+        for x in 0..stride {
+            let a = if x >= bpp { row[x - bpp] } else { 0 };
+            let b = above.get(x).cloned().unwrap_or(0);
+            let c = if x >= bpp {
+                above.get(x - bpp).cloned().unwrap_or(0)
+            } else {
+                0
+            };
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return bad("unknown filter"),
+            };
+            row[x] = line[x].wrapping_add(predicted);
+        }
+    }
+
+    // samples scaled to 8 bits, except palette indices
+    let sample = |row: &[u8], i: usize| -> u8 {
+        match depth {
+            16 => row[i * 2],
+            8 => row[i],
+            _ => {
+                let bit = i * depth;
+                let value = (row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1) as u8;
+                if colour == COLOUR_INDEXED {
+                    value
+                } else {
+                    (value as usize * 255 / ((1 << depth) - 1)) as u8
+                }
+            }
+        }
+    };
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in rows.chunks(stride.max(1)).take(height) {
+        for x in 0..width {
+            let i = x * channels;
+            pixels.push(match colour {
+                COLOUR_INDEXED => match palette.get(sample(row, i) as usize) {
+                    Some(&c) => c,
+                    None => return bad("palette index out of range"),
+                },
+                COLOUR_GREY | COLOUR_GREY_ALPHA => {
+                    let v = sample(row, i);
+                    [v, v, v]
+                }
+                _ => [sample(row, i), sample(row, i + 1), sample(row, i + 2)],
+            });
+        }
+    }
+
+    Ok(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+#[test]
+fn png_test() {
+    let png = encode_indexed(2, 1, &[[0, 0, 0], [255, 255, 255]], &[1, 0]);
+    assert!(png.starts_with(&SIGNATURE));
+    assert!(&png[12..16] == b"IHDR");
+    assert!(png[24..26] == [8, COLOUR_INDEXED]);
+    assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]));
+
+    // PLTE holds the two colours, then the one row with no filter
+    assert!(&png[37..41] == b"PLTE");
+    let idat = 41 + 6 + 4;
+    let len = u32::from_be_bytes([png[idat], png[idat + 1], png[idat + 2], png[idat + 3]]) as usize;
+    let mut rows = Vec::new();
+    ZlibDecoder::new(&png[idat + 8..idat + 8 + len])
+        .read_to_end(&mut rows)
+        .unwrap();
+    assert!(rows == [0, 1, 0]);
+
+    let image = decode(&png).unwrap();
+    assert!(image.width == 2 && image.height == 1);
+    assert!(image.pixels == [[255, 255, 255], [0, 0, 0]]);
+
+    let rgb = [[1, 2, 3], [4, 5, 6], [7, 8, 9], [10, 11, 12]];
+    assert!(decode(&encode_rgb(2, 2, &rgb)).unwrap().pixels == rgb);
+    assert!(decode(b"GIF89a").is_err());
+
+    // a palette with a colour cut short, then a header too big to allocate
+    let mut short = png.clone();
+    short[36] = 5;
+    assert!(decode(&short).is_err());
+    let mut huge = png.clone();
+    huge[16..24].copy_from_slice(&[0xFF; 8]);
+    match decode(&huge) {
+        Err(EmuError::Io(reason)) => assert!(reason.starts_with("bad PNG")),
+        r => panic!("expected a bad PNG error, got {:?}", r),
+    }
+}
@@ -0,0 +1,392 @@
+//! Finding where a game keeps a value, like lives or HP, by narrowing down
+//! RAM between snapshots.
+//!
+//! A search starts with every byte of work RAM, high RAM and cartridge RAM
+//! as a candidate. Each filter compares the values now against the last
+//! snapshot, or against a constant, and drops the candidates that don't
+//! match: lose a life, filter on "decreased by 1", and repeat until only a
+//! few are left.
+
+use std::fmt;
+use std::str::FromStr;
+
+use gb_error::EmuError;
+use gb_mem::RamRegion;
+use gb_system::GameBoy;
+
+const WRAM_BANK_SIZE: usize = 0x1000;
+
+/// How values are laid out in memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueSize {
+    U8,
+    U16Le,
+    U16Be,
+}
+
+impl ValueSize {
+    fn bytes(self) -> usize {
+        match self {
+            ValueSize::U8 => 1,
+            ValueSize::U16Le | ValueSize::U16Be => 2,
+        }
+    }
+
+    fn read(self, ram: &[u8], offset: usize) -> Option<u16> {
+        let b = ram.get(offset..offset + self.bytes())?;
+        Some(match self {
+            ValueSize::U8 => b[0] as u16,
+            ValueSize::U16Le => u16::from_le_bytes([b[0], b[1]]),
+            ValueSize::U16Be => u16::from_be_bytes([b[0], b[1]]),
+        })
+    }
+
+    /// The biggest value that fits.
+    pub fn max(self) -> u16 {
+        match self {
+            ValueSize::U8 => 0xFF,
+            ValueSize::U16Le | ValueSize::U16Be => 0xFFFF,
+        }
+    }
+}
+
+impl FromStr for ValueSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "u8" | "8" => Ok(ValueSize::U8),
+            "u16" | "u16le" | "16" => Ok(ValueSize::U16Le),
+            "u16be" => Ok(ValueSize::U16Be),
+            _ => Err(format!("unknown size {}, expected u8, u16le or u16be", s)),
+        }
+    }
+}
+
+impl fmt::Display for ValueSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ValueSize::U8 => "u8",
+            ValueSize::U16Le => "u16le",
+            ValueSize::U16Be => "u16be",
+        })
+    }
+}
+
+/// What a value is compared with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    /// Its value at the last snapshot.
+    Previous,
+    Value(u16),
+}
+
+/// A test for which candidates to keep. Differences wrap around, so a
+/// byte going from 0 to 255 has decreased by 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Equal(Operand),
+    NotEqual(Operand),
+    Greater(Operand),
+    Less(Operand),
+    IncreasedBy(u16),
+    DecreasedBy(u16),
+}
+
+impl Filter {
+    fn matches(self, now: u16, previous: u16, size: ValueSize) -> bool {
+        let operand = |o| match o {
+            Operand::Previous => previous,
+            Operand::Value(v) => v,
+        };
+        let wrap = |v: u16| v & size.max();
+        match self {
+            Filter::Equal(o) => now == operand(o),
+            Filter::NotEqual(o) => now != operand(o),
+            Filter::Greater(o) => now > operand(o),
+            Filter::Less(o) => now < operand(o),
+            Filter::IncreasedBy(n) => now == wrap(previous.wrapping_add(n)),
+            Filter::DecreasedBy(n) => now == wrap(previous.wrapping_sub(n)),
+        }
+    }
+}
+
+/// Filters as typed: `=`, `!=`, `>` or `<` on their own compare with the
+/// last snapshot and with a number after them compare with that, `+n` and
+/// `-n` are changes by n, and `changed`, `unchanged`, `increased` and
+/// `decreased` are the obvious ones. Numbers are decimal or `0x` hex.
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let number = |n: &str| {
+            let n = n.trim();
+            let parsed = match n.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => n.parse(),
+            };
+            parsed.map_err(|_| format!("{} isn't a 16-bit number", n))
+        };
+        let operand = |n: &str| -> Result<Operand, String> {
+            if n.trim().is_empty() {
+                Ok(Operand::Previous)
+            } else {
+                Ok(Operand::Value(number(n)?))
+            }
+        };
+
+        match s {
+            "changed" => return Ok(Filter::NotEqual(Operand::Previous)),
+            "unchanged" => return Ok(Filter::Equal(Operand::Previous)),
+            "increased" => return Ok(Filter::Greater(Operand::Previous)),
+            "decreased" => return Ok(Filter::Less(Operand::Previous)),
+            _ => {}
+        }
+        if let Some(rest) = s.strip_prefix("!=") {
+            Ok(Filter::NotEqual(operand(rest)?))
+        } else if let Some(rest) = s.strip_prefix('=') {
+            Ok(Filter::Equal(operand(rest)?))
+        } else if let Some(rest) = s.strip_prefix('>') {
+            Ok(Filter::Greater(operand(rest)?))
+        } else if let Some(rest) = s.strip_prefix('<') {
+            Ok(Filter::Less(operand(rest)?))
+        } else if let Some(rest) = s.strip_prefix('+') {
+            Ok(Filter::IncreasedBy(number(rest)?))
+        } else if let Some(rest) = s.strip_prefix('-') {
+            Ok(Filter::DecreasedBy(number(rest)?))
+        } else {
+            Err(format!(
+                "unknown filter {}, expected =, !=, >, <, +n, -n, changed or unchanged",
+                s
+            ))
+        }
+    }
+}
+
+/// One address still in the running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    pub region: RamRegion,
+    /// Bytes into the region, counting every bank.
+    pub offset: usize,
+    /// The bank that has to be switched in, 0 where there's no choice.
+    pub bank: usize,
+    pub addr: u16,
+    pub value: u16,
+    pub previous: u16,
+}
+
+/// A search in progress.
+pub struct RamSearch {
+    size: ValueSize,
+    // each region as it was at the last snapshot
+    snapshots: Vec<(RamRegion, Vec<u8>)>,
+    // (index into snapshots, offset) of everything still matching
+    candidates: Vec<(usize, usize)>,
+}
+
+impl RamSearch {
+    /// Starts a search with every address a candidate, taking the first
+    /// snapshot.
+    pub fn new(gb: &GameBoy, size: ValueSize) -> Self {
+        let snapshots: Vec<_> = RamRegion::ALL
+            .iter()
+            .map(|&region| (region, gb.ram(region).to_vec()))
+            .collect();
+        let mut candidates = Vec::new();
+        for (i, &(region, ref ram)) in snapshots.iter().enumerate() {
+            let end = (ram.len() + 1).saturating_sub(size.bytes());
+            // a value can't run off the end of one bank into the next
+            let bank = region.bank_size();
+            candidates.extend(
+                (0..end)
+                    .filter(|offset| offset % bank + size.bytes() <= bank)
+                    .map(|offset| (i, offset)),
+            );
+        }
+        RamSearch {
+            size,
+            snapshots,
+            candidates,
+        }
+    }
+
+    pub fn size(&self) -> ValueSize {
+        self.size
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    /// Keeps the candidates that pass `filter` against how RAM is now, then
+    /// takes a new snapshot. Returns how many are left.
+    pub fn filter(&mut self, gb: &GameBoy, filter: Filter) -> usize {
+        let size = self.size;
+        let snapshots = &self.snapshots;
+        self.candidates.retain(|&(i, offset)| {
+            let (region, ref before) = snapshots[i];
+            match (size.read(gb.ram(region), offset), size.read(before, offset)) {
+                (Some(now), Some(previous)) => filter.matches(now, previous, size),
+                _ => false,
+            }
+        });
+        self.snapshot(gb);
+        self.candidates.len()
+    }
+
+    /// Takes a new snapshot to compare the next filter against, without
+    /// dropping anything.
+    pub fn snapshot(&mut self, gb: &GameBoy) {
+        for &mut (region, ref mut ram) in self.snapshots.iter_mut() {
+            ram.clear();
+            ram.extend_from_slice(gb.ram(region));
+        }
+    }
+
+    /// The candidates left, with the values at the last two snapshots.
+    pub fn candidates(&self, gb: &GameBoy) -> Vec<Candidate> {
+        self.candidates
+            .iter()
+            .map(|&(i, offset)| {
+                let (region, ref before) = self.snapshots[i];
+                let (bank, addr) = region.address(offset);
+                Candidate {
+                    region,
+                    offset,
+                    bank,
+                    addr,
+                    value: self.size.read(gb.ram(region), offset).unwrap_or(0),
+                    previous: self.size.read(before, offset).unwrap_or(0),
+                }
+            })
+            .collect()
+    }
+
+    /// The candidates as a watch list, a line each of bank, address, size
+    /// and current value, like `01:D157 u16le 1234`.
+    pub fn watch_list(&self, gb: &GameBoy) -> String {
+        self.candidates(gb)
+            .iter()
+            .map(|c| format!("{:02X}:{:04X} {} {}\n", c.bank, c.addr, self.size, c.value))
+            .collect()
+    }
+
+    /// GameShark codes that hold a candidate at `value`, one per byte.
+    /// Fails if `value` doesn't fit the search's size.
+    pub fn game_shark(
+        &self,
+        gb: &GameBoy,
+        candidate: &Candidate,
+        value: u16,
+    ) -> Result<Vec<String>, EmuError> {
+        if value > self.size.max() {
+            return Err(EmuError::Cheat(format!(
+                "{} doesn't fit in {}",
+                value, self.size
+            )));
+        }
+        let code_type = match candidate.region {
+            // switchable banks only need naming when there's more than one
+            RamRegion::WorkRam
+                if candidate.bank > 0 && gb.ram(RamRegion::WorkRam).len() > 2 * WRAM_BANK_SIZE =>
+            {
+                0x90 | candidate.bank as u8
+            }
+            RamRegion::CartRam if candidate.bank > 0 => {
+                return Err(EmuError::Cheat(format!(
+                    "{:02X}:{:04X}: GameShark codes can't reach cartridge RAM bank {}",
+                    candidate.bank, candidate.addr, candidate.bank
+                )));
+            }
+            _ => 0x01,
+        };
+
+        let bytes = match self.size {
+            ValueSize::U8 => vec![value as u8],
+            ValueSize::U16Le => value.to_le_bytes().to_vec(),
+            ValueSize::U16Be => value.to_be_bytes().to_vec(),
+        };
+        Ok(bytes
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                let addr = candidate.addr + i as u16;
+                format!(
+                    "{:02X}{:02X}{:02X}{:02X}",
+                    code_type,
+                    b,
+                    addr & 0xFF,
+                    addr >> 8
+                )
+            })
+            .collect())
+    }
+}
+
+#[test]
+fn filter_parse_test() {
+    let parse = |s: &str| s.parse::<Filter>().unwrap();
+    assert!(parse("=") == Filter::Equal(Operand::Previous));
+    assert!(parse("!= 3") == Filter::NotEqual(Operand::Value(3)));
+    assert!(parse(">0x10") == Filter::Greater(Operand::Value(0x10)));
+    assert!(parse("+1") == Filter::IncreasedBy(1));
+    assert!(parse("-2") == Filter::DecreasedBy(2));
+    assert!(parse("changed") == Filter::NotEqual(Operand::Previous));
+    assert!("+".parse::<Filter>().is_err());
+    assert!("=70000".parse::<Filter>().is_err());
+    assert!("~".parse::<Filter>().is_err());
+
+    assert!(Filter::DecreasedBy(1).matches(0xFF, 0x00, ValueSize::U8));
+    assert!(!Filter::DecreasedBy(1).matches(0xFF, 0x00, ValueSize::U16Le));
+}
+
+#[test]
+fn ram_search_test() {
+    use gb_system::test_rom;
+
+    // count down from 0x0340 in big endian at $C010, a step each V-blank:
+    // LD HL,$C010; LD [HL],$03; INC HL; LD [HL],$40; LD A,$01; LDH [$FF],A;
+    // XOR A; LDH [$0F],A; HALT; DEC [HL]; JR -7
+    let program = [
+        0x21, 0x10, 0xC0, 0x36, 0x03, 0x23, 0x36, 0x40, 0x3E, 0x01, 0xE0, 0xFF, 0xAF, 0xE0, 0x0F,
+        0x76, 0x35, 0x18, 0xF9,
+    ];
+    let mut gb = GameBoy::from_bytes(test_rom(&program)).unwrap();
+    gb.run_frame().unwrap();
+
+    // no 16-bit value straddles two banks
+    let search = RamSearch::new(&gb, ValueSize::U16Le);
+    assert!(search
+        .candidates(&gb)
+        .iter()
+        .all(|c| c.offset % c.region.bank_size() != c.region.bank_size() - 1));
+    // one fewer than the bytes in each of two WRAM banks and high RAM
+    assert!(RamSearch::new(&gb, ValueSize::U8).len() == search.len() + 3);
+
+    let mut search = RamSearch::new(&gb, ValueSize::U16Be);
+    let all = search.len();
+    gb.run_frame().unwrap();
+    assert!(search.filter(&gb, Filter::DecreasedBy(1)) < all);
+    gb.run_frame().unwrap();
+    search.filter(&gb, "-1".parse().unwrap());
+    search.snapshot(&gb);
+    search.filter(&gb, Filter::Equal(Operand::Previous));
+
+    let found = search.candidates(&gb);
+    assert!(found.len() == 1);
+    let c = found[0];
+    assert!(c.region == RamRegion::WorkRam && c.addr == 0xC010);
+    assert!(search.watch_list(&gb) == format!("00:C010 u16be {}\n", c.value));
+    assert!(search.game_shark(&gb, &c, 0x0999).unwrap() == vec!["010910C0", "019911C0"]);
+
+    let search = RamSearch::new(&gb, ValueSize::U8);
+    let c = search.candidates(&gb)[0];
+    assert!(search.game_shark(&gb, &c, 0xFF).is_ok());
+    assert!(search.game_shark(&gb, &c, 0x100).is_err());
+}
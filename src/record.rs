@@ -0,0 +1,507 @@
+//! Records runs to video: uncompressed Y4M to pipe into an encoder, or an
+//! animated GIF. The audio can go alongside as a WAV.
+//!
+//! Call `Recorder::frame` whenever the emulator finishes a frame and
+//! `finish` at the end; nothing is complete on disk until then.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use gb_error::EmuError;
+use gb_ppu::rgb888;
+use gb_system::{GameBoy, AUDIO_SAMPLE_RATE, CYCLES_PER_FRAME};
+use runloop::CPU_HZ;
+use screenshot::{Palette, Screenshot};
+
+// GIF delays are in hundredths of a second
+const GIF_TICKS_PER_SECOND: u64 = 100;
+const LZW_MAX_CODE: u16 = 4095;
+
+fn io_error(path: &Path) -> impl Fn(std::io::Error) -> EmuError + '_ {
+    move |e| EmuError::Io(format!("{}: {}", path.display(), e))
+}
+
+/// How to record.
+#[derive(Debug, Clone)]
+pub struct RecordOptions {
+    /// Frames to drop after each one kept. GIF players slow down delays
+    /// under 2/100ths of a second, so GIFs want at least 1.
+    pub frame_skip: u32,
+    pub scale: usize,
+    /// See `Screenshot::capture`.
+    pub palette: Option<Palette>,
+}
+
+impl Default for RecordOptions {
+    fn default() -> Self {
+        RecordOptions {
+            frame_skip: 0,
+            scale: 1,
+            palette: None,
+        }
+    }
+}
+
+enum Video {
+    Y4m(BufWriter<File>),
+    Gif(Gif<BufWriter<File>>),
+}
+
+/// Writes frames to a video file, and optionally audio to a WAV.
+pub struct Recorder {
+    video: Video,
+    audio: Option<Wav>,
+    options: RecordOptions,
+    // frames seen, kept or not
+    frames: u64,
+    // time of the last kept frame, in GIF ticks
+    ticks: u64,
+}
+
+impl Recorder {
+    /// Starts recording to `path`, a GIF if it ends in .gif and Y4M
+    /// otherwise. `audio` is where to put a WAV of the sound.
+    pub fn create(
+        path: &Path,
+        audio: Option<&Path>,
+        options: RecordOptions,
+    ) -> Result<Self, EmuError> {
+        let file = BufWriter::new(File::create(path).map_err(io_error(path))?);
+        let gif = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("gif"));
+        let video = if gif {
+            Video::Gif(Gif::new(file))
+        } else {
+            Video::Y4m(file)
+        };
+        let audio = match audio {
+            Some(path) => Some(Wav::create(path)?),
+            None => None,
+        };
+        Ok(Recorder {
+            video,
+            audio,
+            options,
+            frames: 0,
+            ticks: 0,
+        })
+    }
+
+    /// Adds the frame `gb` has just finished, along with the sound
+    /// generated while it ran.
+    pub fn frame(&mut self, gb: &mut GameBoy) -> Result<(), EmuError> {
+        self.frames += 1;
+        if let Some(ref mut wav) = self.audio {
+            wav.write(&gb.take_audio_samples(), self.frames)?;
+        }
+        if !(self.frames - 1).is_multiple_of(self.options.frame_skip as u64 + 1) {
+            return Ok(());
+        }
+
+        let screen =
+            Screenshot::capture(gb, self.options.palette.as_ref()).scaled(self.options.scale);
+        let skip = self.options.frame_skip as u64 + 1;
+        match self.video {
+            Video::Y4m(ref mut out) => {
+                if self.frames == 1 {
+                    writeln!(
+                        out,
+                        "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444 XCOLORRANGE=FULL",
+                        screen.width(),
+                        screen.height(),
+                        CPU_HZ,
+                        CYCLES_PER_FRAME * skip
+                    )
+                    .map_err(|e| EmuError::Io(format!("writing video: {}", e)))?;
+                }
+                write_y4m_frame(out, &screen)
+                    .map_err(|e| EmuError::Io(format!("writing video: {}", e)))?;
+            }
+            Video::Gif(ref mut gif) => {
+                // round each frame's end time so the delays don't drift
+                let frame = (self.frames - 1) / skip + 1;
+                let end = frame * skip * CYCLES_PER_FRAME * GIF_TICKS_PER_SECOND;
+                let end = (end + CPU_HZ / 2) / CPU_HZ;
+                gif.frame(&screen, (end - self.ticks) as u16)
+                    .map_err(|e| EmuError::Io(format!("writing video: {}", e)))?;
+                self.ticks = end;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out anything held back and closes the files.
+    pub fn finish(self) -> Result<(), EmuError> {
+        let done = match self.video {
+            Video::Y4m(mut out) => out.flush(),
+            Video::Gif(gif) => gif.finish().and_then(|mut out| out.flush()),
+        };
+        done.map_err(|e| EmuError::Io(format!("writing video: {}", e)))?;
+        match self.audio {
+            Some(wav) => wav.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Full range BT.601 with no chroma subsampling, so colours stay exact enough
+fn write_y4m_frame<W: Write>(out: &mut W, screen: &Screenshot) -> std::io::Result<()> {
+    let rgb: Vec<[i32; 3]> = screen
+        .pixels()
+        .iter()
+        .map(|&p| {
+            let [r, g, b] = rgb888(p);
+            [r as i32, g as i32, b as i32]
+        })
+        .collect();
+    let plane = |f: &dyn Fn(i32, i32, i32) -> i32| -> Vec<u8> {
+        rgb.iter()
+            .map(|&[r, g, b]| f(r, g, b).clamp(0, 255) as u8)
+            .collect()
+    };
+    out.write_all(b"FRAME\n")?;
+    out.write_all(&plane(&|r, g, b| (77 * r + 150 * g + 29 * b + 128) >> 8))?;
+    out.write_all(&plane(&|r, g, b| {
+        ((-43 * r - 85 * g + 128 * b + 128) >> 8) + 128
+    }))?;
+    out.write_all(&plane(&|r, g, b| {
+        ((128 * r - 107 * g - 21 * b + 128) >> 8) + 128
+    }))
+}
+
+// An animated GIF written a frame behind, so runs of identical frames can
+// become one long one
+struct Gif<W: Write> {
+    out: W,
+    started: bool,
+    global: Vec<u16>,
+    // what the viewer shows once the written frames have played
+    shown: Vec<u16>,
+    pending: Option<(Vec<u16>, u16)>,
+    width: usize,
+    height: usize,
+}
+
+impl<W: Write> Gif<W> {
+    fn new(out: W) -> Self {
+        Gif {
+            out,
+            started: false,
+            global: Vec::new(),
+            shown: Vec::new(),
+            pending: None,
+            width: 0,
+            height: 0,
+        }
+    }
+
+    fn frame(&mut self, screen: &Screenshot, delay: u16) -> std::io::Result<()> {
+        if !self.started {
+            self.width = screen.width();
+            self.height = screen.height();
+            self.global = palette_of(screen.pixels());
+            self.start()?;
+        }
+
+        if let Some((ref pixels, ref mut held)) = self.pending {
+            if pixels[..] == *screen.pixels() {
+                *held = held.saturating_add(delay);
+                return Ok(());
+            }
+        }
+        self.flush_pending()?;
+        self.pending = Some((screen.pixels().to_vec(), delay));
+        Ok(())
+    }
+
+    fn start(&mut self) -> std::io::Result<()> {
+        self.started = true;
+        let bits = table_bits(self.global.len());
+        self.out.write_all(b"GIF89a")?;
+        self.out.write_all(&(self.width as u16).to_le_bytes())?;
+        self.out.write_all(&(self.height as u16).to_le_bytes())?;
+        // global table, 8 bits a channel, no background or aspect ratio
+        self.out.write_all(&[0x80 | 0x70 | (bits - 1), 0, 0])?;
+        write_table(&mut self.out, &self.global, bits)?;
+        // loop forever
+        self.out
+            .write_all(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00")
+    }
+
+    // Writes the held frame, only the rectangle that changed
+    fn flush_pending(&mut self) -> std::io::Result<()> {
+        let (pixels, delay) = match self.pending.take() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        let (mut left, mut top, mut right, mut bottom) = (self.width, self.height, 0, 0);
+        for (i, &p) in pixels.iter().enumerate() {
+            if self.shown.get(i) != Some(&p) {
+                let (x, y) = (i % self.width, i / self.width);
+                left = left.min(x);
+                right = right.max(x + 1);
+                top = top.min(y);
+                bottom = bottom.max(y + 1);
+            }
+        }
+        // nothing changed, but the delay still has to go somewhere
+        if right == 0 {
+            left = 0;
+            top = 0;
+            right = 1;
+            bottom = 1;
+        }
+
+        let mut area = Vec::with_capacity((right - left) * (bottom - top));
+        for y in top..bottom {
+            area.extend_from_slice(&pixels[y * self.width + left..y * self.width + right]);
+        }
+
+        // the global table does unless the frame brings new colours
+        let used = palette_of(&area);
+        let local = if used.iter().all(|c| self.global.contains(c)) {
+            None
+        } else {
+            Some(used)
+        };
+        let table = local.as_ref().unwrap_or(&self.global);
+        let indices: Vec<u8> = area.iter().map(|&c| nearest(table, c)).collect();
+
+        // graphic control: leave the frame in place, then wait
+        self.out.write_all(&[0x21, 0xF9, 0x04, 0x04])?;
+        self.out.write_all(&delay.to_le_bytes())?;
+        self.out.write_all(&[0x00, 0x00])?;
+
+        self.out.write_all(&[0x2C])?;
+        for v in [left, top, right - left, bottom - top].iter() {
+            self.out.write_all(&(*v as u16).to_le_bytes())?;
+        }
+        let bits = table_bits(table.len());
+        match local {
+            Some(ref local) => {
+                self.out.write_all(&[0x80 | (bits - 1)])?;
+                write_table(&mut self.out, local, bits)?;
+            }
+            None => self.out.write_all(&[0])?,
+        }
+
+        let min_size = bits.max(2);
+        self.out.write_all(&[min_size])?;
+        for block in lzw(&indices, min_size).chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0])?;
+
+        self.shown = pixels;
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<W> {
+        self.flush_pending()?;
+        if self.started {
+            self.out.write_all(&[0x3B])?;
+        }
+        Ok(self.out)
+    }
+}
+
+// The distinct colours in `pixels`, most used first so the common ones keep
+// their place if there are too many. GIF tables stop at 256.
+fn palette_of(pixels: &[u16]) -> Vec<u16> {
+    let mut counts: HashMap<u16, usize> = HashMap::new();
+    for &p in pixels {
+        *counts.entry(p).or_insert(0) += 1;
+    }
+    let mut colours: Vec<(u16, usize)> = counts.into_iter().collect();
+    colours.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    colours.truncate(256);
+    colours.into_iter().map(|(c, _)| c).collect()
+}
+
+fn nearest(table: &[u16], colour: u16) -> u8 {
+    if let Some(i) = table.iter().position(|&c| c == colour) {
+        return i as u8;
+    }
+    let distance = |c: u16| -> i32 {
+        let (a, b) = (rgb888(c), rgb888(colour));
+        (0..3).map(|i| (a[i] as i32 - b[i] as i32).pow(2)).sum()
+    };
+    (0..table.len())
+        .min_by_key(|&i| distance(table[i]))
+        .unwrap_or(0) as u8
+}
+
+// Tables hold a power of two colours, at least 2
+fn table_bits(colours: usize) -> u8 {
+    let mut bits = 1;
+    while (1 << bits) < colours {
+        bits += 1;
+    }
+    bits
+}
+
+fn write_table<W: Write>(out: &mut W, colours: &[u16], bits: u8) -> std::io::Result<()> {
+    for i in 0..1usize << bits {
+        out.write_all(&colours.get(i).map_or([0; 3], |&c| rgb888(c)))?;
+    }
+    Ok(())
+}
+
+// Codes go out least significant bit first
+struct Bits {
+    bytes: Vec<u8>,
+    acc: u32,
+    count: u8,
+}
+
+impl Bits {
+    fn write(&mut self, code: u16, width: u8) {
+        self.acc |= (code as u32) << self.count;
+        self.count += width;
+        while self.count >= 8 {
+            self.bytes.push(self.acc as u8);
+            self.acc >>= 8;
+            self.count -= 8;
+        }
+    }
+}
+
+fn lzw(indices: &[u8], min_size: u8) -> Vec<u8> {
+    let clear = 1u16 << min_size;
+    let end = clear + 1;
+    let mut bits = Bits {
+        bytes: Vec::new(),
+        acc: 0,
+        count: 0,
+    };
+    let mut codes: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut width = min_size + 1;
+    let mut last = end;
+
+    bits.write(clear, width);
+    let mut prefix = match indices.first() {
+        Some(&i) => i as u16,
+        None => {
+            bits.write(end, width);
+            return bits.bytes;
+        }
+    };
+    for &i in &indices[1..] {
+        if let Some(&code) = codes.get(&(prefix, i)) {
+            prefix = code;
+            continue;
+        }
+        bits.write(prefix, width);
+        last += 1;
+        codes.insert((prefix, i), last);
+        if last >= 1 << width {
+            width += 1;
+        }
+        // a full table starts over
+        if last == LZW_MAX_CODE {
+            bits.write(clear, width);
+            codes.clear();
+            width = min_size + 1;
+            last = end;
+        }
+        prefix = i as u16;
+    }
+    bits.write(prefix, width);
+    bits.write(end, width);
+    if bits.count > 0 {
+        bits.bytes.push(bits.acc as u8);
+    }
+    bits.bytes
+}
+
+// 16-bit stereo PCM. The sizes in the header are filled in at the end.
+struct Wav {
+    out: BufWriter<File>,
+    path: std::path::PathBuf,
+    samples: u64,
+}
+
+impl Wav {
+    fn create(path: &Path) -> Result<Self, EmuError> {
+        let mut out = BufWriter::new(File::create(path).map_err(io_error(path))?);
+        let channels = 2u16;
+        let mut header = Vec::with_capacity(44);
+        header.extend_from_slice(b"RIFF\0\0\0\0WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&AUDIO_SAMPLE_RATE.to_le_bytes());
+        header.extend_from_slice(&(AUDIO_SAMPLE_RATE * 4).to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data\0\0\0\0");
+        out.write_all(&header).map_err(io_error(path))?;
+        Ok(Wav {
+            out,
+            path: path.to_path_buf(),
+            samples: 0,
+        })
+    }
+
+    // Adds `samples`, padded with silence if the emulator made fewer than
+    // `frames` frames' worth so the sound stays in step with the video
+    fn write(&mut self, samples: &[i16], frames: u64) -> Result<(), EmuError> {
+        let due = frames * CYCLES_PER_FRAME * AUDIO_SAMPLE_RATE as u64 / CPU_HZ * 2;
+        let silence = due.saturating_sub(self.samples + samples.len() as u64) as usize;
+        let bytes: Vec<u8> = samples
+            .iter()
+            .chain(std::iter::repeat_n(&0, silence))
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        self.out.write_all(&bytes).map_err(io_error(&self.path))?;
+        self.samples += (samples.len() + silence) as u64;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), EmuError> {
+        let data = (self.samples * 2) as u32;
+        let path = self.path.clone();
+        let fix = |out: &mut BufWriter<File>| -> std::io::Result<()> {
+            out.seek(SeekFrom::Start(4))?;
+            out.write_all(&(data + 36).to_le_bytes())?;
+            out.seek(SeekFrom::Start(40))?;
+            out.write_all(&data.to_le_bytes())?;
+            out.flush()
+        };
+        fix(&mut self.out).map_err(io_error(&path))
+    }
+}
+
+#[test]
+fn record_test() {
+    use gb_system::test_rom;
+
+    // clear, 0, 1, then "0 1" is code 6, and adding code 8 widens to 4 bits
+    let mut expected = Bits {
+        bytes: Vec::new(),
+        acc: 0,
+        count: 0,
+    };
+    for &(code, width) in [(4, 3), (0, 3), (1, 3), (6, 3), (6, 4), (5, 4)].iter() {
+        expected.write(code, width);
+    }
+    expected.bytes.push(expected.acc as u8);
+    assert!(lzw(&[0, 1, 0, 1, 0, 1], 2) == expected.bytes);
+
+    // identical frames become one longer one
+    let mut gb = GameBoy::from_bytes(test_rom(&[0x18, 0xFE])).unwrap();
+    gb.run_frame().unwrap();
+    let screen = Screenshot::capture(&gb, None);
+    let mut gif = Gif::new(Vec::new());
+    gif.frame(&screen, 2).unwrap();
+    gif.frame(&screen, 2).unwrap();
+    assert!(gif.pending.as_ref().unwrap().1 == 4);
+    let data = gif.finish().unwrap();
+    assert!(data.starts_with(b"GIF89a\xA0\x00\x90\x00"));
+    assert!(data.ends_with(&[0x3B]));
+}
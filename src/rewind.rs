@@ -0,0 +1,300 @@
+//! Running a game backwards.
+//!
+//! Every few frames the whole machine state is saved into a ring buffer
+//! with a memory limit. Only the newest snapshot is kept whole; each older
+//! one is stored as the XOR of it against the next one, run length encoded.
+//! Most of RAM doesn't change from one snapshot to the next, so the deltas
+//! are mostly runs of zeros and come out small. The oldest snapshots are
+//! dropped to stay under the limit.
+//!
+//! The buttons for every frame are kept too, so stepping back to a frame
+//! between snapshots loads the one before it and plays the frames since.
+
+use std::collections::VecDeque;
+
+use gb_error::EmuError;
+use gb_system::GameBoy;
+
+/// Frames between snapshots unless asked otherwise.
+pub const DEFAULT_INTERVAL: u32 = 4;
+
+/// Memory the buffer may use unless asked otherwise.
+pub const DEFAULT_BUDGET: usize = 16 * 1024 * 1024;
+
+// Turns `old` into the XOR of it against `new`, as runs of unchanged bytes
+// and literals. Each run is a varint count of bytes that stayed the same,
+// then a varint count of changed bytes and their XORs. The length of `old`
+// comes first, and anything past the end of the shorter one counts as 0.
+fn delta(new: &[u8], old: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    put_varint(&mut out, old.len());
+
+    let xor = |i: usize| old.get(i).cloned().unwrap_or(0) ^ new.get(i).cloned().unwrap_or(0);
+    let mut i = 0;
+    while i < old.len() {
+        let start = i;
+        while i < old.len() && xor(i) == 0 {
+            i += 1;
+        }
+        put_varint(&mut out, i - start);
+
+        let start = i;
+        // a short run of zeros costs more to end the literal for than to
+        // carry along in it
+        while i < old.len()
+            && (xor(i) != 0 || (i + 2 < old.len() && (xor(i + 1) | xor(i + 2)) != 0))
+        {
+            i += 1;
+        }
+        put_varint(&mut out, i - start);
+        out.extend((start..i).map(xor));
+    }
+    out
+}
+
+// Rebuilds the older state from `new` and a `delta` made against it
+fn undelta(new: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 0;
+    let len = get_varint(delta, &mut pos)?;
+    let mut old: Vec<u8> = (0..len).map(|i| new.get(i).cloned().unwrap_or(0)).collect();
+
+    let mut i = 0;
+    while i < len {
+        i += get_varint(delta, &mut pos)?;
+        let changed = get_varint(delta, &mut pos)?;
+        let xors = delta.get(pos..pos + changed)?;
+        for (b, x) in old.get_mut(i..i + changed)?.iter_mut().zip(xors) {
+            *b ^= x;
+        }
+        pos += changed;
+        i += changed;
+    }
+    Some(old)
+}
+
+fn put_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push(val as u8 | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn get_varint(data: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let b = *data.get(*pos)?;
+        *pos += 1;
+        val |= ((b & 0x7F) as usize).checked_shl(shift)?;
+        if b & 0x80 == 0 {
+            return Some(val);
+        }
+        shift += 7;
+    }
+}
+
+/// Snapshots of where a game has been, to go back through.
+pub struct Rewind {
+    interval: u32,
+    budget: usize,
+
+    // frames recorded since the start, which is where the machine is now
+    frame: u64,
+    // the newest snapshot, whole, and the frame it was taken after
+    latest: Option<(u64, Vec<u8>)>,
+    // older snapshots, oldest first, each a delta against the one after it
+    older: VecDeque<(u64, Vec<u8>)>,
+    // buttons held during each frame since the oldest snapshot
+    inputs: VecDeque<u8>,
+    // bytes in `older`
+    used: usize,
+}
+
+impl Rewind {
+    /// Takes a snapshot every `interval` frames, using no more than about
+    /// `budget` bytes for them.
+    pub fn new(interval: u32, budget: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frame: 0,
+            latest: None,
+            older: VecDeque::new(),
+            inputs: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    /// Memory in use, roughly.
+    pub fn memory(&self) -> usize {
+        let latest = self.latest.as_ref().map_or(0, |l| l.1.len());
+        latest + self.used + self.inputs.len()
+    }
+
+    // The frame of the oldest snapshot
+    fn oldest(&self) -> Option<u64> {
+        self.older
+            .front()
+            .or(self.latest.as_ref())
+            .map(|&(frame, _)| frame)
+    }
+
+    /// Whether there's anywhere to go back to.
+    pub fn can_step_back(&self) -> bool {
+        self.oldest().is_some_and(|oldest| oldest < self.frame)
+    }
+
+    /// Adds the frame `gb` has just run, with the buttons it held, and
+    /// takes a snapshot if one is due.
+    pub fn record_frame(&mut self, gb: &GameBoy) {
+        self.frame += 1;
+        if self.latest.is_some() {
+            self.inputs.push_back(gb.buttons());
+        }
+        if self.latest.is_none() || self.frame.is_multiple_of(self.interval as u64) {
+            self.snapshot(gb.save_state());
+        }
+    }
+
+    fn snapshot(&mut self, state: Vec<u8>) {
+        if let Some((frame, old)) = self.latest.take() {
+            let delta = delta(&state, &old);
+            self.used += delta.len();
+            self.older.push_back((frame, delta));
+        }
+        self.latest = Some((self.frame, state));
+
+        while self.memory() > self.budget {
+            let (frame, delta) = match self.older.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            self.used -= delta.len();
+            // keep the buttons from the new oldest snapshot on
+            let next = self.oldest().unwrap_or(frame);
+            let dropped = (next - frame) as usize;
+            self.inputs.drain(..dropped.min(self.inputs.len()));
+        }
+    }
+
+    /// Puts `gb` back a frame. Returns false, leaving it alone, if there's
+    /// nothing left to go back to. Recording carries on from the earlier
+    /// frame, forgetting what came after.
+    pub fn step_back(&mut self, gb: &mut GameBoy) -> Result<bool, EmuError> {
+        if !self.can_step_back() {
+            return Ok(false);
+        }
+        let target = self.frame - 1;
+
+        // undo snapshots until the newest is at or before the target
+        while self
+            .latest
+            .as_ref()
+            .is_some_and(|&(frame, _)| frame > target)
+        {
+            let (_, newer) = self.latest.take().unwrap_or_default();
+            let (frame, delta) = match self.older.pop_back() {
+                Some(older) => older,
+                None => return Ok(false),
+            };
+            self.used -= delta.len();
+            let state = undelta(&newer, &delta)
+                .ok_or_else(|| EmuError::StateLoad(String::from("rewind snapshot is corrupt")))?;
+            self.latest = Some((frame, state));
+        }
+
+        let (frame, state) = match self.latest {
+            Some((frame, ref state)) => (frame, state),
+            None => return Ok(false),
+        };
+        gb.load_state(state)?;
+
+        // play the frames from the snapshot up to the target again
+        let first = (frame - self.oldest().unwrap_or(frame)) as usize;
+        let replay: Vec<u8> = self
+            .inputs
+            .iter()
+            .skip(first)
+            .take((target - frame) as usize)
+            .cloned()
+            .collect();
+        for bits in replay {
+            gb.set_buttons(bits);
+            gb.run_frame()?;
+        }
+
+        self.frame = target;
+        let kept = first + (target - frame) as usize;
+        self.inputs.truncate(kept);
+        Ok(true)
+    }
+}
+
+#[test]
+fn delta_test() {
+    let old: Vec<u8> = (0..1000).map(|i| (i / 7) as u8).collect();
+    let mut new = old.clone();
+    new[3] ^= 1;
+    new[500] = 0xAA;
+    new[501] = 0xBB;
+    new[999] = 0;
+    let d = delta(&new, &old);
+    assert!(d.len() < 32);
+    assert!(undelta(&new, &d).unwrap() == old);
+
+    // states of different sizes
+    let d = delta(&new[..600], &old);
+    assert!(undelta(&new[..600], &d).unwrap() == old);
+    let d = delta(&new, &old[..10]);
+    assert!(undelta(&new, &d).unwrap() == old[..10]);
+    assert!(undelta(&new, &d[..d.len() - 1]).is_none());
+}
+
+#[test]
+fn rewind_test() {
+    use gb_joypad::Button;
+    use gb_system::joypad_test_rom;
+
+    let mut gb = GameBoy::from_bytes(joypad_test_rom()).unwrap();
+    let mut rewind = Rewind::new(3, DEFAULT_BUDGET);
+    assert!(!rewind.step_back(&mut gb).unwrap());
+
+    let mut states = Vec::new();
+    for frame in 0..10 {
+        gb.set_button(Button::Right, frame % 4 == 1);
+        gb.run_frame().unwrap();
+        rewind.record_frame(&gb);
+        states.push(gb.save_state());
+    }
+
+    // back a frame at a time, between snapshots and onto them
+    for frame in (0..9).rev() {
+        assert!(rewind.step_back(&mut gb).unwrap());
+        assert!(gb.save_state() == states[frame]);
+    }
+    assert!(!rewind.step_back(&mut gb).unwrap());
+
+    // and carrying on again from there
+    for (frame, state) in states.iter().enumerate().take(6).skip(1) {
+        gb.set_button(Button::Right, frame % 4 == 1);
+        gb.run_frame().unwrap();
+        rewind.record_frame(&gb);
+        assert!(gb.save_state() == *state);
+    }
+    assert!(rewind.step_back(&mut gb).unwrap());
+    assert!(gb.save_state() == states[4]);
+
+    // a tight budget only keeps the newest snapshots
+    let mut rewind = Rewind::new(1, states[0].len() + 200);
+    for _ in 0..20 {
+        gb.run_frame().unwrap();
+        rewind.record_frame(&gb);
+    }
+    assert!(rewind.memory() <= states[0].len() + 200);
+    let mut steps = 0;
+    while rewind.step_back(&mut gb).unwrap() {
+        steps += 1;
+    }
+    assert!(steps > 0 && steps < 19);
+}
@@ -0,0 +1,281 @@
+//! Identifies ROMs against a No-Intro style XML DAT file, and looks up
+//! per-game compatibility overrides.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crc32fast::Hasher;
+use md5::{Digest, Md5};
+use roxmltree::Document;
+use serde_json::Value;
+use sha1::Sha1;
+
+use gb_error::EmuError;
+use gb_rom::GbRom;
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn read_file(path: &Path) -> Result<String, EmuError> {
+    fs::read_to_string(path).map_err(|e| EmuError::Io(format!("{}: {}", path.display(), e)))
+}
+
+/// The hashes DAT files identify dumps by. Digests are lower case hex.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomHashes {
+    pub size: usize,
+    pub crc32: u32,
+    pub md5: String,
+    pub sha1: String,
+}
+
+impl RomHashes {
+    pub fn new(data: &[u8]) -> Self {
+        let mut crc = Hasher::new();
+        crc.update(data);
+
+        RomHashes {
+            size: data.len(),
+            crc32: crc.finalize(),
+            md5: to_hex(&Md5::digest(data)),
+            sha1: to_hex(&Sha1::digest(data)),
+        }
+    }
+}
+
+/// One ROM listed in a DAT.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatEntry {
+    /// The canonical name of the game.
+    pub name: String,
+    pub size: Option<usize>,
+    pub crc32: Option<u32>,
+    pub md5: Option<String>,
+    pub sha1: Option<String>,
+    /// False for dumps the DAT lists as bad.
+    pub good: bool,
+}
+
+impl DatEntry {
+    fn matches(&self, hashes: &RomHashes) -> bool {
+        if let Some(ref sha1) = self.sha1 {
+            return *sha1 == hashes.sha1;
+        }
+        if let Some(ref md5) = self.md5 {
+            return *md5 == hashes.md5;
+        }
+        self.crc32 == Some(hashes.crc32) && self.size.is_none_or(|s| s == hashes.size)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RomDatabase {
+    entries: Vec<DatEntry>,
+}
+
+impl RomDatabase {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, EmuError> {
+        RomDatabase::from_xml(&read_file(path.as_ref())?)
+    }
+
+    pub fn from_xml(xml: &str) -> Result<Self, EmuError> {
+        let doc = match Document::parse(xml) {
+            Ok(doc) => doc,
+            Err(e) => return Err(EmuError::Io(format!("parsing DAT: {}", e))),
+        };
+
+        let mut entries = Vec::new();
+        for game in doc.descendants().filter(|n| n.has_tag_name("game")) {
+            let name = game.attribute("name").unwrap_or_default();
+            for rom in game.children().filter(|n| n.has_tag_name("rom")) {
+                let lower = |attr| rom.attribute(attr).map(|s: &str| s.to_lowercase());
+                entries.push(DatEntry {
+                    name: String::from(name),
+                    size: rom.attribute("size").and_then(|s| s.parse().ok()),
+                    crc32: rom
+                        .attribute("crc")
+                        .and_then(|s| u32::from_str_radix(s, 16).ok()),
+                    md5: lower("md5"),
+                    sha1: lower("sha1"),
+                    // No-Intro flags bad dumps, GoodTools style sets tag the name
+                    good: rom.attribute("status") != Some("baddump") && !name.contains("[b"),
+                });
+            }
+        }
+
+        Ok(RomDatabase { entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn lookup(&self, hashes: &RomHashes) -> Option<&DatEntry> {
+        self.entries.iter().find(|e| e.matches(hashes))
+    }
+
+    /// Hashes `rom` and finds it in the database.
+    pub fn identify(&self, rom: &GbRom) -> (RomHashes, Option<&DatEntry>) {
+        let hashes = RomHashes::new(rom.data());
+        let entry = self.lookup(&hashes);
+        (hashes, entry)
+    }
+}
+
+/// Settings a game needs to run properly that its header doesn't tell us.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GameOverride {
+    /// The model to run as, e.g. "dmg", "cgb" or "sgb".
+    pub model: Option<String>,
+    /// A mapper to use in place of the header's, e.g. "mbc1m" for multicarts.
+    pub mapper: Option<String>,
+    /// DMG shades from lightest to darkest, as 0xRRGGBB.
+    pub palette: Option<[u32; 4]>,
+}
+
+/// Overrides from a JSON file, keyed by canonical name or SHA-1:
+///
+/// ```json
+/// {
+///     "Tetris (World) (Rev 1)": { "model": "dmg", "palette": ["#E0F8D0", "#88C070", "#346856", "#081820"] },
+///     "0123456789abcdef0123456789abcdef01234567": { "mapper": "mbc1m" }
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct Overrides {
+    games: HashMap<String, GameOverride>,
+}
+
+impl Overrides {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, EmuError> {
+        Overrides::from_json(&read_file(path.as_ref())?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, EmuError> {
+        let bad = |reason: String| EmuError::Io(format!("parsing overrides: {}", reason));
+
+        let root: Value = serde_json::from_str(json).map_err(|e| bad(e.to_string()))?;
+        let games = match root.as_object() {
+            Some(games) => games,
+            None => return Err(bad(String::from("expected an object of games"))),
+        };
+
+        let mut overrides = Overrides::default();
+        for (key, game) in games {
+            let text = |field| game.get(field).and_then(Value::as_str).map(String::from);
+            let palette = match game.get("palette") {
+                Some(p) => Some(parse_palette(p).ok_or_else(|| {
+                    bad(format!("{}: palette must be four \"#RRGGBB\" colours", key))
+                })?),
+                None => None,
+            };
+
+            overrides.games.insert(
+                key.to_lowercase(),
+                GameOverride {
+                    model: text("model"),
+                    mapper: text("mapper"),
+                    palette,
+                },
+            );
+        }
+        Ok(overrides)
+    }
+
+    /// Finds the overrides for a ROM, by SHA-1 first and then by the name
+    /// the DAT gave it.
+    pub fn get(&self, hashes: &RomHashes, entry: Option<&DatEntry>) -> Option<&GameOverride> {
+        self.games
+            .get(&hashes.sha1)
+            .or_else(|| entry.and_then(|e| self.games.get(&e.name.to_lowercase())))
+    }
+}
+
+fn parse_palette(val: &Value) -> Option<[u32; 4]> {
+    let colours = val.as_array()?;
+    if colours.len() != 4 {
+        return None;
+    }
+
+    let mut palette = [0u32; 4];
+    for (shade, colour) in palette.iter_mut().zip(colours) {
+        let hex = colour.as_str()?.trim_start_matches('#');
+        *shade = u32::from_str_radix(hex, 16)
+            .ok()
+            .filter(|c| *c <= 0xFF_FFFF)?;
+    }
+    Some(palette)
+}
+
+#[test]
+fn dat_lookup_test() {
+    let data = vec![0u8; 0x8000];
+    let hashes = RomHashes::new(&data);
+    let xml = format!(
+        r#"<?xml version="1.0"?>
+        <datafile>
+            <header><name>Nintendo - Game Boy</name></header>
+            <game name="Zeroes (World)">
+                <rom name="Zeroes (World).gb" size="32768" crc="{:08X}" sha1="{}"/>
+            </game>
+            <game name="Zeroes (World) [b]">
+                <rom name="Zeroes (World) [b].gb" size="32768" crc="DEADBEEF" status="baddump"/>
+            </game>
+        </datafile>"#,
+        hashes.crc32,
+        hashes.sha1.to_uppercase()
+    );
+
+    let db = RomDatabase::from_xml(&xml).unwrap();
+    assert!(db.len() == 2);
+
+    let entry = db.lookup(&hashes).unwrap();
+    assert!(entry.name == "Zeroes (World)");
+    assert!(entry.good);
+
+    let bad = RomHashes {
+        crc32: 0xDEADBEEF,
+        ..RomHashes::new(&[])
+    };
+    assert!(db.lookup(&bad).is_none());
+    let bad = RomHashes {
+        size: 0x8000,
+        ..bad
+    };
+    assert!(!db.lookup(&bad).unwrap().good);
+}
+
+#[test]
+fn overrides_test() {
+    let bad = r##"{ "Zeroes (World)": { "palette": ["#FFFFFF"] } }"##;
+    assert!(Overrides::from_json(bad).is_err());
+
+    let json = r##"{
+        "Zeroes (World)": {
+            "model": "cgb",
+            "palette": ["#FFFFFF", "#AAAAAA", "#555555", "#000000"]
+        }
+    }"##;
+    let overrides = Overrides::from_json(json).unwrap();
+    let entry = DatEntry {
+        name: String::from("Zeroes (World)"),
+        size: None,
+        crc32: None,
+        md5: None,
+        sha1: None,
+        good: true,
+    };
+
+    let hashes = RomHashes::new(&[]);
+    assert!(overrides.get(&hashes, None).is_none());
+    let game = overrides.get(&hashes, Some(&entry)).unwrap();
+    assert!(game.model == Some(String::from("cgb")));
+    assert!(game.mapper.is_none());
+    assert!(game.palette == Some([0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]));
+}
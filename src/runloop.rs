@@ -0,0 +1,219 @@
+//! Runs a [`GameBoy`](../struct.GameBoy.html) a frame at a time, paced to
+//! real time.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gb_error::EmuError;
+use gb_system::{GameBoy, CYCLES_PER_FRAME};
+
+/// The CPU clock at normal speed, which is also the LCD's dot clock.
+pub const CPU_HZ: u64 = 4_194_304;
+
+/// The slowest and fastest `Speed::Multiplier` a loop will take.
+pub const MIN_SPEED: f64 = 0.01;
+pub const MAX_SPEED: f64 = 100.0;
+
+// falling further behind than this gives up on catching up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// How fast to run compared to the real console.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    /// 1.0 is real time, 2.0 fast-forwards at double speed and 0.5 is slow
+    /// motion.
+    Multiplier(f64),
+    /// As fast as the host allows, for benchmarking.
+    Uncapped,
+}
+
+/// What happened in one trip round the loop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameReport {
+    /// CPU cycles emulated, 0 while paused.
+    pub cycles: u64,
+    /// How long the frame should last on the host at the current speed.
+    /// None when uncapped.
+    pub duration: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub struct RunLoop {
+    speed: Speed,
+    paused: bool,
+    advance: u64,
+    deadline: Option<Instant>,
+
+    frames: u64,
+    cycles: u64,
+}
+
+impl Default for RunLoop {
+    fn default() -> Self {
+        RunLoop::new()
+    }
+}
+
+impl RunLoop {
+    /// A loop running in real time.
+    pub fn new() -> Self {
+        RunLoop {
+            speed: Speed::Multiplier(1.0),
+            paused: false,
+            advance: 0,
+            deadline: None,
+
+            frames: 0,
+            cycles: 0,
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Multipliers outside `MIN_SPEED..=MAX_SPEED` are ignored.
+    pub fn set_speed(&mut self, speed: Speed) {
+        if let Speed::Multiplier(m) = speed {
+            if !(MIN_SPEED..=MAX_SPEED).contains(&m) {
+                return;
+            }
+        }
+        self.speed = speed;
+        self.deadline = None;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.advance = 0;
+        self.deadline = None;
+    }
+
+    /// Runs one more frame while paused.
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.advance += 1;
+        }
+    }
+
+    /// Frames run through this loop.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// CPU cycles run through this loop.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Host time `cycles` take at the current speed
+    fn duration(&self, cycles: u64, double_speed: bool) -> Option<Duration> {
+        let hz = if double_speed { CPU_HZ * 2 } else { CPU_HZ };
+        match self.speed {
+            Speed::Multiplier(m) => Duration::try_from_secs_f64(cycles as f64 / hz as f64 / m).ok(),
+            Speed::Uncapped => None,
+        }
+    }
+
+    /// Emulates the next frame, unless paused with no frame to advance.
+    /// Doesn't wait; call `pace` with the report for that.
+    pub fn run_frame(&mut self, gb: &mut GameBoy) -> Result<FrameReport, EmuError> {
+        if self.paused {
+            if self.advance == 0 {
+                // idle for a real frame rather than spinning
+                let frame = CYCLES_PER_FRAME as f64 / CPU_HZ as f64;
+                return Ok(FrameReport {
+                    cycles: 0,
+                    duration: Some(Duration::from_secs_f64(frame)),
+                });
+            }
+            self.advance -= 1;
+        }
+
+        let double_speed = gb.is_double_speed();
+        let cycles = gb.run_frame()?;
+        self.frames += 1;
+        self.cycles += cycles;
+        Ok(FrameReport {
+            cycles,
+            duration: self.duration(cycles, double_speed),
+        })
+    }
+
+    /// A report for a frame that shows for as long as one would but isn't
+    /// emulated, such as one stepped back to.
+    pub fn idle_frame(&self) -> FrameReport {
+        FrameReport {
+            cycles: 0,
+            duration: self.duration(CYCLES_PER_FRAME, false),
+        }
+    }
+
+    /// Sleeps until the frame in `report` is due to finish. Frames are
+    /// timed against a running deadline so rounding doesn't drift, and a
+    /// host that falls behind starts afresh instead of racing to catch up.
+    pub fn pace(&mut self, report: &FrameReport) {
+        let duration = match report.duration {
+            Some(d) => d,
+            None => {
+                self.deadline = None;
+                return;
+            }
+        };
+
+        let now = Instant::now();
+        let deadline = self.deadline.unwrap_or(now) + duration;
+        if deadline > now {
+            thread::sleep(deadline - now);
+            self.deadline = Some(deadline);
+        } else if now - deadline > MAX_LAG {
+            self.deadline = None;
+        } else {
+            self.deadline = Some(deadline);
+        }
+    }
+}
+
+#[test]
+fn runloop_test() {
+    use gb_system::test_rom;
+
+    // JR -2
+    let mut gb = GameBoy::from_bytes(test_rom(&[0x18, 0xFE])).unwrap();
+    let mut runner = RunLoop::new();
+
+    // the first frame only runs up to the LCD's first V-blank
+    let first = runner.run_frame(&mut gb).unwrap();
+    assert!(first.cycles > 0 && first.cycles < CYCLES_PER_FRAME);
+    let report = runner.run_frame(&mut gb).unwrap();
+    assert!(report.cycles > CYCLES_PER_FRAME - 12 && report.cycles < CYCLES_PER_FRAME + 12);
+    let frame = report.duration.unwrap();
+    assert!(frame > Duration::from_micros(16_700) && frame < Duration::from_micros(16_800));
+
+    runner.set_speed(Speed::Multiplier(2.0));
+    let fast = runner.run_frame(&mut gb).unwrap().duration.unwrap();
+    assert!(fast < frame / 2 + Duration::from_micros(10));
+    runner.set_speed(Speed::Multiplier(1e-300));
+    runner.set_speed(Speed::Multiplier(f64::NAN));
+    assert!(runner.speed() == Speed::Multiplier(2.0));
+
+    runner.set_speed(Speed::Uncapped);
+    assert!(runner.run_frame(&mut gb).unwrap().duration.is_none());
+
+    runner.pause();
+    assert!(runner.run_frame(&mut gb).unwrap().cycles == 0);
+    runner.advance_frame();
+    assert!(runner.run_frame(&mut gb).unwrap().cycles > 0);
+    assert!(runner.run_frame(&mut gb).unwrap().cycles == 0);
+
+    assert!(runner.frames() == 5);
+    assert!(runner.cycles() == gb.cycles());
+}
@@ -0,0 +1,170 @@
+//! Captures the screen and saves it as a PNG.
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use gb_error::EmuError;
+use gb_ppu::{rgb555, rgb888, SCREEN_HEIGHT, SCREEN_WIDTH};
+use gb_system::{GameBoy, Model};
+use png;
+
+/// The four colours a DMG's shades show as, lightest first, in RGB555 with
+/// red in the low bits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette(pub [u16; 4]);
+
+impl Palette {
+    /// What `GameBoy::rgb_framebuffer` uses.
+    pub const GREY: Palette = Palette([0x7FFF, 0x56B5, 0x294A, 0x0000]);
+    /// The pea soup of the original LCD.
+    pub const GREEN: Palette = Palette([
+        rgb555(0x9BBC0F),
+        rgb555(0x8BAC0F),
+        rgb555(0x306230),
+        rgb555(0x0F380F),
+    ]);
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    /// `grey`, `green` or four RRGGBB hex colours separated by commas.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grey" | "gray" => return Ok(Palette::GREY),
+            "green" => return Ok(Palette::GREEN),
+            _ => {}
+        }
+
+        let colours: Vec<&str> = s.split(',').collect();
+        if colours.len() != 4 {
+            return Err(format!(
+                "palette needs grey, green or 4 RRGGBB colours: {}",
+                s
+            ));
+        }
+        let mut palette = [0; 4];
+        for (shade, colour) in palette.iter_mut().zip(colours) {
+            let hex = colour.trim().trim_start_matches('#');
+            let rgb = match u32::from_str_radix(hex, 16) {
+                Ok(rgb) if hex.len() == 6 => rgb,
+                _ => return Err(format!("bad colour in palette: {}", colour)),
+            };
+            *shade = rgb555(rgb);
+        }
+        Ok(Palette(palette))
+    }
+}
+
+/// A copy of the screen in RGB555, red in the low bits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Screenshot {
+    width: usize,
+    height: usize,
+    pixels: Vec<u16>,
+}
+
+impl Screenshot {
+    /// Copies the screen as it is now. `palette` recolours a DMG's shades;
+    /// CGB and SGB screens already have colours of their own so keep them.
+    pub fn capture(gb: &GameBoy, palette: Option<&Palette>) -> Self {
+        let pixels = match palette {
+            Some(palette) if gb.model() == Model::Dmg => gb
+                .framebuffer()
+                .iter()
+                .map(|&shade| palette.0[shade as usize & 3])
+                .collect(),
+            _ => gb.rgb_framebuffer().to_vec(),
+        };
+        Screenshot {
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+            pixels,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Row major RGB555 pixels.
+    pub fn pixels(&self) -> &[u16] {
+        &self.pixels
+    }
+
+    /// Blows every pixel up to a `scale` by `scale` square.
+    pub fn scaled(&self, scale: usize) -> Screenshot {
+        let scale = scale.max(1);
+        let width = self.width * scale;
+        let mut pixels = Vec::with_capacity(width * self.height * scale);
+        for row in self.pixels.chunks(self.width) {
+            let line: Vec<u16> = row
+                .iter()
+                .flat_map(|&p| std::iter::repeat_n(p, scale))
+                .collect();
+            for _ in 0..scale {
+                pixels.extend_from_slice(&line);
+            }
+        }
+        Screenshot {
+            width,
+            height: self.height * scale,
+            pixels,
+        }
+    }
+
+    /// Encodes as a PNG. Indexed images are smaller, but fall back to RGB
+    /// if there are somehow more than 256 colours.
+    pub fn to_png(&self, indexed: bool) -> Vec<u8> {
+        if indexed {
+            let mut colours: Vec<u16> = Vec::new();
+            let mut indices = Vec::with_capacity(self.pixels.len());
+            for &p in &self.pixels {
+                let i = match colours.iter().position(|&c| c == p) {
+                    Some(i) => i,
+                    None => {
+                        colours.push(p);
+                        colours.len() - 1
+                    }
+                };
+                if i > 0xFF {
+                    return self.to_png(false);
+                }
+                indices.push(i as u8);
+            }
+            let palette: Vec<[u8; 3]> = colours.into_iter().map(rgb888).collect();
+            png::encode_indexed(self.width, self.height, &palette, &indices)
+        } else {
+            let pixels: Vec<[u8; 3]> = self.pixels.iter().map(|&p| rgb888(p)).collect();
+            png::encode_rgb(self.width, self.height, &pixels)
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P, indexed: bool) -> Result<(), EmuError> {
+        let path = path.as_ref();
+        fs::write(path, self.to_png(indexed))
+            .map_err(|e| EmuError::Io(format!("{}: {}", path.display(), e)))
+    }
+}
+
+#[test]
+fn screenshot_test() {
+    use gb_system::test_rom;
+
+    assert!("green".parse::<Palette>() == Ok(Palette::GREEN));
+    assert!("ffffff,aaaaaa,555555,000000".parse::<Palette>().unwrap().0[0] == 0x7FFF);
+    assert!("ffffff,aaaaaa".parse::<Palette>().is_err());
+
+    let mut gb = GameBoy::from_bytes(test_rom(&[0x18, 0xFE])).unwrap();
+    gb.run_frame().unwrap();
+    let shot = Screenshot::capture(&gb, Some(&Palette::GREEN)).scaled(2);
+    assert!(shot.width() == 320 && shot.height() == 288);
+    let shade = gb.framebuffer()[0] as usize;
+    assert!(shot.pixels()[0] == Palette::GREEN.0[shade]);
+    assert!(shot.pixels()[321] == shot.pixels()[0]);
+}
@@ -0,0 +1,218 @@
+//! Draws the screen in a terminal and reads the keyboard from it, for
+//! machines without a display.
+//!
+//! Each character cell shows two pixels stacked with an upper half block,
+//! the top one as the foreground colour and the bottom as the background,
+//! using 24-bit ANSI colour.
+
+use std::fmt::Write;
+use std::io;
+
+use gb_joypad::Button;
+use gb_ppu::rgb888;
+use gb_system::GameBoy;
+
+// Terminals only say when a key goes down, and auto-repeat takes a while to
+// start, so a press holds the button for this many frames
+pub const HOLD_FRAMES: u32 = 8;
+
+/// Something typed at the terminal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key {
+    Button(Button),
+    Rewind,
+    Pause,
+    Quit,
+}
+
+/// Turns raw terminal input into keys. Arrows or WASD are the D-pad, X and
+/// Z are A and B, Enter is Start and Backspace or Space is Select. R
+/// rewinds, P pauses, and Q, Esc or Ctrl-C quit. Esc only counts when it
+/// arrives on its own, since it also starts escape sequences.
+pub fn parse_keys(bytes: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == 0x1B {
+            match bytes.get(i + 1) {
+                // Esc on its own; anything after it was sent with it
+                None if i == 0 => keys.push(Key::Quit),
+                // a control sequence runs through parameter bytes to a final
+                // byte. Arrows end in A-D, with modifiers like ESC [ 1 ; 5 A,
+                // or come as ESC O A-D in application mode
+                Some(&b'[') | Some(&b'O') => {
+                    i += 2;
+                    while i < bytes.len() && !(0x40..=0x7E).contains(&bytes[i]) {
+                        i += 1;
+                    }
+                    let button = match bytes.get(i) {
+                        Some(b'A') => Some(Button::Up),
+                        Some(b'B') => Some(Button::Down),
+                        Some(b'C') => Some(Button::Right),
+                        Some(b'D') => Some(Button::Left),
+                        _ => None,
+                    };
+                    keys.extend(button.map(Key::Button));
+                }
+                // Alt and a key, or the tail of a sequence that was cut off
+                _ => i += 1,
+            }
+            i += 1;
+            continue;
+        }
+
+        let key = match bytes[i].to_ascii_lowercase() {
+            b'w' => Some(Key::Button(Button::Up)),
+            b's' => Some(Key::Button(Button::Down)),
+            b'a' => Some(Key::Button(Button::Left)),
+            b'd' => Some(Key::Button(Button::Right)),
+            b'x' => Some(Key::Button(Button::A)),
+            b'z' => Some(Key::Button(Button::B)),
+            b'\r' | b'\n' => Some(Key::Button(Button::Start)),
+            b' ' | 0x08 | 0x7F => Some(Key::Button(Button::Select)),
+            b'r' => Some(Key::Rewind),
+            b'p' => Some(Key::Pause),
+            b'q' | 0x03 => Some(Key::Quit),
+            _ => None,
+        };
+        keys.extend(key);
+        i += 1;
+    }
+    keys
+}
+
+/// Keeps buttons down for a few frames after each key press.
+#[derive(Debug, Default)]
+pub struct HeldButtons {
+    frames: [u32; 8],
+}
+
+impl HeldButtons {
+    pub fn new() -> Self {
+        HeldButtons::default()
+    }
+
+    pub fn press(&mut self, button: Button) {
+        if let Some(i) = Button::ALL.iter().position(|b| *b == button) {
+            self.frames[i] = HOLD_FRAMES;
+        }
+    }
+
+    /// Passes the held buttons on to `gb` and counts down a frame.
+    pub fn update(&mut self, gb: &mut GameBoy) {
+        for (i, frames) in self.frames.iter_mut().enumerate() {
+            gb.set_button(Button::ALL[i], *frames > 0);
+            *frames = frames.saturating_sub(1);
+        }
+    }
+}
+
+/// Draws a `width` pixel wide RGB555 screen, like
+/// `GameBoy::rgb_framebuffer`, from the top left of the terminal. The
+/// status line, if any, goes underneath.
+pub fn render(pixels: &[u16], width: usize, status: Option<&str>) -> String {
+    let mut out = String::from("\x1b[H");
+    let mut colours = None;
+    for rows in pixels.chunks(width * 2) {
+        let (top, bottom) = rows.split_at(width.min(rows.len()));
+        for (x, &upper) in top.iter().enumerate() {
+            let lower = bottom.get(x).cloned().unwrap_or(0);
+            // only say when the colours change, it's most of the output
+            if colours != Some((upper, lower)) {
+                let [r, g, b] = rgb888(upper);
+                let [br, bg, bb] = rgb888(lower);
+                let _ = write!(
+                    out,
+                    "\x1b[38;2;{};{};{};48;2;{};{};{}m",
+                    r, g, b, br, bg, bb
+                );
+                colours = Some((upper, lower));
+            }
+            out.push('▀');
+        }
+        out.push_str("\x1b[0m\r\n");
+        colours = None;
+    }
+    if let Some(status) = status {
+        let _ = write!(out, "\x1b[2K{}\r\n", status);
+    }
+    out
+}
+
+/// Puts the terminal in raw mode on the alternate screen with the cursor
+/// hidden, and puts it back when dropped.
+pub struct Terminal {
+    #[cfg(unix)]
+    saved: libc::termios,
+}
+
+impl Terminal {
+    #[cfg(unix)]
+    pub fn enter() -> io::Result<Self> {
+        unsafe {
+            let mut saved = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut saved) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = saved;
+            libc::cfmakeraw(&mut raw);
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+            let _ = io::Write::flush(&mut io::stdout());
+            Ok(Terminal { saved })
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn enter() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "raw terminal input isn't supported on this platform",
+        ))
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::Write::flush(&mut io::stdout());
+        #[cfg(unix)]
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved);
+        }
+    }
+}
+
+#[test]
+fn term_test() {
+    assert!(
+        parse_keys(b"\x1b[Ax\rRq")
+            == vec![
+                Key::Button(Button::Up),
+                Key::Button(Button::A),
+                Key::Button(Button::Start),
+                Key::Rewind,
+                Key::Quit,
+            ]
+    );
+
+    // sequences with parameters are read through to their final byte, and
+    // only a lone Esc quits
+    assert!(
+        parse_keys(b"\x1b[1;5C\x1b[200~\x1bOBp")
+            == vec![
+                Key::Button(Button::Right),
+                Key::Button(Button::Down),
+                Key::Pause,
+            ]
+    );
+    assert!(parse_keys(b"\x1b") == vec![Key::Quit]);
+    assert!(parse_keys(b"\x1bq\x1b[1;").is_empty());
+    assert!(parse_keys(b"x\x1b") == vec![Key::Button(Button::A)]);
+
+    // two rows of one pixel each make one cell
+    let out = render(&[0x7FFF, 0x001F], 1, Some("PC 0100"));
+    assert!(out == "\x1b[H\x1b[38;2;255;255;255;48;2;255;0;0m▀\x1b[0m\r\n\x1b[2KPC 0100\r\n");
+}
@@ -24,6 +24,16 @@ pub struct TraceLog {
     changes: Vec<MemChange>,
 }
 
+impl MemChange {
+    pub fn dest(&self) -> &MemChangeDest {
+        &self.dest
+    }
+
+    pub fn value(&self) -> u8 {
+        self.value
+    }
+}
+
 impl TraceLog {
     pub fn new(op: OpCodes) -> Self {
         TraceLog {
@@ -31,4 +41,12 @@ impl TraceLog {
             changes: Vec::new(),
         }
     }
+
+    pub fn opcode(&self) -> OpCodes {
+        self.opcode
+    }
+
+    pub fn changes(&self) -> &[MemChange] {
+        &self.changes
+    }
 }